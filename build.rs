@@ -1,14 +1,215 @@
-use std::process::Command;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::{env, fs};
+
+const DATABASE: &str = "builtins/bpftrace.toml";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Database {
+    /// The release the documentation is from, no builtin can be newer.
+    bpftrace: String,
+    variables: Vec<Variable>,
+    numbered_variables: Vec<NumberedVariable>,
+    functions: Vec<Function>,
+    map_functions: Vec<Function>,
+    providers: Vec<Provider>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Variable {
+    name: String,
+    #[serde(default, rename = "type")]
+    ty: String,
+    #[serde(default)]
+    description: String,
+    since: Option<String>,
+    until: Option<String>,
+}
+
+/// A family of variables like `arg0`, `arg1`, ...
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NumberedVariable {
+    prefix: String,
+    #[serde(rename = "type")]
+    ty: String,
+    description: String,
+    providers: Vec<String>,
+    /// How many there are, by architecture.
+    #[serde(default)]
+    limits: BTreeMap<String, usize>,
+    since: Option<String>,
+    until: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Function {
+    name: String,
+    #[serde(default)]
+    signature: String,
+    /// The type of the value returned, if any.
+    returns: Option<String>,
+    #[serde(default)]
+    description: String,
+    since: Option<String>,
+    until: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Provider {
+    name: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+    syntax: String,
+    description: String,
+    since: Option<String>,
+    until: Option<String>,
+}
 
 fn main() {
-    assert!(
-        Command::new("/usr/bin/env")
-            .arg("python")
-            .arg("./scripts/generate.py")
-            .output()
-            .unwrap()
-            .status
-            .success()
+    println!("cargo:rerun-if-changed={DATABASE}");
+    let text = fs::read_to_string(DATABASE).unwrap();
+    let database: Database = toml::from_str(&text).unwrap_or_else(|e| panic!("{DATABASE}: {e}"));
+
+    let release = parse_version("bpftrace", &database.bpftrace);
+    let since = database
+        .variables
+        .iter()
+        .map(|x| (&x.name, &x.since))
+        .chain(
+            database
+                .numbered_variables
+                .iter()
+                .map(|x| (&x.prefix, &x.since)),
+        )
+        .chain(database.functions.iter().map(|x| (&x.name, &x.since)))
+        .chain(database.map_functions.iter().map(|x| (&x.name, &x.since)))
+        .chain(database.providers.iter().map(|x| (&x.name, &x.since)));
+    for family in &database.numbered_variables {
+        if let Some(provider) = family
+            .providers
+            .iter()
+            .find(|x| !database.providers.iter().any(|p| &p.name == *x))
+        {
+            panic!(
+                "{DATABASE}: unknown provider `{provider}` of `{}`",
+                family.prefix
+            );
+        }
+    }
+    for (name, since) in since {
+        if let Some(since) = since {
+            assert!(
+                parse_version(name, since) <= release,
+                "{DATABASE}: `{name}` is newer than bpftrace {}",
+                database.bpftrace
+            );
+        }
+    }
+
+    let mut functions = database.functions;
+    functions.extend(database.map_functions);
+    functions.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let keywords = database
+        .variables
+        .iter()
+        .map(|x| symbol(&x.name, &x.ty, &x.description, &x.since, &x.until))
+        .collect::<String>();
+    let numbered = database
+        .numbered_variables
+        .iter()
+        .map(|x| {
+            let symbol = symbol(&x.prefix, &x.ty, &x.description, &x.since, &x.until);
+            let limits = x
+                .limits
+                .iter()
+                .map(|(arch, count)| format!("({arch:?}, {count}), "))
+                .collect::<String>();
+            format!(
+                "NumberedSymbol {{\nsymbol: {symbol}providers: &{:?},\nlimits: &[{limits}],\n}},\n",
+                x.providers
+            )
+        })
+        .collect::<String>();
+    let builtins = functions
+        .iter()
+        .map(|x| symbol(&x.name, &x.signature, &x.description, &x.since, &x.until))
+        .collect::<String>();
+    let providers = database
+        .providers
+        .iter()
+        .map(|x| symbol(&x.name, &x.syntax, &x.description, &x.since, &x.until))
+        .collect::<String>();
+    let aliases = database
+        .providers
+        .iter()
+        .flat_map(|x| x.aliases.iter().map(move |alias| (alias, &x.name)))
+        .map(|(alias, name)| format!("({alias:?}, {name:?}),\n"))
+        .collect::<String>();
+    let return_types = functions
+        .iter()
+        .filter_map(|x| Some(format!("({:?}, {:?}),\n", x.name, x.returns.as_ref()?)))
+        .collect::<String>();
+
+    let out = env::var("OUT_DIR").unwrap();
+    let write = |name: &str, contents: String| {
+        fs::write(Path::new(&out).join(name), contents).unwrap();
+    };
+    write(
+        "builtins.rs",
+        format!(
+            "BuiltinSymbols {{\nkeywords: &[\n{keywords}],\nnumbered: &[\n{numbered}],\nfunctions: &[\n{builtins}],\n}}\n"
+        ),
     );
-    println!("cargo:rerun-if-changed=./target/builtins.gen.rs");
+    write("providers.rs", format!("&[\n{providers}]\n"));
+    write("provider_aliases.rs", format!("&[\n{aliases}]\n"));
+    write("return_types.rs", format!("&[\n{return_types}]\n"));
+}
+
+fn symbol(
+    name: &str,
+    detail: &str,
+    documentation: &str,
+    since: &Option<String>,
+    until: &Option<String>,
+) -> String {
+    let mut symbol = String::new();
+    writeln!(symbol, "BuiltinSymbol {{").unwrap();
+    writeln!(symbol, "name: {name:?},").unwrap();
+    writeln!(symbol, "detail: {detail:?},").unwrap();
+    writeln!(symbol, "documentation: {documentation:?},").unwrap();
+    writeln!(symbol, "since: {},", version(name, since)).unwrap();
+    writeln!(symbol, "until: {},", version(name, until)).unwrap();
+    writeln!(symbol, "}},").unwrap();
+    symbol
+}
+
+/// A release like `0.21` as a `Version` expression.
+fn version(name: &str, version: &Option<String>) -> String {
+    match version {
+        Some(version) => {
+            let (major, minor, patch) = parse_version(name, version);
+            format!("Some(Version::new({major}, {minor}, {patch}))")
+        }
+        None => "None".to_string(),
+    }
+}
+
+fn parse_version(name: &str, version: &str) -> (u32, u32, u32) {
+    let parts = version
+        .split('.')
+        .map(|x| x.parse::<u32>().ok())
+        .collect::<Option<Vec<_>>>();
+    match parts.as_deref() {
+        Some(&[major, minor]) => (major, minor, 0),
+        Some(&[major, minor, patch]) => (major, minor, patch),
+        _ => panic!("{DATABASE}: invalid version \"{version}\" of `{name}`"),
+    }
 }
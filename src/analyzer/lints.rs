@@ -0,0 +1,338 @@
+use std::collections::HashSet;
+
+use pest::Span;
+
+use crate::builtins::unalias;
+use crate::parser::{
+    AssignOp, Expr, IdentKind, Identifier, Loop, Lvalue, MapLeak, MapReadBeforeWrite, MapReadKind,
+    Node, Preamble, Probe, Program, Statement, UnusedVariable, Walk, WriteOnlyMap,
+};
+
+/// Functions whose result maps hold for bpftrace to print at exit.
+const AGGREGATIONS: &[&str] = &[
+    "avg", "count", "hist", "lhist", "max", "min", "stats", "sum",
+];
+
+/// Identifiers a probe writes to and reads from.
+#[derive(Default)]
+struct Usage<'a, 'b> {
+    writes: Vec<&'b Identifier<'a>>,
+    reads: Vec<&'b Identifier<'a>>,
+    /// Blocks of `if` statements along with the maps their condition checks.
+    guards: Vec<(Span<'a>, &'a str)>,
+    /// Maps passed to `delete()` or `clear()`.
+    cleared: Vec<&'a str>,
+    /// Maps assigned something other than an aggregation or a count, as
+    /// opposed to `@x = count()`, `@x++` or `@x += 1`.
+    assigned: HashSet<&'a str>,
+}
+
+impl<'a, 'b> Usage<'a, 'b> {
+    fn collect(nodes: impl Iterator<Item = &'b dyn Node<'a>>) -> Self {
+        let mut usage = Self::default();
+        // expressions that look like reads but only write (`$x++`, loop
+        // variables) or clean up (`delete(@m)`) the identifier
+        let mut skip: HashSet<*const Expr<'a>> = HashSet::new();
+
+        for node in nodes.flat_map(Walk::new) {
+            if let Some(stmt) = node.as_statement() {
+                let target = match stmt {
+                    Statement::Assignment(assign) => {
+                        let Lvalue::Identifier(ident) = &assign.lvalue;
+                        usage.writes.push(ident);
+                        if assign.op == AssignOp::Assign
+                            && !matches!(assign.rvalue.as_ref(),
+                                Expr::Call(call) if AGGREGATIONS.contains(&call.func.name))
+                        {
+                            usage.assigned.insert(ident.name);
+                        }
+                        None
+                    }
+                    // counters like `@x++`
+                    Statement::Expr(expr) => match expr.as_ref() {
+                        Expr::UnaryExpr(unary) => Some((unary.expr.as_ref(), false)),
+                        _ => None,
+                    },
+                    Statement::Loop(loop_stmt) => match loop_stmt.as_ref() {
+                        Loop::For(for_loop) => Some((for_loop.lhs.as_ref(), true)),
+                        Loop::While(_) | Loop::Unroll(_) => None,
+                    },
+                    Statement::IfCond(if_cond) => {
+                        let guard = if_cond.span;
+                        usage
+                            .guards
+                            .extend(maps_in(if_cond.condition.as_node()).map(|name| (guard, name)));
+                        None
+                    }
+                    Statement::Return(_) | Statement::Error(_) => None,
+                };
+                if let Some((target @ Expr::Identifier(ident), assigned)) = target {
+                    usage.writes.push(ident);
+                    if assigned {
+                        usage.assigned.insert(ident.name);
+                    }
+                    skip.insert(target);
+                }
+            }
+
+            match node.as_expr() {
+                Some(Expr::Call(call)) if matches!(call.func.name, "delete" | "clear") => {
+                    if let Some(Expr::Identifier(map)) = call.args.first() {
+                        usage.cleared.push(map.name);
+                    }
+                    if call.func.name == "delete" {
+                        skip.extend(call.args.iter().map(|x| x as *const _));
+                    }
+                }
+                Some(expr @ Expr::Identifier(ident)) if !skip.contains(&(expr as *const _)) => {
+                    usage.reads.push(ident);
+                }
+                _ => {}
+            }
+        }
+        usage
+    }
+
+    fn writes(&self, name: &str) -> bool {
+        self.writes.iter().any(|x| x.name == name)
+    }
+
+    fn unread(&self, kind: IdentKind) -> impl Iterator<Item = &'b Identifier<'a>> {
+        let mut seen = HashSet::new();
+        self.writes.iter().copied().filter(move |ident| {
+            ident.kind == kind
+                && !self
+                    .reads
+                    .iter()
+                    .any(|x| x.kind == kind && x.name == ident.name)
+                && seen.insert(ident.name)
+        })
+    }
+
+    fn maps(&self) -> Self {
+        Self {
+            writes: self.writes.iter().copied().filter(is_map).collect(),
+            reads: self.reads.iter().copied().filter(is_map).collect(),
+            guards: self.guards.clone(),
+            cleared: self.cleared.clone(),
+            assigned: self.assigned.clone(),
+        }
+    }
+}
+
+fn is_map(ident: &&Identifier) -> bool {
+    ident.kind == IdentKind::Map
+}
+
+fn maps_in<'a, 'b>(node: &'b dyn Node<'a>) -> impl Iterator<Item = &'a str> + 'b {
+    Walk::new(node).filter_map(|node| match node.as_expr() {
+        Some(Expr::Identifier(ident)) if ident.kind == IdentKind::Map => Some(ident.name),
+        _ => None,
+    })
+}
+
+fn probe_usage<'a, 'b>(probe: &'b Probe<'a>) -> Usage<'a, 'b> {
+    Usage::collect(std::iter::once(probe.as_node()))
+}
+
+/// Probes of a program along with the maps they use.
+fn map_usages<'a, 'b>(program: &'b Program<'a>) -> Vec<(&'b Probe<'a>, Usage<'a, 'b>)> {
+    program
+        .preambles
+        .iter()
+        .filter_map(|x| match x {
+            Preamble::Probe(probe) => Some((probe, probe_usage(probe).maps())),
+            Preamble::Include(_)
+            | Preamble::Definition(_)
+            | Preamble::Function(_)
+            | Preamble::Error(_) => None,
+        })
+        .collect()
+}
+
+/// Reports scratch variables that are never read within their probe and maps
+/// that are written but never read, deleted or cleared by any probe. Maps
+/// only ever holding aggregations or counts are left alone, bpftrace prints
+/// them at exit.
+pub fn lint<'a>(program: &Program<'a>, errors: &mut Vec<Statement<'a>>) {
+    let mut maps = Usage::default();
+    for preamble in &program.preambles {
+        let Preamble::Probe(probe) = preamble else {
+            continue;
+        };
+        let usage = probe_usage(probe);
+
+        errors.extend(
+            usage
+                .unread(IdentKind::Scratch)
+                .map(|ident| UnusedVariable::new(ident.name, ident.span)),
+        );
+        maps.writes.extend(&usage.writes);
+        maps.reads.extend(&usage.reads);
+        maps.assigned.extend(&usage.assigned);
+        maps.cleared.extend(&usage.cleared);
+    }
+
+    errors.extend(
+        maps.unread(IdentKind::Map)
+            .filter(|ident| maps.assigned.contains(ident.name))
+            .filter(|ident| !maps.cleared.contains(&ident.name))
+            .map(|ident| WriteOnlyMap::new(ident.name, ident.span)),
+    );
+}
+
+/// Reports map reads that can happen before any probe had a chance to write
+/// the map: reads in `BEGIN`, keyed reads not guarded by a filter or `if`,
+/// and keyed reads in return probes that no matching entry probe writes.
+pub fn lint_map_reads<'a>(program: &Program<'a>, errors: &mut Vec<Statement<'a>>) {
+    let probes = map_usages(program);
+
+    for (index, (probe, usage)) in probes.iter().enumerate() {
+        let written_elsewhere = |name| {
+            probes
+                .iter()
+                .enumerate()
+                .any(|(i, (_, other))| i != index && other.writes(name))
+        };
+        let filter_maps = probe
+            .condition
+            .iter()
+            .flat_map(|x| maps_in(x.as_node()))
+            .collect::<Vec<_>>();
+        let is_begin = probe.attach_points.iter().all(|x| *x == "BEGIN");
+        let mut reported = HashSet::new();
+
+        for read in &usage.reads {
+            // an earlier write in the same probe makes the read fine
+            let written_before = usage
+                .writes
+                .iter()
+                .any(|x| x.name == read.name && x.span.start() < read.span.start());
+            if written_before || !written_elsewhere(read.name) {
+                continue;
+            }
+
+            let kind = if is_begin {
+                Some(MapReadKind::Begin)
+            } else if read.keys.is_empty() {
+                None
+            } else if let Some(matched) = entry_written(probe, read, &probes) {
+                // the entry probe writing the key is what guards the read
+                (!matched).then_some(MapReadKind::UnmatchedEntry)
+            } else {
+                let guarded = filter_maps.contains(&read.name)
+                    || usage.guards.iter().any(|(block, name)| {
+                        *name == read.name
+                            && block.start() <= read.span.start()
+                            && read.span.end() <= block.end()
+                    });
+                (!guarded).then_some(MapReadKind::Unguarded)
+            };
+
+            if let Some(kind) = kind
+                && reported.insert(read.name)
+            {
+                errors.push(MapReadBeforeWrite::new(read, kind));
+            }
+        }
+    }
+}
+
+/// Whether an entry probe for the same function writes the key a return
+/// probe reads, `None` for reads that aren't keyed by `tid` or `pid` or that
+/// aren't in a return probe.
+fn entry_written(probe: &Probe, read: &Identifier, probes: &[(&Probe, Usage)]) -> Option<bool> {
+    if !matches!(read.key_text(), Some("tid" | "pid")) {
+        return None;
+    }
+    if !probe
+        .attach_points
+        .iter()
+        .any(|x| return_entry(x).is_some())
+    {
+        return None;
+    }
+
+    let written = probes.iter().any(|(entry, usage)| {
+        returns_from(probe, entry)
+            && usage
+                .writes
+                .iter()
+                .any(|x| x.name == read.name && x.key_text() == read.key_text())
+    });
+    Some(written)
+}
+
+/// Reports maps written with a `tid` or `pid` key that no probe deletes or
+/// clears, which makes them grow with every thread or process seen.
+pub fn lint_map_leaks<'a>(program: &Program<'a>, errors: &mut Vec<Statement<'a>>) {
+    let probes = map_usages(program);
+    let cleared = probes
+        .iter()
+        .flat_map(|(_, usage)| usage.cleared.iter().copied())
+        .collect::<HashSet<_>>();
+
+    let mut reported = HashSet::new();
+    for (probe, usage) in &probes {
+        for write in &usage.writes {
+            if !matches!(write.key_text(), Some("tid" | "pid"))
+                || cleared.contains(write.name)
+                || !reported.insert(write.name)
+            {
+                continue;
+            }
+            let return_block = probes
+                .iter()
+                .find(|(other, _)| returns_from(other, probe))
+                .map(|(other, _)| other.block.span);
+            errors.push(MapLeak::new(write, return_block));
+        }
+    }
+}
+
+/// Whether `probe` is a return probe of a function `entry` attaches to.
+fn returns_from(probe: &Probe, entry: &Probe) -> bool {
+    probe
+        .attach_points
+        .iter()
+        .filter_map(|x| return_entry(x))
+        .any(|(p, t)| {
+            entry.attach_points.iter().any(|ap| {
+                let (provider, target) = split_attach_point(ap);
+                p == provider && (glob_match(t, target) || glob_match(target, t))
+            })
+        })
+}
+
+/// Splits an attach point into its (unaliased) provider and the rest.
+fn split_attach_point(attach_point: &str) -> (&str, &str) {
+    let (provider, target) = attach_point.split_once(':').unwrap_or((attach_point, ""));
+    (unalias(provider), target)
+}
+
+/// The entry provider and target matching a return probe attach point.
+fn return_entry(attach_point: &str) -> Option<(&'static str, &str)> {
+    let (provider, target) = split_attach_point(attach_point);
+    let entry = match provider {
+        "kretprobe" => "kprobe",
+        "uretprobe" => "uprobe",
+        "fexit" => "fentry",
+        _ => return None,
+    };
+    Some((entry, target))
+}
+
+/// Matches `text` against `pattern`, where `*` matches any sequence.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            let Some(text) = text.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=text.len())
+                .filter(|i| text.is_char_boundary(*i))
+                .any(|i| glob_match(rest, &text[i..]))
+        }
+    }
+}
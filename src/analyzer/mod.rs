@@ -1,2 +1,8 @@
+mod lints;
+pub mod options;
+pub mod params;
+pub mod providers;
 pub mod semantic_analyzer;
 mod tests;
+pub mod types;
+pub mod versions;
@@ -0,0 +1,128 @@
+use crate::parser::{Expr, GetoptConflict, IdentKind, Node, Program, Statement, Walk};
+use pest::Span;
+use serde_json::{Value, json};
+
+/// A named option a script reads with `getopt("name", default)`, passed to
+/// it as `--name=value`.
+#[derive(Debug)]
+pub struct ScriptOption<'a> {
+    pub name: &'a str,
+    /// The name along with its quotes.
+    pub span: Span<'a>,
+    /// `bool` without a default, else the type of the default.
+    pub ty: &'static str,
+    /// As written, `None` when not given, in which case the option is
+    /// `false` unless passed.
+    pub default: Option<&'a str>,
+}
+
+impl ScriptOption<'_> {
+    /// The default as a JSON value, e.g. for `btls check --format json`.
+    pub fn default_value(&self) -> Value {
+        let Some(default) = self.default else {
+            return Value::Bool(false);
+        };
+        match self.ty {
+            "string" => Value::String(unquote(default).to_string()),
+            "bool" => Value::Bool(default == "true"),
+            _ => default
+                .parse::<i64>()
+                .map_or(Value::String(default.to_string()), Value::from),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "type": self.ty,
+            "default": self.default_value(),
+        })
+    }
+
+    /// As in `--name: int64 = 10`.
+    pub fn describe(&self) -> String {
+        format!(
+            "--{}: {} = {}",
+            self.name,
+            self.ty,
+            self.default.unwrap_or("false")
+        )
+    }
+}
+
+/// Every `getopt()` call with a literal name, in the order they appear.
+pub fn calls<'a>(program: &Program<'a>) -> Vec<ScriptOption<'a>> {
+    let mut options = vec![];
+    for node in Walk::new(program.as_node()) {
+        let Some(Expr::Call(call)) = node.as_expr() else {
+            continue;
+        };
+        if call.func.name != "getopt" {
+            continue;
+        }
+        let Some(Expr::String(name)) = call.args.first() else {
+            continue;
+        };
+        let default = call.args.get(1);
+        options.push(ScriptOption {
+            name: unquote(name.value),
+            span: name.span,
+            ty: default.map_or(Some("bool"), type_of).unwrap_or("?"),
+            default: default.map(|x| x.span().as_str()),
+        });
+    }
+    options
+}
+
+/// The options of a script, where each is first read.
+pub fn table<'a>(program: &Program<'a>) -> Vec<ScriptOption<'a>> {
+    let mut options: Vec<ScriptOption> = vec![];
+    for option in calls(program) {
+        if !options.iter().any(|x| x.name == option.name) {
+            options.push(option);
+        }
+    }
+    options
+}
+
+/// Reports options read again with another default than where they are
+/// first read.
+pub fn check<'a>(program: &Program<'a>, errors: &mut Vec<Statement<'a>>) {
+    let calls = calls(program);
+    for (i, option) in calls.iter().enumerate() {
+        let Some(first) = calls[..i].iter().find(|x| x.name == option.name) else {
+            continue;
+        };
+        if first.ty != option.ty || first.default != option.default {
+            errors.push(GetoptConflict::new(
+                option.name,
+                option.span,
+                first.describe(),
+            ));
+        }
+    }
+}
+
+fn type_of(expr: &Expr) -> Option<&'static str> {
+    match expr {
+        Expr::Integer(_) => Some("int64"),
+        Expr::String(_) => Some("string"),
+        Expr::Identifier(ident)
+            if ident.kind == IdentKind::Bare && matches!(ident.name, "true" | "false") =>
+        {
+            Some("bool")
+        }
+        Expr::UnaryExpr(unary)
+            if unary.operator() == "-" && matches!(unary.expr.as_ref(), Expr::Integer(_)) =>
+        {
+            Some("int64")
+        }
+        _ => None,
+    }
+}
+
+fn unquote(text: &str) -> &str {
+    text.strip_prefix('"')
+        .and_then(|x| x.strip_suffix('"'))
+        .unwrap_or(text)
+}
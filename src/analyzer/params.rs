@@ -0,0 +1,218 @@
+use crate::config::{Config, ParamType};
+use crate::parser::{
+    Expr, IdentKind, Identifier, Lvalue, Node, PositionalParam, PositionalParamKind, Program,
+    Statement, Walk,
+};
+use std::collections::{BTreeMap, HashSet};
+
+/// A positional parameter a script expects.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Param {
+    /// 1 for `$1`.
+    pub number: usize,
+    pub ty: Option<ParamType>,
+    pub description: String,
+}
+
+/// The parameters a script declares with `@param` lines in its header
+/// comment, as in `// @param $1 int the PID to trace`, or else those of the
+/// `positional_params` setting.
+pub fn declared(text: &str, config: &Config) -> Vec<Param> {
+    let mut params = header_params(text);
+    if params.is_empty() {
+        params = config
+            .positional_params
+            .iter()
+            .enumerate()
+            .map(|(i, x)| Param {
+                number: i + 1,
+                ty: x.ty,
+                description: x.description.clone(),
+            })
+            .collect();
+    }
+    params
+}
+
+/// The `@param` lines of the comments a script starts with.
+fn header_params(text: &str) -> Vec<Param> {
+    let mut params: Vec<Param> = vec![];
+    let mut block_comment = false;
+    for line in text.lines() {
+        let line = line.trim();
+        let comment = if block_comment {
+            block_comment = !line.contains("*/");
+            line.trim_start_matches('*')
+        } else if let Some(rest) = line.strip_prefix("//") {
+            rest
+        } else if let Some(rest) = line.strip_prefix("/*") {
+            block_comment = !rest.contains("*/");
+            rest.trim_start_matches('*')
+        } else if line.is_empty() || line.starts_with("#!") {
+            continue;
+        } else {
+            break;
+        };
+        let Some(declaration) = comment.trim().strip_prefix("@param") else {
+            continue;
+        };
+        let mut words = declaration.split_whitespace().peekable();
+        let Some(number) = words
+            .next()
+            .and_then(|x| x.strip_prefix('$'))
+            .and_then(|x| x.parse::<usize>().ok())
+            .filter(|x| *x > 0)
+        else {
+            continue;
+        };
+        let ty = words.next_if(|x| x.parse::<ParamType>().is_ok());
+        let description = words.collect::<Vec<_>>().join(" ");
+        if !params.iter().any(|x| x.number == number) {
+            params.push(Param {
+                number,
+                ty: ty.and_then(|x| x.parse().ok()),
+                description: description.trim_end_matches("*/").trim_end().to_string(),
+            });
+        }
+    }
+    params.sort_by_key(|x| x.number);
+    params
+}
+
+/// How a positional parameter is used, by the order it's first used in.
+#[derive(Default)]
+struct Uses<'a, 'b> {
+    first: Option<&'b Identifier<'a>>,
+    integer: Option<&'b Identifier<'a>>,
+    string: Option<&'b Identifier<'a>>,
+}
+
+/// Checks that positional parameters are used consistently with each other
+/// and with their declarations.
+pub fn check<'a>(program: &Program<'a>, params: &[Param], errors: &mut Vec<Statement<'a>>) {
+    // `str($1)` reads the parameter as a string, anything else as an integer
+    let mut strings = HashSet::new();
+    let mut assigned = HashSet::new();
+    for node in Walk::new(program.as_node()) {
+        match node.as_statement() {
+            Some(Statement::Assignment(assign)) => {
+                let Lvalue::Identifier(ident) = &assign.lvalue;
+                assigned.insert(ident.span.start());
+            }
+            _ => match node.as_expr() {
+                Some(Expr::Call(call)) if call.func.name == "str" => {
+                    if let Some(Expr::Identifier(ident)) = call.args.first() {
+                        strings.insert(ident.span.start());
+                    }
+                }
+                _ => {}
+            },
+        }
+    }
+
+    let mut uses: BTreeMap<usize, Uses> = BTreeMap::new();
+    for node in Walk::new(program.as_node()) {
+        let Some(ident) = node.as_identifier() else {
+            continue;
+        };
+        let Some(number) = number(ident) else {
+            continue;
+        };
+        if assigned.contains(&ident.span.start()) {
+            errors.push(PositionalParam::new(ident, PositionalParamKind::Assigned));
+            continue;
+        }
+        let entry = uses.entry(number).or_default();
+        entry.first.get_or_insert(ident);
+        if strings.contains(&ident.span.start()) {
+            entry.string.get_or_insert(ident);
+        } else {
+            entry.integer.get_or_insert(ident);
+        }
+    }
+
+    let known =
+        |number: &usize| uses.contains_key(number) || params.iter().any(|x| x.number == *number);
+    for (number, used) in &uses {
+        let Some(first) = used.first else {
+            continue;
+        };
+        let param = params.iter().find(|x| x.number == *number);
+        if let Some(missing) = (1..*number).find(|x| !known(x)) {
+            errors.push(PositionalParam::new(
+                first,
+                PositionalParamKind::Gap(missing),
+            ));
+        } else if !params.is_empty() && param.is_none() {
+            errors.push(PositionalParam::new(first, PositionalParamKind::Undeclared));
+        }
+        match (param.and_then(|x| x.ty), used.integer, used.string) {
+            (Some(ParamType::String), Some(ident), _) => {
+                errors.push(PositionalParam::new(
+                    ident,
+                    PositionalParamKind::StringAsInteger,
+                ));
+            }
+            (Some(ParamType::Int), _, Some(ident)) => {
+                errors.push(PositionalParam::new(
+                    ident,
+                    PositionalParamKind::IntegerAsString,
+                ));
+            }
+            (None, Some(integer), Some(string)) => {
+                let last = if integer.span.start() < string.span.start() {
+                    string
+                } else {
+                    integer
+                };
+                errors.push(PositionalParam::new(last, PositionalParamKind::Mixed));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The number of a positional parameter, `None` for `$#` and everything
+/// else.
+pub fn number(ident: &Identifier) -> Option<usize> {
+    if ident.kind != IdentKind::Positional {
+        return None;
+    }
+    ident.name.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_params() {
+        let text = "#!/usr/bin/env bpftrace\n\
+            // Traces opens.\n\
+            // @param $2 str path prefix\n\
+            /*\n * @param $1 int the PID */\n\
+            // @param $3\n\
+            BEGIN {}\n\
+            // @param $4 int too late\n";
+        let params = declared(text, &Config::default());
+        let summary = params
+            .iter()
+            .map(|x| (x.number, x.ty, x.description.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (1, Some(ParamType::Int), "the PID"),
+                (2, Some(ParamType::String), "path prefix"),
+                (3, None, ""),
+            ]
+        );
+
+        let config = Config {
+            positional_params: vec![Default::default()],
+            ..Default::default()
+        };
+        assert_eq!(declared("BEGIN {}", &config).len(), 1);
+        assert_eq!(declared(text, &config).len(), 3);
+    }
+}
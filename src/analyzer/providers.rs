@@ -0,0 +1,58 @@
+use crate::builtins::{BUILTINS, PROBE_PROVIDERS, unalias};
+use crate::parser::{
+    IdentKind, Node, Preamble, Probe, Program, Statement, UnavailableBuiltin,
+    UnavailableBuiltinKind, UnknownProvider, Walk,
+};
+use pest::Span;
+
+/// The providers of the attach points of a probe, as written.
+pub fn providers<'a>(probe: &Probe<'a>) -> impl Iterator<Item = Span<'a>> {
+    let input = probe.span.get_input();
+    probe.attach_points.iter().filter_map(move |attach_point| {
+        let provider = attach_point.split(':').next().unwrap_or(attach_point);
+        // attach points are slices of the source
+        let start = attach_point.as_ptr() as usize - input.as_ptr() as usize;
+        Span::new(input, start, start + provider.len())
+    })
+}
+
+/// Reports unknown probe providers, and numbered builtins like `arg0` used in
+/// probes not having them or beyond those of the architecture.
+pub fn check<'a>(program: &Program<'a>, arch: &str, errors: &mut Vec<Statement<'a>>) {
+    for preamble in &program.preambles {
+        let Preamble::Probe(probe) = preamble else {
+            continue;
+        };
+        let mut known = vec![];
+        for span in providers(probe) {
+            let provider = unalias(span.as_str());
+            if PROBE_PROVIDERS.iter().any(|x| x.name == provider) {
+                known.push(provider);
+            } else if !provider.contains('*') {
+                errors.push(UnknownProvider::new(span.as_str(), span));
+            }
+        }
+
+        for node in Walk::new(probe.as_node()) {
+            let Some(ident) = node.as_identifier() else {
+                continue;
+            };
+            if ident.kind != IdentKind::Bare {
+                continue;
+            }
+            let Some((family, number)) = BUILTINS.numbered(ident.name) else {
+                continue;
+            };
+            if let Some(count) = family.limit(arch)
+                && number >= count
+            {
+                let arch = arch.to_string();
+                let kind = UnavailableBuiltinKind::OutOfRange { arch, count };
+                errors.push(UnavailableBuiltin::new(ident, kind));
+            } else if let Some(provider) = known.iter().find(|x| !family.providers.contains(x)) {
+                let kind = UnavailableBuiltinKind::Provider(provider.to_string());
+                errors.push(UnavailableBuiltin::new(ident, kind));
+            }
+        }
+    }
+}
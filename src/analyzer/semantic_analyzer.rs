@@ -1,18 +1,27 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
+use super::params::{self, Param};
+use super::{lints, options, providers, types, versions};
 use crate::builtins::BUILTINS;
+use crate::config::Config;
+use crate::headers::{self, Definitions, HeaderCache};
 use crate::parser::{
-    Block, Expr, IdentKind, Loop, Lvalue, Node, Preamble, Probe, Program, Statement, UndefinedFunc,
-    UndefinedIdent, Walk, ast::parse,
+    Block, Expr, Function, IdentKind, Loop, Lvalue, Node, Preamble, Probe, Program, Statement,
+    UndefinedFunc, UndefinedIdent, UnknownField, UnresolvedInclude, ast::parse,
 };
 use crate::server::Context;
 use crate::storage::Document;
-use anyhow::Result;
-use std::path::Path;
+use anyhow::{Result, bail};
+use self_cell::self_cell;
+use tower_lsp::lsp_types::Url;
 
 fn var_prefix(kind: IdentKind) -> &'static str {
     match kind {
-        IdentKind::Scratch => "$",
+        IdentKind::Scratch | IdentKind::Positional => "$",
         IdentKind::Map => "@",
         IdentKind::Bare => "",
     }
@@ -23,7 +32,8 @@ fn collect_global_maps(program: &Program) -> Vec<String> {
     for preamble in &program.preambles {
         match preamble {
             Preamble::Probe(probe) => collect_maps_in_block(&probe.block, &mut maps),
-            Preamble::Error(_) => {}
+            Preamble::Function(function) => collect_maps_in_block(&function.block, &mut maps),
+            Preamble::Include(_) | Preamble::Definition(_) | Preamble::Error(_) => {}
         }
     }
     maps
@@ -50,11 +60,23 @@ fn collect_maps_in_block(block: &Block, maps: &mut Vec<String>) {
                 Loop::While(w) => {
                     collect_maps_in_block(&w.block, maps);
                 }
+                Loop::Unroll(u) => {
+                    collect_maps_in_block(&u.block, maps);
+                }
             },
             Statement::IfCond(if_cond) => {
                 collect_maps_in_block(&if_cond.block, maps);
             }
-            Statement::Expr(_) | Statement::Error(_) => {}
+            // `@m++` and `@m--` define the map as well
+            Statement::Expr(expr) => {
+                if let Expr::UnaryExpr(unary) = expr.as_ref()
+                    && let Expr::Identifier(ident) = unary.expr.as_ref()
+                    && ident.kind == IdentKind::Map
+                {
+                    maps.push(format!("@{}", ident.name));
+                }
+            }
+            Statement::Return(_) | Statement::Error(_) => {}
         }
     }
 }
@@ -80,7 +102,11 @@ fn collect_vars_in_preamble(preamble: &Preamble, offset: usize, vars: &mut Vec<S
         Preamble::Probe(probe) => {
             collect_vars_in_block(&probe.block, offset, vars);
         }
-        Preamble::Error(_) => {}
+        Preamble::Function(function) => {
+            vars.extend(function.params.iter().map(|x| format!("${}", x.ident.name)));
+            collect_vars_in_block(&function.block, offset, vars);
+        }
+        Preamble::Include(_) | Preamble::Definition(_) | Preamble::Error(_) => {}
     }
 }
 
@@ -115,6 +141,11 @@ fn collect_vars_in_block(block: &Block, offset: usize, vars: &mut Vec<String>) {
                             collect_vars_in_block(&f.block, offset, vars);
                         }
                     }
+                    Loop::Unroll(u) => {
+                        if u.block.span().start() <= offset && offset < u.block.span().end() {
+                            collect_vars_in_block(&u.block, offset, vars);
+                        }
+                    }
                 }
             }
             Statement::IfCond(if_cond) => {
@@ -122,178 +153,399 @@ fn collect_vars_in_block(block: &Block, offset: usize, vars: &mut Vec<String>) {
                     collect_vars_in_block(&if_cond.block, offset, vars);
                 }
             }
-            Statement::Expr(_) | Statement::Error(_) => {}
+            Statement::Expr(_) | Statement::Return(_) | Statement::Error(_) => {}
         }
     }
 }
 
-pub struct SemanticAnalyzer {
-    pub content: String,
-}
+self_cell!(
+    struct ProgramCell {
+        owner: Arc<String>,
+        #[covariant]
+        dependent: Program,
+    }
+    impl {Debug}
+);
 
-pub struct AnalyzedFile<'a> {
+/// The result of analyzing a single version of a document. The AST borrows
+/// from the document text, so both are kept alive together.
+#[derive(Debug)]
+pub struct AnalyzedFile {
+    /// Tells analyses apart, each change to the document, its configuration
+    /// or the headers it includes makes a new one.
+    pub id: u64,
     pub document: Arc<Document>,
-    pub variables: Vec<String>,
-    pub ast: Program<'a>,
+    /// What the script and the headers it includes define.
+    pub definitions: Definitions,
+    /// The header each `#include` resolved to, in order.
+    pub includes: Vec<Option<PathBuf>>,
+    /// The positional parameters the script declares.
+    pub params: Vec<Param>,
+    /// The configuration and headers the analysis relied on, along with the
+    /// modification time of the headers.
+    config: Arc<Config>,
+    headers: Vec<(PathBuf, Option<SystemTime>)>,
+    program: ProgramCell,
+}
+
+/// How many analyses were made so far, see `AnalyzedFile::id`.
+static ANALYSES: AtomicU64 = AtomicU64::new(0);
+
+impl AnalyzedFile {
+    pub fn new(
+        document: Arc<Document>,
+        headers: &HeaderCache,
+        config: Arc<Config>,
+    ) -> Result<Self> {
+        let params = params::declared(&document.data, &config);
+        let mut definitions = Definitions::default();
+        let mut resolved = headers::Resolved::default();
+        // quoted includes are looked up next to the script first
+        let dir = document
+            .uri
+            .to_file_path()
+            .ok()
+            .and_then(|x| x.parent().map(Path::to_path_buf));
+        let program = ProgramCell::try_new(document.data.clone(), |content| {
+            let ast = parse(content)?;
+            resolved = headers.resolve(
+                &includes(&ast),
+                dir.as_deref(),
+                &config.include_paths,
+                &config.arch,
+            );
+            let mut visible = vec![Arc::new(headers::scan(&c_source(&ast)))];
+            visible.extend(resolved.headers.iter().cloned());
+            definitions = Definitions::new(visible);
+            analyze_program(ast, &definitions, &resolved.files, &params, &config)
+        })?;
+        Ok(Self {
+            id: ANALYSES.fetch_add(1, Ordering::Relaxed),
+            document,
+            definitions,
+            includes: resolved.files,
+            params,
+            config,
+            headers: resolved.versions,
+            program,
+        })
+    }
+
+    pub fn ast(&self) -> &Program<'_> {
+        self.program.borrow_dependent()
+    }
+
+    /// Whether the configuration or any of the headers changed since the
+    /// analysis.
+    fn outdated(&self, config: &Config) -> bool {
+        *self.config != *config
+            || self
+                .headers
+                .iter()
+                .any(|(file, modified)| headers::modified(file) != *modified)
+    }
+}
+
+/// The paths of the `#include` directives of a program, and whether they are
+/// in angle brackets.
+fn includes(program: &Program) -> Vec<(String, bool)> {
+    program
+        .preambles
+        .iter()
+        .filter_map(|x| match x {
+            Preamble::Include(include) => Some((include.path.to_string(), include.system)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The C definitions of a script, with everything else blanked out byte for
+/// byte so that offsets stay the same.
+fn c_source(program: &Program) -> String {
+    let text = program.span.get_input();
+    let mut source = text
+        .bytes()
+        .map(|x| if x == b'\n' { '\n' } else { ' ' })
+        .collect::<String>();
+    for preamble in &program.preambles {
+        if let Preamble::Definition(definition) = preamble {
+            let span = definition.span;
+            source.replace_range(span.start()..span.end(), definition.text);
+        }
+    }
+    source
+}
+
+/// Caches analysis results per document. An entry is reused for as long as
+/// the document it was built from is unchanged (same URI, version and data)
+/// and so are the headers it includes.
+#[derive(Default)]
+pub struct SemanticAnalyzer {
+    cache: Mutex<HashMap<Url, Arc<AnalyzedFile>>>,
+    headers: HeaderCache,
 }
 
 impl SemanticAnalyzer {
     pub fn new() -> Self {
-        Self {
-            content: "".to_string(),
-        }
+        Default::default()
     }
 
-    pub async fn analyze(&mut self, context: &Context, path: &Path) -> Result<AnalyzedFile<'_>> {
-        let document = context.storage.lock().await.read(path);
-        self.content = (*document.data).clone();
-        let mut ast = parse(&self.content)?;
-        let mut errors = vec![];
-        let global_maps = collect_global_maps(&ast);
+    pub async fn analyze(&self, context: &Context, uri: &Url) -> Result<Arc<AnalyzedFile>> {
+        let document = context.storage.lock().await.read(uri);
+        if document.version.is_error() {
+            bail!("failed to read \"{uri}\"");
+        }
+        let config = context.config(uri).await;
+        if let Some(analyzed) = self.cached(&document)
+            && !analyzed.outdated(&config)
+        {
+            return Ok(analyzed);
+        }
 
-        for preamble in &ast.preambles {
-            if let Preamble::Probe(probe) = preamble {
-                check_probe(probe, &global_maps, &mut errors);
-            }
+        let analyzed = Arc::new(AnalyzedFile::new(document.clone(), &self.headers, config)?);
+        let mut cache = self.cache.lock().unwrap();
+        // another request may have finished analyzing the same version first
+        if let Some(existing) = cache.get(uri).filter(|x| {
+            x.document == document && x.config == analyzed.config && x.headers == analyzed.headers
+        }) {
+            return Ok(existing.clone());
         }
+        cache.insert(uri.clone(), analyzed.clone());
+        Ok(analyzed)
+    }
 
-        let mut variables = vec![];
-        let root = Walk::new(ast.as_node());
-        root.into_iter().for_each(|n| {
-            if let Some(stmt) = n.as_statement() {
-                match stmt {
-                    Statement::Loop(x) => {
-                        if let Loop::For(x) = x.as_ref() {
-                            if let Expr::Identifier(ident) = x.lhs.as_ref() {
-                                variables.push(format!("{}{}", var_prefix(ident.kind), ident.name));
-                            }
-                        }
-                    }
-                    Statement::Assignment(a) => match &a.lvalue {
-                        Lvalue::Identifier(ident) => {
-                            variables.push(format!("{}{}", var_prefix(ident.kind), ident.name))
-                        }
-                    },
-                    _ => {}
-                }
-            }
-        });
+    fn cached(&self, document: &Arc<Document>) -> Option<Arc<AnalyzedFile>> {
+        self.cache
+            .lock()
+            .unwrap()
+            .get(&document.uri)
+            .filter(|x| x.document == *document)
+            .cloned()
+    }
 
-        // TODO: append errors to their associated block
-        // currently, we just append the errors to the first block (which works fine)
-        ast.preambles
-            .iter_mut()
-            .filter_map(|x| match x {
-                Preamble::Probe(p) => Some(&mut p.block),
-                _ => None,
-            })
-            .next()
-            .map(|x| x.statements.extend(errors));
+    pub fn invalidate(&self, uri: &Url) {
+        self.cache.lock().unwrap().remove(uri);
+    }
+}
 
-        Ok(AnalyzedFile {
-            document,
-            ast,
-            variables,
+fn analyze_program<'a>(
+    mut ast: Program<'a>,
+    definitions: &Definitions,
+    include_files: &[Option<PathBuf>],
+    params: &[Param],
+    config: &Config,
+) -> Result<Program<'a>> {
+    let mut errors = vec![];
+    let global_maps = collect_global_maps(&ast);
+
+    let includes = ast.preambles.iter().filter_map(|x| match x {
+        Preamble::Include(include) => Some(include),
+        _ => None,
+    });
+    for (include, file) in includes.zip(include_files) {
+        if file.is_none() {
+            errors.push(UnresolvedInclude::new(include.path, include.path_span));
+        }
+    }
+    let functions = ast
+        .preambles
+        .iter()
+        .filter_map(|x| match x {
+            Preamble::Function(function) => Some(function.name.name),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let checker = Checker {
+        global_maps: &global_maps,
+        functions: &functions,
+        definitions,
+    };
+    for preamble in &ast.preambles {
+        match preamble {
+            Preamble::Probe(probe) => checker.check_probe(probe, &mut errors),
+            Preamble::Function(function) => checker.check_function(function, &mut errors),
+            Preamble::Include(_) | Preamble::Definition(_) | Preamble::Error(_) => {}
+        }
+    }
+    check_fields(&ast, definitions, &mut errors);
+    params::check(&ast, params, &mut errors);
+    options::check(&ast, &mut errors);
+    providers::check(&ast, &config.arch, &mut errors);
+    if let Some(version) = config.bpftrace_version {
+        versions::check(&ast, version, &mut errors);
+    }
+    lints::lint(&ast, &mut errors);
+    lints::lint_map_reads(&ast, &mut errors);
+    lints::lint_map_leaks(&ast, &mut errors);
+
+    // TODO: append errors to their associated block
+    // currently, we just append the errors to the first block (which works fine)
+    if let Some(block) = ast
+        .preambles
+        .iter_mut()
+        .filter_map(|x| match x {
+            Preamble::Probe(p) => Some(&mut p.block),
+            _ => None,
         })
+        .next()
+    {
+        block.statements.extend(errors);
     }
+
+    Ok(ast)
 }
 
-fn check_probe<'a>(
-    probe: &Probe<'a>,
-    global_maps: &[String],
+/// Reports fields that the records they are accessed on don't have. Fields
+/// of records that aren't defined can't be told apart from typos.
+fn check_fields<'a>(
+    program: &Program<'a>,
+    definitions: &Definitions,
     errors: &mut Vec<Statement<'a>>,
 ) {
-    let mut scope = Vec::new();
-    if let Some(cond) = &probe.condition {
-        check_expr(cond, &scope, global_maps, errors);
+    let types = types::infer(program, definitions);
+    for (access, base) in &types.fields {
+        let Some(record) = base.as_deref().and_then(|x| definitions.record(x)) else {
+            continue;
+        };
+        if !record.fields.iter().any(|x| x.name == access.field.name) {
+            errors.push(UnknownField::new(&access.field, record.type_name()));
+        }
     }
-    check_block(&probe.block, &mut scope, global_maps, errors);
 }
 
-fn check_block<'a>(
-    block: &Block<'a>,
-    scope: &mut Vec<String>,
-    global_maps: &[String],
-    errors: &mut Vec<Statement<'a>>,
-) {
-    for stmt in &block.statements {
-        match stmt {
-            Statement::Assignment(assign) => {
-                check_expr(&assign.rvalue, scope, global_maps, errors);
-                let Lvalue::Identifier(ident) = &assign.lvalue;
-                if ident.kind != IdentKind::Map {
-                    scope.push(format!("{}{}", var_prefix(ident.kind), ident.name));
+/// Checks the identifiers and calls of a probe or function.
+struct Checker<'c> {
+    global_maps: &'c [String],
+    /// The names of the functions the script defines.
+    functions: &'c [&'c str],
+    definitions: &'c Definitions,
+}
+
+impl Checker<'_> {
+    fn check_probe<'a>(&self, probe: &Probe<'a>, errors: &mut Vec<Statement<'a>>) {
+        let mut scope = Vec::new();
+        if let Some(cond) = &probe.condition {
+            self.check_expr(cond, &scope, errors);
+        }
+        self.check_block(&probe.block, &mut scope, errors);
+    }
+
+    fn check_function<'a>(&self, function: &Function<'a>, errors: &mut Vec<Statement<'a>>) {
+        let mut scope = function
+            .params
+            .iter()
+            .map(|x| format!("${}", x.ident.name))
+            .collect();
+        self.check_block(&function.block, &mut scope, errors);
+    }
+
+    fn check_block<'a>(
+        &self,
+        block: &Block<'a>,
+        scope: &mut Vec<String>,
+        errors: &mut Vec<Statement<'a>>,
+    ) {
+        for stmt in &block.statements {
+            match stmt {
+                Statement::Assignment(assign) => {
+                    self.check_expr(&assign.rvalue, scope, errors);
+                    let Lvalue::Identifier(ident) = &assign.lvalue;
+                    for key in &ident.keys {
+                        self.check_expr(key, scope, errors);
+                    }
+                    if ident.kind != IdentKind::Map {
+                        scope.push(format!("{}{}", var_prefix(ident.kind), ident.name));
+                    }
                 }
-            }
-            Statement::Loop(loop_stmt) => match loop_stmt.as_ref() {
-                Loop::For(for_loop) => {
-                    check_expr(&for_loop.rhs, scope, global_maps, errors);
-                    if let Expr::Identifier(ident) = for_loop.lhs.as_ref() {
-                        if ident.kind != IdentKind::Map {
-                            scope.push(format!("{}{}", var_prefix(ident.kind), ident.name));
+                Statement::Loop(loop_stmt) => match loop_stmt.as_ref() {
+                    Loop::For(for_loop) => {
+                        self.check_expr(&for_loop.rhs, scope, errors);
+                        if let Expr::Identifier(ident) = for_loop.lhs.as_ref() {
+                            if ident.kind != IdentKind::Map {
+                                scope.push(format!("{}{}", var_prefix(ident.kind), ident.name));
+                            }
                         }
+                        let mut inner = scope.clone();
+                        self.check_block(&for_loop.block, &mut inner, errors);
+                    }
+                    Loop::While(w) => {
+                        self.check_expr(&w.condition, scope, errors);
+                        let mut inner = scope.clone();
+                        self.check_block(&w.block, &mut inner, errors);
                     }
+                    Loop::Unroll(u) => {
+                        self.check_expr(&u.count, scope, errors);
+                        let mut inner = scope.clone();
+                        self.check_block(&u.block, &mut inner, errors);
+                    }
+                },
+                Statement::IfCond(if_cond) => {
+                    self.check_expr(&if_cond.condition, scope, errors);
                     let mut inner = scope.clone();
-                    check_block(&for_loop.block, &mut inner, global_maps, errors);
+                    self.check_block(&if_cond.block, &mut inner, errors);
                 }
-                Loop::While(w) => {
-                    check_expr(&w.condition, scope, global_maps, errors);
-                    let mut inner = scope.clone();
-                    check_block(&w.block, &mut inner, global_maps, errors);
+                Statement::Expr(expr) => {
+                    self.check_expr(expr, scope, errors);
                 }
-            },
-            Statement::IfCond(if_cond) => {
-                check_expr(&if_cond.condition, scope, global_maps, errors);
-                let mut inner = scope.clone();
-                check_block(&if_cond.block, &mut inner, global_maps, errors);
-            }
-            Statement::Expr(expr) => {
-                check_expr(expr, scope, global_maps, errors);
+                Statement::Return(ret) => {
+                    if let Some(value) = &ret.value {
+                        self.check_expr(value, scope, errors);
+                    }
+                }
+                Statement::Error(_) => {}
             }
-            Statement::Error(_) => {}
         }
     }
-}
 
-fn check_expr<'a>(
-    expr: &Expr<'a>,
-    scope: &[String],
-    global_maps: &[String],
-    errors: &mut Vec<Statement<'a>>,
-) {
-    match expr {
-        Expr::Identifier(ident) => match ident.kind {
-            IdentKind::Bare => {
-                if !BUILTINS.keywords.iter().any(|k| k.name == ident.name) {
-                    errors.push(UndefinedIdent::new(ident.name, ident.span));
+    fn check_expr<'a>(&self, expr: &Expr<'a>, scope: &[String], errors: &mut Vec<Statement<'a>>) {
+        match expr {
+            Expr::Identifier(ident) => {
+                match ident.kind {
+                    IdentKind::Bare => {
+                        if !BUILTINS.keywords.iter().any(|k| k.name == ident.name)
+                            && BUILTINS.numbered(ident.name).is_none()
+                            && self.definitions.constant(ident.name).is_none()
+                        {
+                            errors.push(UndefinedIdent::new(ident));
+                        }
+                    }
+                    IdentKind::Scratch => {
+                        if !scope.contains(&format!("${}", ident.name)) {
+                            errors.push(UndefinedIdent::new(ident));
+                        }
+                    }
+                    IdentKind::Map => {
+                        if !self.global_maps.contains(&format!("@{}", ident.name)) {
+                            errors.push(UndefinedIdent::new(ident));
+                        }
+                    }
+                    // checked against the declared parameters, see `params::check`
+                    IdentKind::Positional => {}
                 }
-            }
-            IdentKind::Scratch => {
-                if !scope.contains(&format!("${}", ident.name)) {
-                    errors.push(UndefinedIdent::new(ident.name, ident.span));
+                for key in &ident.keys {
+                    self.check_expr(key, scope, errors);
                 }
             }
-            IdentKind::Map => {
-                if !global_maps.contains(&format!("@{}", ident.name)) {
-                    errors.push(UndefinedIdent::new(ident.name, ident.span));
+            Expr::Call(call) => {
+                if !BUILTINS.functions.iter().any(|f| f.name == call.func.name)
+                    && !self.functions.contains(&call.func.name)
+                {
+                    errors.push(UndefinedFunc::new(call.func.name, call.span()));
+                }
+                for arg in &call.args {
+                    self.check_expr(arg, scope, errors);
                 }
             }
-        },
-        Expr::Call(call) => {
-            if !BUILTINS.functions.iter().any(|f| f.name == call.func.name) {
-                errors.push(UndefinedFunc::new(call.func.name, call.span()));
+            Expr::BinaryExpr(bin) => {
+                self.check_expr(&bin.lhs, scope, errors);
+                self.check_expr(&bin.rhs, scope, errors);
             }
-            for arg in &call.args {
-                check_expr(arg, scope, global_maps, errors);
+            Expr::UnaryExpr(unary) => {
+                self.check_expr(&unary.expr, scope, errors);
             }
+            Expr::Field(field) => self.check_expr(&field.expr, scope, errors),
+            Expr::Cast(cast) => self.check_expr(&cast.expr, scope, errors),
+            Expr::Integer(_) | Expr::String(_) => {}
         }
-        Expr::BinaryExpr(bin) => {
-            check_expr(&bin.lhs, scope, global_maps, errors);
-            check_expr(&bin.rhs, scope, global_maps, errors);
-        }
-        Expr::UnaryExpr(unary) => {
-            check_expr(&unary.expr, scope, global_maps, errors);
-        }
-        Expr::Integer(_) | Expr::String(_) => {}
     }
 }
@@ -1,14 +1,41 @@
 #![cfg(test)]
 
+use std::collections::HashMap;
 use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{Mutex, RwLock};
+use tower_lsp::LanguageServer;
+use tower_lsp::lsp_types::{
+    CodeActionContext, CodeActionOrCommand, CodeActionParams, CompletionResponse,
+    DiagnosticSeverity, DidChangeConfigurationParams, DocumentDiagnosticReport, FoldingRangeKind,
+    GotoDefinitionResponse, HoverContents, InlayHintLabel, MessageType, Position, Range,
+    SymbolKind, TextDocumentIdentifier, TextEdit, Url, WorkspaceFolder,
+    WorkspaceFoldersChangeEvent,
+};
 
 use super::*;
+use crate::btf::BtfCache;
+use crate::builtins::{PROBE_PROVIDERS, SNIPPETS};
 use crate::client::*;
+use crate::code_action_provider;
+use crate::common::utils::OwnedLineIndex;
+use crate::completion_provider;
+use crate::config;
+use crate::definition_provider;
+use crate::diagnostic_provider::*;
+use crate::folding_range_provider;
+use crate::hover_provider;
+use crate::inlay_hint_provider;
 use crate::parser::*;
+use crate::selection_range_provider;
 use crate::server::*;
 use crate::storage::*;
+use crate::workspace::WorkspaceIndex;
+
+fn file_uri(path: impl AsRef<Path>) -> Url {
+    Url::from_file_path(path).unwrap()
+}
 
 fn init_context() -> Context {
     let client = Client::new_test();
@@ -18,7 +45,12 @@ fn init_context() -> Context {
     Context {
         client,
         storage: Arc::new(Mutex::new(storage)),
-        analyzer: Mutex::new(analyzer),
+        analyzer,
+        btf: BtfCache::new(),
+        diagnostics: DiagnosticScheduler::new(),
+        configs: RwLock::new(HashMap::new()),
+        settings: RwLock::new(serde_json::Value::Null),
+        workspace: WorkspaceIndex::new(),
     }
 }
 
@@ -33,19 +65,19 @@ async fn test_sanity() {
             $var3 = undefinedfunc();
         }"#;
 
-    let path = Path::new("tmp_path");
+    let uri = &file_uri("/tmp_path");
     let context = init_context();
     {
         let mut storage = context.storage.lock().await;
-        storage.load(path, prog, 0);
+        storage.load(uri, prog, 0);
     }
 
-    let mut analyzer = context.analyzer.lock().await;
-    let analyzed = analyzer.analyze(&context, path).await.unwrap();
-    assert_eq!(analyzed.variables.len(), 3);
+    let analyzed = context.analyzer.analyze(&context, uri).await.unwrap();
+    assert_eq!(variable_count(&analyzed), 3);
 
-    let errors = analyzed.ast.errors().collect::<Vec<_>>();
-    assert_eq!(errors.len(), 3);
+    // $var, $var2 and $var3 are never read
+    let errors = analyzed.ast().errors().collect::<Vec<_>>();
+    assert_eq!(errors.len(), 6);
     assert!(matches!(
         errors[1],
         ErrorRef::Statement(ErrorStatement::UndefinedIdent(..))
@@ -55,3 +87,1496 @@ async fn test_sanity() {
         ErrorRef::Statement(ErrorStatement::UndefinedFunc(..))
     ));
 }
+
+/// How many variables are visible at the end of the last probe.
+fn variable_count(analyzed: &semantic_analyzer::AnalyzedFile) -> usize {
+    let end = analyzed.document.data.rfind('}').unwrap();
+    semantic_analyzer::variables_at(analyzed.ast(), end).len()
+}
+
+#[tokio::test]
+async fn test_cache() {
+    let uri = &file_uri("/tmp_path");
+    let context = init_context();
+    context
+        .storage
+        .lock()
+        .await
+        .load(uri, "BEGIN { $x = 1; }", 0);
+
+    let first = context.analyzer.analyze(&context, uri).await.unwrap();
+    let second = context.analyzer.analyze(&context, uri).await.unwrap();
+    assert!(Arc::ptr_eq(&first, &second));
+
+    context
+        .storage
+        .lock()
+        .await
+        .load(uri, "BEGIN { $x = 1; $y = 2; }", 1);
+    let third = context.analyzer.analyze(&context, uri).await.unwrap();
+    assert!(!Arc::ptr_eq(&first, &third));
+    assert_eq!(variable_count(&third), 2);
+    // the old analysis stays valid for whoever still holds it
+    assert_eq!(variable_count(&first), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_debounced_diagnostics() {
+    let uri = &file_uri("/tmp_path.bt");
+    let context = Arc::new(init_context());
+
+    for (revision, prog) in ["BEGIN { $x; }", "BEGIN { $x; $y; }"].iter().enumerate() {
+        context
+            .storage
+            .lock()
+            .await
+            .load(uri, prog, revision as i32);
+        context
+            .diagnostics
+            .schedule(&context, uri.clone(), DEBOUNCE);
+    }
+    // runs start waiting once first polled
+    tokio::task::yield_now().await;
+    tokio::time::advance(DEBOUNCE / 2).await;
+    tokio::task::yield_now().await;
+    assert!(context.client.published.lock().unwrap().is_empty());
+    tokio::time::advance(DEBOUNCE / 2).await;
+    tokio::task::yield_now().await;
+
+    // the first run was superseded before it got to publish anything
+    let published = context.client.published.lock().unwrap();
+    assert_eq!(published.len(), 1);
+    assert_eq!(published[0].diagnostics.len(), 2);
+    assert_eq!(published[0].version, Some(1));
+}
+
+#[tokio::test]
+async fn test_pull_diagnostics() {
+    let uri = &file_uri("/tmp_path.bt");
+    let context = init_context();
+    context.storage.lock().await.load(uri, "BEGIN { $x; }", 0);
+
+    let DocumentDiagnosticReport::Full(report) =
+        document_diagnostic(&context, uri, None).await.unwrap()
+    else {
+        panic!("expected a full report");
+    };
+    let report = report.full_document_diagnostic_report;
+    assert_eq!(report.items.len(), 1);
+
+    let unchanged = document_diagnostic(&context, uri, report.result_id.clone())
+        .await
+        .unwrap();
+    assert!(matches!(unchanged, DocumentDiagnosticReport::Unchanged(..)));
+
+    context.storage.lock().await.load(uri, "BEGIN { }", 1);
+    let changed = document_diagnostic(&context, uri, report.result_id)
+        .await
+        .unwrap();
+    assert!(matches!(changed, DocumentDiagnosticReport::Full(..)));
+}
+
+#[tokio::test]
+async fn test_pull_diagnostics_project_file() {
+    let root = std::env::temp_dir().join(format!("btls-pull-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let project_file = root.join(".btls.toml");
+    std::fs::write(&project_file, "").unwrap();
+    let uri = &Url::from_file_path(root.join("script.bt")).unwrap();
+    let context = init_context();
+    context.storage.lock().await.load(uri, "BEGIN { $x; }", 0);
+
+    let DocumentDiagnosticReport::Full(report) =
+        document_diagnostic(&context, uri, None).await.unwrap()
+    else {
+        panic!("expected a full report");
+    };
+    let result_id = report.full_document_diagnostic_report.result_id;
+
+    std::fs::write(&project_file, "[severities]\nundefined-ident = \"off\"\n").unwrap();
+    // make sure the change shows even where modification times are coarse
+    std::fs::File::options()
+        .write(true)
+        .open(&project_file)
+        .unwrap()
+        .set_modified(std::time::SystemTime::now() + Duration::from_secs(10))
+        .unwrap();
+    let changed = document_diagnostic(&context, uri, result_id).await.unwrap();
+    std::fs::remove_dir_all(&root).unwrap();
+    let DocumentDiagnosticReport::Full(report) = changed else {
+        panic!("expected a full report");
+    };
+    assert!(report.full_document_diagnostic_report.items.is_empty());
+}
+
+#[tokio::test]
+async fn test_unused_lints() {
+    let prog = r#"
+        BEGIN {
+            $unused = 1;
+            $read = 2;
+            $incremented = 0;
+            $incremented++;
+            @written = 1;
+            @aggregated[comm] = count();
+            @per_comm[comm]++;
+            @total += 1;
+            @printed = 1;
+            @counted++;
+            @deleted[tid] = $read;
+            delete(@deleted[tid]);
+            @cleared = 1;
+            clear(@cleared);
+        }
+        END {
+            print(@printed);
+            for ($kv : @counted) {}
+        }"#;
+
+    let uri = &file_uri("/tmp_path");
+    let context = init_context();
+    context.storage.lock().await.load(uri, prog, 0);
+    let analyzed = context.analyzer.analyze(&context, uri).await.unwrap();
+
+    let lints = analyzed
+        .ast()
+        .errors()
+        .map(|e| e.diagnosis())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        lints,
+        [
+            r#"Scratch variable "$unused" is never read"#,
+            r#"Scratch variable "$incremented" is never read"#,
+            r#"Scratch variable "$kv" is never read"#,
+            r#"Map "@written" is written but never read"#,
+        ]
+    );
+}
+
+/// The text of a snippet with its placeholders replaced by their defaults.
+fn expand_snippet(body: &str) -> String {
+    let mut text = String::new();
+    let mut rest = body;
+    while let Some(start) = rest.find('$') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        if let Some(placeholder) = rest.strip_prefix('{') {
+            let end = placeholder.find('}').unwrap();
+            let (_, default) = placeholder[..end].split_once(':').unwrap_or(("", ""));
+            text.push_str(default);
+            rest = &placeholder[end + 1..];
+        } else {
+            rest = rest.trim_start_matches(|x: char| x.is_ascii_digit());
+        }
+    }
+    text + rest
+}
+
+#[tokio::test]
+async fn test_snippets_diagnostics() {
+    let context = init_context();
+    for snippet in SNIPPETS {
+        let uri = &file_uri(format!("/tmp/{}.bt", snippet.label));
+        // `print-interval` prints a map some other probe fills
+        let text = format!("BEGIN {{ @ = count(); }}\n{}", expand_snippet(snippet.body));
+        context.storage.lock().await.load(uri, &text, 0);
+        let analyzed = context.analyzer.analyze(&context, uri).await.unwrap();
+        let errors = analyzed
+            .ast()
+            .errors()
+            .map(|e| e.diagnosis())
+            .collect::<Vec<_>>();
+        assert!(errors.is_empty(), "{}: {errors:?}", snippet.label);
+    }
+}
+
+#[tokio::test]
+async fn test_map_read_before_write() {
+    let prog = r#"
+        BEGIN {
+            print(@total);
+        }
+        kprobe:vfs_read {
+            @start[tid] = nsecs;
+            @total = @total + 1;
+        }
+        kretprobe:vfs_read {
+            @lat = nsecs - @start[tid];
+            print(@lat);
+            delete(@start[tid]);
+        }
+        kretprobe:vfs_write {
+            print(@start[tid]);
+        }
+        tracepoint:syscalls:sys_exit_read /@start[tid]/ {
+            print(@start[tid]);
+        }
+        tracepoint:syscalls:sys_exit_write {
+            if (@start[tid]) {
+                print(@start[tid]);
+            }
+            print(@start[pid + 1]);
+        }"#;
+
+    let uri = &file_uri("/tmp_path");
+    let context = init_context();
+    context.storage.lock().await.load(uri, prog, 0);
+    let analyzed = context.analyzer.analyze(&context, uri).await.unwrap();
+
+    let lints = analyzed
+        .ast()
+        .errors()
+        .map(|e| e.diagnosis())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        lints,
+        [
+            r#"Map "@total" is read in BEGIN, before any probe writing it runs"#,
+            r#"No entry probe matching this return probe writes "@start[tid]""#,
+            r#"Map "@start[pid + 1]" may be read before it is written, consider a filter like /@start[pid + 1]/"#,
+        ]
+    );
+}
+
+/// Edits of the code actions offered at a position.
+async fn quick_fixes(context: &Context, uri: &Url, position: Position) -> Vec<(Position, String)> {
+    code_actions(context, uri, position, CodeActionContext::default())
+        .await
+        .into_iter()
+        .flat_map(|(_, edits)| edits)
+        .map(|edit| (edit.range.start, edit.new_text))
+        .collect()
+}
+
+/// Titles and edits of the code actions offered at a position.
+async fn code_actions(
+    context: &Context,
+    uri: &Url,
+    position: Position,
+    action_context: CodeActionContext,
+) -> Vec<(String, Vec<TextEdit>)> {
+    let params = CodeActionParams {
+        text_document: TextDocumentIdentifier { uri: uri.clone() },
+        range: Range::new(position, position),
+        context: action_context,
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    };
+    let actions = code_action_provider::code_actions(context, &params)
+        .await
+        .unwrap()
+        .unwrap_or_default();
+    actions
+        .into_iter()
+        .filter_map(|action| match action {
+            CodeActionOrCommand::CodeAction(action) => {
+                Some((action.title, action.edit?.changes?.remove(uri)?))
+            }
+            CodeActionOrCommand::Command(_) => None,
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_map_leak() {
+    let prog = r#"
+        kprobe:vfs_read {
+            @start[tid] = nsecs;
+            @bytes[pid] = 1;
+            @reads[comm] = count();
+        }
+        kretprobe:vfs_read /@start[tid]/ {
+            print(@start[tid]);
+        }
+        uprobe:bash:readline {
+            @line[tid] = 1;
+        }
+        uretprobe:bash:readline /@line[tid]/ { print(@line[tid]); }
+        interval:s:1 {
+            print(@bytes);
+            print(@reads);
+            clear(@bytes);
+        }"#;
+
+    let uri = &file_uri("/tmp/leak.bt");
+    let context = init_context();
+    context.storage.lock().await.load(uri, prog, 0);
+    let analyzed = context.analyzer.analyze(&context, uri).await.unwrap();
+
+    let lints = analyzed
+        .ast()
+        .errors()
+        .map(|e| e.diagnosis())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        lints,
+        [
+            r#"Map "@start" is keyed by tid but never deleted, it grows until max_map_keys is hit"#,
+            r#"Map "@line" is keyed by tid but never deleted, it grows until max_map_keys is hit"#,
+        ]
+    );
+
+    assert_eq!(
+        quick_fixes(&context, uri, Position::new(2, 13)).await,
+        [(
+            Position::new(8, 0),
+            "            delete(@start[tid]);\n".to_string()
+        )]
+    );
+    assert_eq!(
+        quick_fixes(&context, uri, Position::new(10, 13)).await,
+        [(Position::new(12, 66), "delete(@line[tid]); ".to_string())]
+    );
+    assert_eq!(quick_fixes(&context, uri, Position::new(3, 13)).await, []);
+}
+
+#[tokio::test]
+async fn test_quick_fixes() {
+    let prog = r#"
+        BEGIN {
+            $count = 1;
+            @total = 1;
+            print($cuont + @totl + nsec);
+            prnt($missing);
+            what is this
+        }"#;
+
+    let uri = &file_uri("/tmp/fixes.bt");
+    let context = init_context();
+    context.storage.lock().await.load(uri, prog, 0);
+
+    let titles = |actions: Vec<(String, Vec<TextEdit>)>| {
+        actions
+            .into_iter()
+            .map(|(title, edits)| {
+                let edits = edits
+                    .into_iter()
+                    .map(|x| (x.range.start.line, x.range.start.character, x.new_text))
+                    .collect::<Vec<_>>();
+                (title, edits)
+            })
+            .collect::<Vec<_>>()
+    };
+    let at = |line, character| {
+        code_actions(
+            &context,
+            uri,
+            Position::new(line, character),
+            CodeActionContext::default(),
+        )
+    };
+    let edit = |line, character, text: &str| vec![(line, character, text.to_string())];
+
+    assert_eq!(
+        titles(at(4, 20).await),
+        [
+            ("Change to `$count`".to_string(), edit(4, 19, "count")),
+            (
+                "Declare `$cuont = 0;` at top of probe".to_string(),
+                edit(2, 0, "            $cuont = 0;\n")
+            ),
+        ]
+    );
+    assert_eq!(
+        titles(at(4, 29).await),
+        [("Change to `@total`".to_string(), edit(4, 28, "total"))]
+    );
+    assert_eq!(
+        titles(at(4, 35).await),
+        [("Change to `nsecs`".to_string(), edit(4, 35, "nsecs"))]
+    );
+    assert_eq!(
+        titles(at(5, 12).await),
+        [("Change to `print`".to_string(), edit(5, 12, "print"))]
+    );
+    assert_eq!(
+        titles(at(5, 18).await),
+        [
+            // the call spans its arguments too
+            ("Change to `print`".to_string(), edit(5, 12, "print")),
+            (
+                "Declare `$missing = 0;` at top of probe".to_string(),
+                edit(2, 0, "            $missing = 0;\n")
+            ),
+        ]
+    );
+    assert_eq!(
+        titles(at(6, 14).await),
+        [("Remove unknown statement".to_string(), edit(6, 0, ""))]
+    );
+
+    // fixes travel in the data of the diagnostics sent back by the client
+    let analyzed = context.analyzer.analyze(&context, uri).await.unwrap();
+    let config = context.config(uri).await;
+    let diagnostics = diagnostics(&analyzed, &config)
+        .into_iter()
+        .filter(|x| x.message.contains("cuont"))
+        .collect::<Vec<_>>();
+    assert!(diagnostics[0].data.is_some());
+    let action_context = CodeActionContext {
+        diagnostics,
+        ..Default::default()
+    };
+    assert_eq!(
+        titles(code_actions(&context, uri, Position::new(0, 0), action_context).await)[0],
+        ("Change to `$count`".to_string(), edit(4, 19, "count"))
+    );
+}
+
+/// Applies the code action with the given title offered for a range, and
+/// returns the resulting text.
+async fn apply_code_action(context: &Context, uri: &Url, range: Range, title: &str) -> String {
+    let params = CodeActionParams {
+        text_document: TextDocumentIdentifier { uri: uri.clone() },
+        range,
+        context: CodeActionContext::default(),
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    };
+    let actions = code_action_provider::code_actions(context, &params)
+        .await
+        .unwrap()
+        .unwrap_or_default();
+    let titles = actions
+        .iter()
+        .filter_map(|x| match x {
+            CodeActionOrCommand::CodeAction(action) => Some(action.title.as_str()),
+            CodeActionOrCommand::Command(_) => None,
+        })
+        .collect::<Vec<_>>();
+    let Some(CodeActionOrCommand::CodeAction(action)) = actions
+        .iter()
+        .find(|x| matches!(x, CodeActionOrCommand::CodeAction(action) if action.title == title))
+    else {
+        panic!("no action {title:?} in {titles:?}");
+    };
+
+    let document = context.storage.lock().await.read(uri);
+    let mut edits = action
+        .edit
+        .clone()
+        .unwrap()
+        .changes
+        .unwrap()
+        .remove(uri)
+        .unwrap();
+    edits.sort_by_key(|x| x.range.start);
+    let mut text = document.data.to_string();
+    for edit in edits.iter().rev() {
+        let start = document.line_index.offset(edit.range.start).unwrap();
+        let end = document.line_index.offset(edit.range.end).unwrap();
+        text.replace_range(start..end, &edit.new_text);
+    }
+    text
+}
+
+#[tokio::test]
+async fn test_refactors() {
+    let context = init_context();
+    let uri = &file_uri("/tmp/refactor.bt");
+    let at = |line, start, end| Range::new(Position::new(line, start), Position::new(line, end));
+
+    let prog = r#"
+        BEGIN {
+            $start = nsecs;
+            $d = 1 + 2;
+            printf("%d %d\n", nsecs - $start, $d * 3);
+        }"#;
+    context.storage.lock().await.load(uri, prog, 0);
+    assert_eq!(
+        apply_code_action(&context, uri, at(4, 30, 44), "Extract to `$value`").await,
+        r#"
+        BEGIN {
+            $start = nsecs;
+            $d = 1 + 2;
+            $value = nsecs - $start;
+            printf("%d %d\n", $value, $d * 3);
+        }"#
+    );
+    // `nsecs` would be read after `$d` is assigned, not before
+    assert!(
+        !code_actions(&context, uri, Position::new(2, 13), Default::default())
+            .await
+            .iter()
+            .any(|(title, _)| title == "Inline `$start`")
+    );
+    assert_eq!(
+        apply_code_action(&context, uri, at(4, 47, 47), "Inline `$d`").await,
+        r#"
+        BEGIN {
+            $start = nsecs;
+            printf("%d %d\n", nsecs - $start, (1 + 2) * 3);
+        }"#
+    );
+
+    let prog = r#"
+        kprobe:f {
+            if (pid == 1) {
+                print(comm);
+            }
+        }"#;
+    let filtered = r#"
+        kprobe:f /pid == 1/ {
+            print(comm);
+        }"#;
+    context.storage.lock().await.load(uri, prog, 1);
+    assert_eq!(
+        apply_code_action(&context, uri, at(2, 14, 14), "Convert `if` to probe filter").await,
+        filtered
+    );
+    context.storage.lock().await.load(uri, filtered, 2);
+    assert_eq!(
+        apply_code_action(&context, uri, at(1, 8, 8), "Convert probe filter to `if`").await,
+        prog
+    );
+
+    let prog = r#"
+        kprobe:a, kprobe:b /pid/ { print(1); }"#;
+    context.storage.lock().await.load(uri, prog, 3);
+    assert_eq!(
+        apply_code_action(
+            &context,
+            uri,
+            at(1, 8, 8),
+            "Split into one probe per attach point"
+        )
+        .await,
+        r#"
+        kprobe:a /pid/ { print(1); }
+
+        kprobe:b /pid/ { print(1); }"#
+    );
+}
+
+#[tokio::test]
+async fn test_completion() {
+    let prog = r#"
+        BEGIN {
+            $count = 1;
+            @counts = 1;
+            print($co + @co + co);
+        }
+        kprobe:vfs_read, kprobe:vfs_write { $other = 1; }"#;
+    let uri = &file_uri("/tmp/completion.bt");
+    let context = init_context();
+    context.storage.lock().await.load(uri, prog, 0);
+
+    let labels = |line, character| {
+        let context = &context;
+        async move {
+            let Some(CompletionResponse::Array(items)) =
+                completion_provider::completion(context, uri, Position::new(line, character))
+                    .await
+                    .unwrap()
+            else {
+                return vec![];
+            };
+            let mut items = items
+                .into_iter()
+                .map(|x| (x.sort_text.unwrap(), x.label))
+                .collect::<Vec<_>>();
+            items.sort();
+            items
+                .into_iter()
+                .map(|(_, label)| label)
+                .collect::<Vec<_>>()
+        }
+    };
+
+    assert_eq!(labels(4, 21).await, ["$count"]);
+    assert_eq!(labels(4, 27).await, ["@counts"]);
+    assert_eq!(
+        labels(4, 32).await,
+        [
+            "comm",
+            "count",
+            "$count",
+            "@counts",
+            "socket_cookie",
+            "strcontains"
+        ]
+    );
+    assert_eq!(
+        labels(6, 9).await[..4],
+        ["kprobe", "kretprobe", "kprobe", "kretprobe"]
+    );
+    assert_eq!(labels(6, 26).await, ["kprobe", "kretprobe"]);
+
+    *context.settings.write().await = serde_json::json!({
+        "snippets": [{ "label": "trace", "body": "kprobe:$1 { $0 }" }],
+    });
+    context.configs.write().await.clear();
+    let labels_at_start = labels(1, 8).await;
+    assert_eq!(
+        labels_at_start.len(),
+        PROBE_PROVIDERS.len() + SNIPPETS.len() + 1
+    );
+    assert_eq!(labels_at_start.last().unwrap(), "trace");
+
+    let kernel = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/kernel");
+    *context.settings.write().await = serde_json::json!({
+        "tracefs_path": kernel.join("tracing"),
+        "kallsyms_path": kernel.join("kallsyms"),
+    });
+    context.configs.write().await.clear();
+    let prog = "BEGIN {}\nkprobe:vfs_r\nt:sched\nkprobe:\nBEGIN {} kretprobe:vfs_w";
+    context.storage.lock().await.load(uri, prog, 1);
+    assert_eq!(
+        labels(1, 12).await,
+        [
+            "kprobe:vfs_read",
+            "kprobe:vfs_read_iter",
+            "kprobe:vfs_readf"
+        ]
+    );
+    assert_eq!(
+        labels(2, 7).await,
+        ["t:sched:sched_switch", "t:sched:sched_wakeup"]
+    );
+    assert!(labels(3, 7).await.is_empty());
+    assert_eq!(labels(4, 24).await, ["kretprobe:vfs_write"]);
+}
+
+#[tokio::test]
+async fn test_inlay_hints() {
+    let prog = r#"
+        kprobe:vfs_read {
+            @start[tid] = nsecs;
+            @reads[pid, comm]++;
+        }
+        kretprobe:vfs_read {
+            $lat = nsecs - @start[tid];
+            $big = $lat > 100;
+            $lat = 0;
+            @ns = lhist($lat, 0, 100, 10);
+            @hist = hist($lat);
+            delete(@start[tid]);
+        }"#;
+    let uri = &file_uri("/tmp/inlay_hints.bt");
+    let context = init_context();
+    context.storage.lock().await.load(uri, prog, 0);
+
+    let hints = |range| {
+        let context = &context;
+        async move {
+            inlay_hint_provider::inlay_hints(context, uri, range)
+                .await
+                .unwrap()
+                .unwrap()
+                .into_iter()
+                .map(|x| match x.label {
+                    InlayHintLabel::String(label) => (x.position.line, x.position.character, label),
+                    _ => panic!("expected a plain label"),
+                })
+                .collect::<Vec<_>>()
+        }
+    };
+    let everything = Range::new(Position::new(0, 0), Position::new(13, 0));
+    assert_eq!(
+        hints(everything).await,
+        [
+            (2, 23, ": map[uint32]timestamp".to_string()),
+            (3, 29, ": map[uint32, string[16]]int64".to_string()),
+            (6, 16, ": uint64".to_string()),
+            (7, 16, ": bool".to_string()),
+            (9, 15, ": lhist_t".to_string()),
+            (9, 30, "min:".to_string()),
+            (9, 33, "max:".to_string()),
+            (9, 38, "step:".to_string()),
+            (10, 17, ": hist_t".to_string()),
+        ]
+    );
+    assert_eq!(
+        hints(Range::new(Position::new(7, 0), Position::new(8, 0))).await,
+        [(7, 16, ": bool".to_string())]
+    );
+
+    *context.settings.write().await = serde_json::json!({
+        "inlay_hints": { "variable_types": false, "map_types": false },
+    });
+    context.configs.write().await.clear();
+    assert_eq!(hints(everything).await.len(), 3);
+}
+
+#[tokio::test]
+async fn test_folding_and_selection_ranges() {
+    let prog = r#"
+// count reads
+// per process
+kprobe:vfs_read
+{
+    if (pid > 1) {
+        @reads[comm] = count();
+    }
+}
+BEGIN { }"#;
+    let uri = &file_uri("/tmp/ranges.bt");
+    let context = init_context();
+    context.storage.lock().await.load(uri, prog, 0);
+
+    let folds = folding_range_provider::folding_ranges(&context, uri)
+        .await
+        .unwrap()
+        .unwrap()
+        .into_iter()
+        .map(|x| (x.start_line, x.end_line, x.kind.unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        folds,
+        [
+            (1, 2, FoldingRangeKind::Comment),
+            (3, 7, FoldingRangeKind::Region),
+            (5, 6, FoldingRangeKind::Region),
+        ]
+    );
+
+    let selections =
+        selection_range_provider::selection_ranges(&context, uri, vec![Position::new(6, 18)])
+            .await
+            .unwrap()
+            .unwrap();
+    let line_index = OwnedLineIndex::new(Arc::new(prog.to_string()));
+    let mut selection = Some(&selections[0]);
+    let mut texts = vec![];
+    while let Some(x) = selection {
+        let start = line_index.offset(x.range.start).unwrap();
+        let end = line_index.offset(x.range.end).unwrap();
+        texts.push(&prog[start..end]);
+        selection = x.parent.as_deref();
+    }
+    assert_eq!(
+        texts,
+        [
+            "comm",
+            "@reads[comm]",
+            "@reads[comm] = count()",
+            "{\n        @reads[comm] = count();\n    }",
+            "if (pid > 1) {\n        @reads[comm] = count();\n    }",
+            "{\n    if (pid > 1) {\n        @reads[comm] = count();\n    }\n}",
+            "kprobe:vfs_read\n{\n    if (pid > 1) {\n        @reads[comm] = count();\n    }\n}",
+            prog,
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_workspace_symbols() {
+    let root = std::env::temp_dir().join(format!("btls-workspace-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("net")).unwrap();
+    std::fs::create_dir_all(root.join(".git")).unwrap();
+    std::fs::write(
+        root.join("net").join("retrans.bt"),
+        "kprobe:tcp_retransmit_skb { @inflight[tid] = 1; @retrans++; }",
+    )
+    .unwrap();
+    std::fs::write(
+        root.join(".git").join("hidden.bt"),
+        "BEGIN { @inflight = 1; }",
+    )
+    .unwrap();
+    std::fs::write(
+        root.join("net").join("helpers.bt"),
+        "fn sock_port($sk: int64): int64 { @ports = 1; return $sk; }",
+    )
+    .unwrap();
+    std::fs::write(root.join("notes.txt"), "kprobe:tcp_retransmit_skb").unwrap();
+    let _ = std::fs::remove_file(root.join("net").join("loop"));
+    std::os::unix::fs::symlink(&root, root.join("net").join("loop")).unwrap();
+
+    let context = init_context();
+    context.workspace.set_folders(vec![WorkspaceFolder {
+        uri: Url::from_file_path(&root).unwrap(),
+        name: "workspace".to_string(),
+    }]);
+    context.workspace.scan(&context.storage).await;
+
+    let symbols = |query: &'static str| {
+        let context = &context;
+        async move {
+            context
+                .workspace
+                .symbols(&context.storage, query)
+                .await
+                .into_iter()
+                .map(|x| {
+                    let file = x.location.uri.to_file_path().unwrap();
+                    let file = file.file_name().unwrap().to_string_lossy().into_owned();
+                    (x.name, x.container_name, file)
+                })
+                .collect::<Vec<_>>()
+        }
+    };
+    assert_eq!(
+        symbols("retrans").await,
+        [
+            (
+                "kprobe:tcp_retransmit_skb".to_string(),
+                None,
+                "retrans.bt".to_string()
+            ),
+            (
+                "@retrans".to_string(),
+                Some("kprobe:tcp_retransmit_skb".to_string()),
+                "retrans.bt".to_string()
+            ),
+        ]
+    );
+
+    assert_eq!(
+        symbols("@ports").await,
+        [(
+            "@ports".to_string(),
+            Some("sock_port".to_string()),
+            "helpers.bt".to_string()
+        )]
+    );
+    let functions = context.workspace.symbols(&context.storage, "sock").await;
+    assert_eq!(functions.len(), 1);
+    assert_eq!(functions[0].name, "sock_port");
+    assert_eq!(functions[0].kind, SymbolKind::FUNCTION);
+
+    // open documents are indexed as they are edited
+    let open = file_uri(root.join("open.bt"));
+    context
+        .storage
+        .lock()
+        .await
+        .load(&open, "BEGIN { @Inflight = 0; }", 0);
+    assert_eq!(symbols("@INFLIGHT").await.len(), 2);
+    context.storage.lock().await.load(&open, "BEGIN { }", 1);
+    assert_eq!(symbols("@inflight").await.len(), 1);
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn test_folder_settings() {
+    let root = std::env::temp_dir().join(format!("btls-folders-{}", std::process::id()));
+    let inner = root.join("inner");
+    let (root_uri, inner_uri) = (
+        Url::from_file_path(&root).unwrap(),
+        Url::from_file_path(&inner).unwrap(),
+    );
+    let folder = |uri: &Url| WorkspaceFolder {
+        uri: uri.clone(),
+        name: uri.path().to_string(),
+    };
+
+    let context = init_context();
+    context
+        .client
+        .supports_configuration
+        .store(true, std::sync::atomic::Ordering::Relaxed);
+    *context.client.scoped_settings.lock().unwrap() = vec![
+        (
+            Some(root_uri.clone()),
+            serde_json::json!({ "bpftrace_version": "0.20", "include_paths": ["include"] }),
+        ),
+        (
+            Some(inner_uri.clone()),
+            serde_json::json!({ "bpftrace_version": "0.21" }),
+        ),
+    ];
+    context
+        .workspace
+        .set_folders(vec![folder(&root_uri), folder(&inner_uri)]);
+
+    let config = context.config(&file_uri(root.join("a.bt"))).await;
+    assert_eq!(
+        config.bpftrace_version,
+        Some(config::Version::new(0, 20, 0))
+    );
+    assert_eq!(config.include_paths, [root.join("include")]);
+    let config = context.config(&file_uri(inner.join("b.bt"))).await;
+    assert_eq!(
+        config.bpftrace_version,
+        Some(config::Version::new(0, 21, 0))
+    );
+    let config = context.config(&file_uri("/elsewhere/c.bt")).await;
+    assert_eq!(config.bpftrace_version, None);
+
+    context
+        .workspace
+        .change_folders(WorkspaceFoldersChangeEvent {
+            added: vec![],
+            removed: vec![folder(&inner_uri)],
+        });
+    context.configs.write().await.clear();
+    let config = context.config(&file_uri(inner.join("b.bt"))).await;
+    assert_eq!(
+        config.bpftrace_version,
+        Some(config::Version::new(0, 20, 0))
+    );
+}
+
+#[tokio::test]
+async fn test_invalid_settings() {
+    let backend = Backend {
+        context: Arc::new(init_context()),
+    };
+    let context = &backend.context;
+    let uri = &file_uri("/tmp/settings.bt");
+    *context.settings.write().await = serde_json::json!({ "arch": "aarch64" });
+    assert_eq!(context.config(uri).await.arch, "aarch64");
+
+    backend
+        .did_change_configuration(DidChangeConfigurationParams {
+            settings: serde_json::json!({ "btls": { "arch": 64, "include_paths": "include" } }),
+        })
+        .await;
+    assert_eq!(*context.config(uri).await, config::Config::default());
+    let shown = context.client.shown.lock().unwrap();
+    assert_eq!(shown.len(), 1);
+    assert_eq!(shown[0].0, MessageType::ERROR);
+    assert!(
+        shown[0]
+            .1
+            .starts_with("btls: invalid settings, using defaults: ")
+    );
+}
+
+#[tokio::test]
+async fn test_in_memory_documents() {
+    let untitled = Url::parse("untitled:Untitled-1").unwrap();
+    let remote = Url::parse("vscode-remote://ssh-remote+host/home/me/tools/trace.bt").unwrap();
+    let context = Arc::new(init_context());
+    context
+        .client
+        .supports_configuration
+        .store(true, std::sync::atomic::Ordering::Relaxed);
+    let folder = Url::parse("vscode-remote://ssh-remote+host/home/me/tools").unwrap();
+    *context.client.scoped_settings.lock().unwrap() = vec![(
+        Some(folder.clone()),
+        serde_json::json!({ "bpftrace_version": "0.21" }),
+    )];
+    context.workspace.set_folders(vec![WorkspaceFolder {
+        uri: folder,
+        name: "tools".to_string(),
+    }]);
+
+    // nothing to read on disk until they're opened
+    assert!(context.analyzer.analyze(&context, &untitled).await.is_err());
+
+    for uri in [&untitled, &remote] {
+        context
+            .storage
+            .lock()
+            .await
+            .load(uri, "BEGIN { $x; @count++; }", 0);
+        context
+            .diagnostics
+            .schedule(&context, uri.clone(), Duration::ZERO);
+    }
+    tokio::time::sleep(DEBOUNCE).await;
+    let published = context.client.published.lock().unwrap().clone();
+    let mut uris = published.iter().map(|x| &x.uri).collect::<Vec<_>>();
+    uris.sort();
+    assert_eq!(uris, [&untitled, &remote]);
+    assert!(published.iter().all(|x| !x.diagnostics.is_empty()));
+
+    let Some(CompletionResponse::Array(items)) =
+        completion_provider::completion(&context, &untitled, Position::new(0, 13))
+            .await
+            .unwrap()
+    else {
+        panic!("expected completions");
+    };
+    assert!(items.iter().any(|x| x.label == "@count"));
+
+    // remote documents get the settings of the folder they're in
+    let config = context.config(&remote).await;
+    assert_eq!(
+        config.bpftrace_version,
+        Some(config::Version::new(0, 21, 0))
+    );
+    let config = context.config(&untitled).await;
+    assert_eq!(config.bpftrace_version, None);
+
+    let symbols = context.workspace.symbols(&context.storage, "count").await;
+    assert_eq!(symbols.len(), 2);
+}
+
+#[tokio::test]
+async fn test_headers() {
+    let prog = r#"#include <linux/path.h>
+#include "missing.h"
+struct Foo { int a; struct path *p; };
+#define LIMIT 10
+
+kretprobe:vfs_open {
+    $p = (struct path *)retval;
+    print($p->dentry->d_name.nme);
+    print(((struct Foo *)retval)->a + LIMIT);
+    print(((struct Foo *)retval)->p->mnt);
+}"#;
+    let sysroot = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sysroot");
+    let uri = &file_uri("/tmp/headers.bt");
+    let context = init_context();
+    *context.settings.write().await = serde_json::json!({
+        "include_paths": [sysroot.join("usr/include")],
+    });
+    context.storage.lock().await.load(uri, prog, 0);
+    let analyzed = context.analyzer.analyze(&context, uri).await.unwrap();
+
+    let errors = analyzed
+        .ast()
+        .errors()
+        .map(|e| e.diagnosis())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        errors,
+        [
+            r#"Cannot find header "missing.h" in the include paths"#,
+            r#"struct qstr has no field "nme""#,
+        ]
+    );
+    assert_eq!(
+        analyzed.includes[0],
+        Some(sysroot.join("usr/include/linux/path.h"))
+    );
+
+    let position = |needle: &str| {
+        let offset = prog.find(needle).unwrap() + needle.len();
+        analyzed.document.line_index.position(offset)
+    };
+    let Some(CompletionResponse::Array(items)) =
+        completion_provider::completion(&context, uri, position("$p->dentry->d_"))
+            .await
+            .unwrap()
+    else {
+        panic!("expected completions");
+    };
+    let labels = items.iter().map(|x| x.label.as_str()).collect::<Vec<_>>();
+    assert_eq!(labels, ["d_flags", "d_parent", "d_name", "d_iname"]);
+    assert_eq!(items[1].detail.as_deref(), Some("struct dentry *"));
+
+    let hover = |needle| {
+        let context = &context;
+        async move {
+            let hover = hover_provider::hover(context, uri, position(needle))
+                .await
+                .unwrap()?;
+            match hover.contents {
+                HoverContents::Markup(x) => Some(x.value),
+                _ => panic!("expected markdown"),
+            }
+        }
+    };
+    assert_eq!(
+        hover("$p->dent").await.unwrap(),
+        "```c\nstruct dentry *dentry\n```\nField of `struct path`"
+    );
+    assert_eq!(
+        hover("((struct Fo").await.unwrap(),
+        "```c\nstruct Foo { int a; struct path *p; }\n```"
+    );
+    assert!(hover("+ LIM").await.unwrap().contains("#define LIMIT 10"));
+    assert!(
+        hover("#include <linux/pa")
+            .await
+            .unwrap()
+            .ends_with("path.h`")
+    );
+    assert!(hover("kretprobe:vfs_").await.is_none());
+}
+
+#[tokio::test]
+async fn test_definitions() {
+    let prog = r#"#include <linux/path.h>
+#include "missing.h"
+struct Foo { int a; };
+
+kretprobe:vfs_open {
+    print(((struct path *)retval)->dentry->d_flags);
+    print(((struct Foo *)retval)->a);
+    print(((struct task *)arg0)->pid);
+}"#;
+    let include = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sysroot/usr/include");
+    let btf = std::env::temp_dir().join("btls-definitions-vmlinux");
+    std::fs::write(&btf, crate::btf::tests::task()).unwrap();
+    let uri = &file_uri("/tmp/definitions.bt");
+    let context = init_context();
+    *context.settings.write().await =
+        serde_json::json!({ "include_paths": [include], "btf_path": btf });
+    context.storage.lock().await.load(uri, prog, 0);
+
+    let definition = |line, character| {
+        let context = &context;
+        async move {
+            let response =
+                definition_provider::definition(context, uri, Position::new(line, character))
+                    .await
+                    .unwrap()?;
+            let GotoDefinitionResponse::Scalar(location) = response else {
+                panic!("expected a single location");
+            };
+            let path = location.uri.to_file_path().unwrap();
+            Some((
+                path,
+                location.range.start.line,
+                location.range.start.character,
+            ))
+        }
+    };
+    let path_h = include.join("linux/path.h");
+    let dcache_h = include.join("linux/dcache.h");
+    assert_eq!(definition(0, 14).await, Some((path_h.clone(), 0, 0)));
+    assert_eq!(definition(1, 14).await, None);
+    assert_eq!(definition(5, 23).await, Some((path_h.clone(), 7, 7)));
+    assert_eq!(definition(5, 37).await, Some((path_h, 9, 16)));
+    assert_eq!(definition(5, 47).await, Some((dcache_h, 17, 14)));
+    assert_eq!(
+        definition(6, 20).await,
+        Some((Path::new("/tmp/definitions.bt").to_path_buf(), 2, 7))
+    );
+    assert_eq!(definition(6, 5).await, None);
+    let (generated, line, character) = definition(7, 21).await.unwrap();
+    assert!(generated.ends_with("btls-btf/struct_task.h"));
+    assert_eq!((line, character), (1, 7));
+    let text = std::fs::read_to_string(generated).unwrap();
+    assert!(text.contains("struct task {\n\tint pid;\n"));
+
+    let links = definition_provider::document_links(&context, uri)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(links.len(), 1);
+    assert_eq!(
+        links[0].range,
+        Range::new(Position::new(0, 10), Position::new(0, 22))
+    );
+}
+
+#[tokio::test]
+async fn test_positional_params() {
+    let prog = r#"// Traces opens of a process.
+// @param $1 int the PID to trace
+// @param $2 str path prefix
+kprobe:vfs_open /pid == $1/ {
+    printf("%s %d\n", str($2), $#);
+    print($2);
+    print(str($1));
+    print($4);
+}
+BEGIN { $1 = 2; }"#;
+    let uri = &file_uri("/tmp/params.bt");
+    let context = init_context();
+    context.storage.lock().await.load(uri, prog, 0);
+    let analyzed = context.analyzer.analyze(&context, uri).await.unwrap();
+
+    let errors = analyzed
+        .ast()
+        .errors()
+        .map(|e| e.diagnosis())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        errors,
+        [
+            "Positional parameter $1 can't be assigned to",
+            "Positional parameter $1 is declared as an integer but used as a string",
+            "Positional parameter $2 is declared as a string, use str($2)",
+            "Positional parameter $4 is used but $3 is not",
+        ]
+    );
+    let config = context.config(uri).await;
+    let severities = diagnostics(&analyzed, &config)
+        .into_iter()
+        .map(|x| x.severity.unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        severities,
+        [
+            DiagnosticSeverity::ERROR,
+            DiagnosticSeverity::WARNING,
+            DiagnosticSeverity::WARNING,
+            DiagnosticSeverity::WARNING,
+        ]
+    );
+
+    let hover = |line, character| {
+        let context = &context;
+        async move {
+            let hover = hover_provider::hover(context, uri, Position::new(line, character))
+                .await
+                .unwrap()
+                .unwrap();
+            match hover.contents {
+                HoverContents::Markup(x) => x.value,
+                _ => panic!("expected markdown"),
+            }
+        }
+    };
+    assert_eq!(hover(3, 24).await, "```\n$1: int\n```\nthe PID to trace");
+    assert!(
+        hover(4, 32)
+            .await
+            .contains("number of positional parameters")
+    );
+    assert!(hover(7, 11).await.ends_with("not declared"));
+
+    let Some(CompletionResponse::Array(items)) =
+        completion_provider::completion(&context, uri, Position::new(4, 32))
+            .await
+            .unwrap()
+    else {
+        panic!("expected completions");
+    };
+    let labels = items.iter().map(|x| x.label.as_str()).collect::<Vec<_>>();
+    assert_eq!(labels, ["$1", "$2"]);
+
+    assert_eq!(
+        code_actions(
+            &context,
+            uri,
+            Position::new(5, 11),
+            CodeActionContext::default()
+        )
+        .await,
+        [(
+            "Change to `str($2)`".to_string(),
+            vec![TextEdit::new(
+                Range::new(Position::new(5, 10), Position::new(5, 12)),
+                "str($2)".to_string()
+            )]
+        )]
+    );
+
+    // undeclared parameters are only checked against each other
+    let prog = "BEGIN { print($3 + $1); print(str($1)); }";
+    context.storage.lock().await.load(uri, prog, 1);
+    let analyzed = context.analyzer.analyze(&context, uri).await.unwrap();
+    let errors = analyzed
+        .ast()
+        .errors()
+        .map(|e| e.diagnosis())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        errors,
+        [
+            "Positional parameter $1 is used both as an integer and as a string",
+            "Positional parameter $3 is used but $2 is not",
+        ]
+    );
+
+    *context.settings.write().await = serde_json::json!({
+        "positional_params": [{ "type": "int" }, { "type": "int" }],
+    });
+    context.configs.write().await.clear();
+    let analyzed = context.analyzer.analyze(&context, uri).await.unwrap();
+    let errors = analyzed
+        .ast()
+        .errors()
+        .map(|e| e.diagnosis())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        errors,
+        [
+            "Positional parameter $1 is declared as an integer but used as a string",
+            "Positional parameter $3 is not declared",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_getopt_options() {
+    let prog = r#"BEGIN {
+    printf("%d\n", getopt("limit", 10));
+    if (getopt("verbose")) { print(getopt("iface", "eth0")); }
+}
+END { print(getopt("limit", -1)); print(getopt("iface", "eth0")); }"#;
+    let uri = &file_uri("/tmp/getopt.bt");
+    let context = init_context();
+    context.storage.lock().await.load(uri, prog, 0);
+    let analyzed = context.analyzer.analyze(&context, uri).await.unwrap();
+
+    let table = options::table(analyzed.ast())
+        .iter()
+        .map(|x| x.to_json())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        table,
+        [
+            serde_json::json!({ "name": "limit", "type": "int64", "default": 10 }),
+            serde_json::json!({ "name": "verbose", "type": "bool", "default": false }),
+            serde_json::json!({ "name": "iface", "type": "string", "default": "eth0" }),
+        ]
+    );
+    let errors = analyzed
+        .ast()
+        .errors()
+        .map(|e| e.diagnosis())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        errors,
+        [
+            r#"Option "--limit" has another default than where it is first read (--limit: int64 = 10)"#
+        ]
+    );
+
+    let hover = hover_provider::hover(&context, uri, Position::new(4, 23))
+        .await
+        .unwrap()
+        .unwrap();
+    let HoverContents::Markup(hover) = hover.contents else {
+        panic!("expected markdown");
+    };
+    assert_eq!(
+        hover.value,
+        "```\n--limit: int64 = 10\n```\nRead with `getopt()`"
+    );
+
+    let symbols = context
+        .workspace
+        .file_symbols(&context.storage, uri)
+        .await
+        .into_iter()
+        .map(|x| (x.name, x.location.range.start.line))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        symbols,
+        [
+            ("BEGIN".to_string(), 0),
+            ("END".to_string(), 4),
+            ("--limit".to_string(), 1),
+            ("--verbose".to_string(), 2),
+            ("--iface".to_string(), 2),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_bpftrace_version() {
+    let prog = r#"BEGIN {
+    @m[1] = getopt("limit", 10);
+    for ($kv : @m) { print($kv); }
+    let $i = 0;
+    while ($i < 3) { $i++; }
+    unroll (2) { $i++; }
+}
+fn double($x: int64): int64 { return $x * 2; }"#;
+    let uri = &file_uri("/tmp/version.bt");
+    let context = init_context();
+    context.storage.lock().await.load(uri, prog, 0);
+    let errors = || async {
+        let analyzed = context.analyzer.analyze(&context, uri).await.unwrap();
+        analyzed
+            .ast()
+            .errors()
+            .filter(|e| e.code() == "unavailable-feature")
+            .map(|e| e.diagnosis())
+            .collect::<Vec<_>>()
+    };
+    assert!(errors().await.is_empty());
+
+    *context.settings.write().await = serde_json::json!({ "bpftrace_version": "0.19" });
+    context.configs.write().await.clear();
+    assert_eq!(
+        errors().await,
+        [
+            "`getopt` requires bpftrace 0.23.0 or later, but 0.19.0 is targeted",
+            "`fn` requires bpftrace 0.20.0 or later, but 0.19.0 is targeted",
+            "`for` requires bpftrace 0.21.0 or later, but 0.19.0 is targeted",
+            "`let` requires bpftrace 0.22.0 or later, but 0.19.0 is targeted",
+        ]
+    );
+
+    *context.settings.write().await = serde_json::json!({ "bpftrace_version": "0.23.1" });
+    context.configs.write().await.clear();
+    assert!(errors().await.is_empty());
+
+    let hover = hover_provider::hover(&context, uri, Position::new(1, 14))
+        .await
+        .unwrap()
+        .unwrap();
+    let HoverContents::Markup(hover) = hover.contents else {
+        panic!("expected markdown");
+    };
+    assert!(hover.value.ends_with("Available since bpftrace 0.23.0."));
+}
+
+#[tokio::test]
+async fn test_user_functions() {
+    let prog = r#"fn scale($v: int64, $by: int64): int64 { return $v * $by; }
+BEGIN { $x = scale(2, 3); print($x); print(unknown(1)); }"#;
+    let uri = &file_uri("/tmp/functions.bt");
+    let context = init_context();
+    context.storage.lock().await.load(uri, prog, 0);
+    let analyzed = context.analyzer.analyze(&context, uri).await.unwrap();
+    assert_eq!(
+        analyzed
+            .ast()
+            .errors()
+            .map(|e| e.diagnosis())
+            .collect::<Vec<_>>(),
+        [r#"Undefined function "unknown""#]
+    );
+
+    let hover = hover_provider::hover(&context, uri, Position::new(1, 14))
+        .await
+        .unwrap()
+        .unwrap();
+    let HoverContents::Markup(hover) = hover.contents else {
+        panic!("expected markdown");
+    };
+    assert_eq!(
+        hover.value,
+        "```\nfn scale($v: int64, $by: int64): int64\n```\nDefined in this script"
+    );
+}
+
+#[tokio::test]
+async fn test_numbered_builtins() {
+    let prog = r#"kprobe:vfs_read { printf("%d %d\n", arg0, sarg1); print(arg6); }
+tracepoint:syscalls:sys_enter_read { print(arg0); }
+kprob:do_sys_open { print(arg1); }
+u:bash:readline { print(arg01); }
+self:signal:SIGUSR1, aw:addr:8:w { exit(); }"#;
+    let uri = &file_uri("/tmp/numbered.bt");
+    let context = init_context();
+    *context.settings.write().await = serde_json::json!({ "arch": "x86_64" });
+    context.storage.lock().await.load(uri, prog, 0);
+    let analyzed = context.analyzer.analyze(&context, uri).await.unwrap();
+    let errors = analyzed
+        .ast()
+        .errors()
+        .map(|e| e.diagnosis())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        errors,
+        [
+            "Undefined Identifier \"arg01\"",
+            "`arg6` is out of range, x86_64 has `arg0` to `arg5`",
+            "`arg0` is not available in `tracepoint` probes",
+            "Unknown probe provider `kprob`",
+        ]
+    );
+    assert_eq!(
+        quick_fixes(&context, uri, Position::new(2, 2)).await,
+        [(Position::new(2, 0), "kprobe".to_string())]
+    );
+
+    let Some(CompletionResponse::Array(items)) =
+        completion_provider::completion(&context, uri, Position::new(0, 59))
+            .await
+            .unwrap()
+    else {
+        panic!("expected completions");
+    };
+    let labels = items
+        .iter()
+        .map(|x| x.label.as_str())
+        .filter(|x| x.contains("arg"))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        labels,
+        [
+            "args", "arg0", "arg1", "arg2", "arg3", "arg4", "arg5", "sarg0"
+        ]
+    );
+    let item = items.into_iter().find(|x| x.label == "arg3").unwrap();
+    assert!(item.detail.is_none() && item.documentation.is_none());
+    let resolved = completion_provider::resolve(item);
+    assert_eq!(resolved.detail.as_deref(), Some("uint64"));
+    assert!(resolved.documentation.is_some());
+}
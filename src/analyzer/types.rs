@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+
+use crate::builtins::{BUILTINS, RETURN_TYPES};
+use crate::headers::Definitions;
+use crate::parser::{
+    AssignOp, Block, Expr, FieldAccess, Function, IdentKind, Identifier, Loop, Lvalue, Node,
+    Preamble, Program, Statement, Walk,
+};
+
+/// Types inferred for the variables of a program. Inference is best effort,
+/// it is meant for showing types rather than checking them, so variables
+/// whose type can't be told are left out.
+#[derive(Debug)]
+pub struct Types<'a, 'b> {
+    /// Scratch variables at their first assignment in a probe.
+    pub variables: Vec<(&'b Identifier<'a>, String)>,
+    /// Maps at their first assignment in the program.
+    pub maps: Vec<MapType<'a, 'b>>,
+    /// Every field access, along with the type of what it's accessed on.
+    pub fields: Vec<(&'b FieldAccess<'a>, Option<String>)>,
+    /// The functions the script defines.
+    functions: Vec<&'b Function<'a>>,
+    definitions: &'b Definitions,
+}
+
+#[derive(Debug)]
+pub struct MapType<'a, 'b> {
+    pub ident: &'b Identifier<'a>,
+    /// One per key expression, `None` where unknown.
+    pub keys: Vec<Option<String>>,
+    pub value: Option<String>,
+}
+
+impl MapType<'_, '_> {
+    /// The type as in `map[uint32, string]uint64`, or just the value type
+    /// for maps without keys.
+    pub fn describe(&self) -> Option<String> {
+        let value = self.value.as_deref()?;
+        if self.keys.is_empty() {
+            return Some(value.to_string());
+        }
+        let keys = self
+            .keys
+            .iter()
+            .map(|x| x.as_deref().unwrap_or("?"))
+            .collect::<Vec<_>>();
+        Some(format!("map[{}]{value}", keys.join(", ")))
+    }
+}
+
+/// Infers the types of a program, records and typedefs being looked up in
+/// `definitions`.
+pub fn infer<'a, 'b>(program: &'b Program<'a>, definitions: &'b Definitions) -> Types<'a, 'b> {
+    let mut types = Types {
+        variables: Vec::new(),
+        maps: Vec::new(),
+        fields: Vec::new(),
+        functions: program
+            .preambles
+            .iter()
+            .filter_map(|x| match x {
+                Preamble::Function(function) => Some(function),
+                _ => None,
+            })
+            .collect(),
+        definitions,
+    };
+    for preamble in &program.preambles {
+        match preamble {
+            Preamble::Probe(probe) => {
+                let mut scope = HashMap::new();
+                if let Some(condition) = &probe.condition {
+                    types.visit(condition, &scope);
+                }
+                types.infer_block(&probe.block, &mut scope);
+            }
+            Preamble::Function(function) => {
+                let mut scope = function
+                    .params
+                    .iter()
+                    .map(|x| (x.ident.name, x.ty.to_string()))
+                    .collect();
+                types.infer_block(&function.block, &mut scope);
+            }
+            Preamble::Include(_) | Preamble::Definition(_) | Preamble::Error(_) => {}
+        }
+    }
+    types
+}
+
+impl<'a, 'b> Types<'a, 'b> {
+    /// Records the field accesses of an expression.
+    fn visit(&mut self, expr: &'b Expr<'a>, scope: &HashMap<&'a str, String>) {
+        for node in Walk::new(expr.as_node()) {
+            if let Some(Expr::Field(field)) = node.as_expr() {
+                let base = self.type_of(&field.expr, scope);
+                self.fields.push((field, base));
+            }
+        }
+    }
+
+    fn infer_block(&mut self, block: &'b Block<'a>, scope: &mut HashMap<&'a str, String>) {
+        for stmt in &block.statements {
+            match stmt {
+                Statement::Assignment(assign) => {
+                    let Lvalue::Identifier(ident) = &assign.lvalue;
+                    self.visit(&assign.rvalue, scope);
+                    for key in &ident.keys {
+                        self.visit(key, scope);
+                    }
+                    let value = match assign.op {
+                        AssignOp::Assign => self.type_of(&assign.rvalue, scope),
+                        AssignOp::AddAssign | AssignOp::SubAssign => Some("int64".to_string()),
+                    };
+                    self.define(ident, value, scope);
+                }
+                // `@m++` defines the map as well
+                Statement::Expr(expr) => {
+                    self.visit(expr, scope);
+                    if let Expr::UnaryExpr(unary) = expr.as_ref()
+                        && let Expr::Identifier(ident) = unary.expr.as_ref()
+                    {
+                        self.define(ident, Some("int64".to_string()), scope);
+                    }
+                }
+                Statement::Loop(loop_stmt) => match loop_stmt.as_ref() {
+                    Loop::For(for_loop) => {
+                        self.visit(&for_loop.rhs, scope);
+                        self.infer_block(&for_loop.block, &mut scope.clone());
+                    }
+                    Loop::While(w) => {
+                        self.visit(&w.condition, scope);
+                        self.infer_block(&w.block, &mut scope.clone());
+                    }
+                    Loop::Unroll(u) => {
+                        self.visit(&u.count, scope);
+                        self.infer_block(&u.block, &mut scope.clone());
+                    }
+                },
+                Statement::IfCond(if_cond) => {
+                    self.visit(&if_cond.condition, scope);
+                    self.infer_block(&if_cond.block, &mut scope.clone());
+                }
+                Statement::Return(ret) => {
+                    if let Some(value) = &ret.value {
+                        self.visit(value, scope);
+                    }
+                }
+                Statement::Error(_) => {}
+            }
+        }
+    }
+
+    fn define(
+        &mut self,
+        ident: &'b Identifier<'a>,
+        value: Option<String>,
+        scope: &mut HashMap<&'a str, String>,
+    ) {
+        match ident.kind {
+            IdentKind::Scratch => {
+                if let Some(value) = value
+                    && !scope.contains_key(ident.name)
+                {
+                    scope.insert(ident.name, value.clone());
+                    self.variables.push((ident, value));
+                }
+            }
+            IdentKind::Map => {
+                if !self.maps.iter().any(|x| x.ident.name == ident.name) {
+                    let keys = ident.keys.iter().map(|x| self.type_of(x, scope)).collect();
+                    self.maps.push(MapType { ident, keys, value });
+                }
+            }
+            IdentKind::Bare | IdentKind::Positional => {}
+        }
+    }
+
+    fn type_of(&self, expr: &Expr<'a>, scope: &HashMap<&'a str, String>) -> Option<String> {
+        match expr {
+            Expr::Integer(_) => Some("int64".to_string()),
+            Expr::String(_) => Some("string".to_string()),
+            Expr::Identifier(ident) => match ident.kind {
+                IdentKind::Scratch => scope.get(ident.name).cloned(),
+                // read as a string only by `str()`
+                IdentKind::Positional => Some("int64".to_string()),
+                IdentKind::Map => self
+                    .maps
+                    .iter()
+                    .find(|x| x.ident.name == ident.name)?
+                    .value
+                    .clone(),
+                IdentKind::Bare => BUILTINS
+                    .keywords
+                    .iter()
+                    .find(|x| x.name == ident.name)
+                    .or_else(|| BUILTINS.numbered(ident.name).map(|(x, _)| &x.symbol))
+                    .map(|x| x.detail.to_string()),
+            },
+            Expr::Call(call) => match self
+                .functions
+                .iter()
+                .find(|x| x.name.name == call.func.name)
+            {
+                Some(function) => function.return_type.map(|x| x.to_string()),
+                None => RETURN_TYPES
+                    .iter()
+                    .find(|(name, _)| *name == call.func.name)
+                    .map(|(_, ty)| ty.to_string()),
+            },
+            Expr::BinaryExpr(bin) => match bin.operator() {
+                "==" | "!=" | "<" | "<=" | ">" | ">=" | "&&" | "||" => Some("bool".to_string()),
+                _ => {
+                    let lhs = self.type_of(&bin.lhs, scope)?;
+                    let rhs = self.type_of(&bin.rhs, scope)?;
+                    if !is_integer(&lhs) || !is_integer(&rhs) {
+                        return None;
+                    }
+                    let unsigned = is_unsigned(&lhs) || is_unsigned(&rhs);
+                    Some(if unsigned { "uint64" } else { "int64" }.to_string())
+                }
+            },
+            Expr::UnaryExpr(unary) => match unary.operator() {
+                "!" => Some("bool".to_string()),
+                "++" | "--" => self.type_of(&unary.expr, scope),
+                _ => Some("int64".to_string()),
+            },
+            Expr::Cast(cast) => Some(normalize(cast.ty)),
+            Expr::Field(field) => {
+                let base = self.type_of(&field.expr, scope)?;
+                let record = self.definitions.record(&base)?;
+                let field = record.fields.iter().find(|x| x.name == field.field.name)?;
+                Some(field.ty.clone())
+            }
+        }
+    }
+}
+
+/// Spells a C type the way headers are scanned, as in `struct path *`.
+pub fn normalize(ty: &str) -> String {
+    let stars = ty.matches('*').count();
+    let words = ty
+        .split(|c: char| c.is_whitespace() || c == '*')
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    match stars {
+        0 => words,
+        _ => format!("{words} {}", "*".repeat(stars)),
+    }
+}
+
+fn is_integer(ty: &str) -> bool {
+    ty == "bool" || ty.starts_with("int") || is_unsigned(ty)
+}
+
+/// `nsecs` is a `timestamp`, which is a `uint64` as far as arithmetic goes.
+fn is_unsigned(ty: &str) -> bool {
+    ty.starts_with("uint") || ty == "timestamp"
+}
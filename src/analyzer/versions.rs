@@ -0,0 +1,86 @@
+use super::providers;
+use crate::builtins::{BUILTINS, BuiltinSymbol, PROBE_PROVIDERS, SYNTAX, unalias};
+use crate::config::Version;
+use crate::parser::{
+    Expr, IdentKind, Loop, Node, Preamble, Program, Statement, UnavailableFeature, Walk,
+};
+use pest::Span;
+
+/// Reports builtins, probe providers and syntax the targeted bpftrace
+/// version doesn't have yet, or no longer has.
+pub fn check<'a>(program: &Program<'a>, target: Version, errors: &mut Vec<Statement<'a>>) {
+    let mut report = |symbol: &BuiltinSymbol, text: &'a str, span: Span<'a>| {
+        if !symbol.available_in(target) {
+            errors.push(UnavailableFeature::new(
+                text,
+                span,
+                symbol.since,
+                symbol.until,
+                target,
+            ));
+        }
+    };
+
+    // the keywords of statements and preambles, with where they start
+    let mut keywords = vec![];
+    for preamble in &program.preambles {
+        let probe = match preamble {
+            Preamble::Probe(probe) => probe,
+            Preamble::Function(function) => {
+                keywords.push(("fn", function.span));
+                continue;
+            }
+            _ => continue,
+        };
+        for span in providers::providers(probe) {
+            let provider = unalias(span.as_str());
+            if let Some(symbol) = PROBE_PROVIDERS.iter().find(|x| x.name == provider) {
+                report(symbol, span.as_str(), span);
+            }
+        }
+    }
+
+    for node in Walk::new(program.as_node()) {
+        match node.as_statement() {
+            Some(Statement::Loop(looped)) => {
+                keywords.push(match looped.as_ref() {
+                    Loop::While(x) => ("while", x.span),
+                    Loop::For(x) => ("for", x.span),
+                    Loop::Unroll(x) => ("unroll", x.span),
+                });
+                continue;
+            }
+            Some(Statement::Assignment(assign)) => {
+                keywords.extend(assign.declaration.map(|x| ("let", x)));
+                continue;
+            }
+            _ => {}
+        }
+        let (symbol, name, span) = match node.as_expr() {
+            Some(Expr::Call(call)) => {
+                let symbol = BUILTINS.functions.iter().find(|x| x.name == call.func.name);
+                (symbol, call.func.name, call.func.span)
+            }
+            Some(Expr::Identifier(ident)) if ident.kind == IdentKind::Bare => {
+                let symbol = BUILTINS
+                    .keywords
+                    .iter()
+                    .find(|x| x.name == ident.name)
+                    .or_else(|| BUILTINS.numbered(ident.name).map(|(x, _)| &x.symbol));
+                (symbol, ident.name, ident.span)
+            }
+            _ => continue,
+        };
+        if let Some(symbol) = symbol {
+            report(symbol, name, span);
+        }
+    }
+
+    for (keyword, span) in keywords {
+        let symbol = SYNTAX.iter().find(|x| x.name == keyword);
+        let span = Span::new(span.get_input(), span.start(), span.start() + keyword.len());
+        if let (Some(symbol), Some(span)) = (symbol, span) {
+            report(symbol, keyword, span);
+        }
+    }
+}
@@ -0,0 +1,441 @@
+use crate::headers::{self, DefinitionKind};
+use anyhow::{Result, bail};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+const MAGIC: u16 = 0xeb9f;
+
+/// A type of the kernel as described by BTF. References to other types are
+/// their ids, 0 being `void`.
+#[derive(Debug)]
+enum Type {
+    /// Integers, floats and anything else only known by name.
+    Named(String),
+    Pointer(u32),
+    Array {
+        ty: u32,
+        len: u32,
+    },
+    Record {
+        union: bool,
+        name: String,
+        members: Vec<Member>,
+    },
+    Enum {
+        name: String,
+        values: Vec<(String, i64)>,
+    },
+    /// A struct or union declared but not defined.
+    Forward {
+        union: bool,
+        name: String,
+    },
+    Typedef {
+        name: String,
+        ty: u32,
+    },
+    /// `const`, `volatile` or `restrict`.
+    Qualified {
+        qualifier: &'static str,
+        ty: u32,
+    },
+    FunctionProto {
+        returns: u32,
+        params: Vec<u32>,
+    },
+    /// Functions, variables, sections and tags, which aren't types.
+    Other,
+}
+
+#[derive(Debug)]
+struct Member {
+    name: String,
+    ty: u32,
+    /// The width of a bitfield.
+    bits: Option<u32>,
+}
+
+/// The types of a BTF file, like `/sys/kernel/btf/vmlinux`.
+#[derive(Debug)]
+pub struct Btf {
+    types: Vec<Type>,
+}
+
+impl Btf {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let u16_at = |offset: usize| -> Result<u16> {
+            match data.get(offset..offset + 2) {
+                Some(x) => Ok(u16::from_le_bytes(x.try_into().unwrap())),
+                None => bail!("truncated BTF"),
+            }
+        };
+        let u32_at = |offset: usize| -> Result<u32> {
+            match data.get(offset..offset + 4) {
+                Some(x) => Ok(u32::from_le_bytes(x.try_into().unwrap())),
+                None => bail!("truncated BTF"),
+            }
+        };
+        if u16_at(0)? != MAGIC {
+            bail!("not a little-endian BTF file");
+        }
+        let header_len = u32_at(4)? as usize;
+        let types_start = header_len + u32_at(8)? as usize;
+        let types_end = types_start + u32_at(12)? as usize;
+        let strings_start = header_len + u32_at(16)? as usize;
+        let strings_end = strings_start + u32_at(20)? as usize;
+        let Some(strings) = data.get(strings_start..strings_end) else {
+            bail!("truncated BTF");
+        };
+        let string = |offset: u32| -> String {
+            let rest = strings.get(offset as usize..).unwrap_or_default();
+            let end = rest.iter().position(|x| *x == 0).unwrap_or(rest.len());
+            String::from_utf8_lossy(&rest[..end]).into_owned()
+        };
+
+        let mut types = vec![];
+        let mut offset = types_start;
+        while offset < types_end {
+            let name = string(u32_at(offset)?);
+            let info = u32_at(offset + 4)?;
+            let size_or_type = u32_at(offset + 8)?;
+            offset += 12;
+            let count = (info & 0xffff) as usize;
+            let kind_flag = info >> 31 == 1;
+            let ty = match (info >> 24) & 0x1f {
+                // integers and floats
+                1 | 16 => {
+                    offset += if (info >> 24) & 0x1f == 1 { 4 } else { 0 };
+                    Type::Named(name)
+                }
+                2 => Type::Pointer(size_or_type),
+                3 => {
+                    let ty = u32_at(offset)?;
+                    let len = u32_at(offset + 8)?;
+                    offset += 12;
+                    Type::Array { ty, len }
+                }
+                kind @ (4 | 5) => {
+                    let mut members = vec![];
+                    for _ in 0..count {
+                        let bitfield = u32_at(offset + 8)? >> 24;
+                        members.push(Member {
+                            name: string(u32_at(offset)?),
+                            ty: u32_at(offset + 4)?,
+                            bits: (kind_flag && bitfield != 0).then_some(bitfield),
+                        });
+                        offset += 12;
+                    }
+                    Type::Record {
+                        union: kind == 5,
+                        name,
+                        members,
+                    }
+                }
+                6 => {
+                    let mut values = vec![];
+                    for _ in 0..count {
+                        let value = u32_at(offset + 4)?;
+                        // the flag tells signed values apart
+                        let value = match kind_flag {
+                            true => value as i32 as i64,
+                            false => value as i64,
+                        };
+                        values.push((string(u32_at(offset)?), value));
+                        offset += 8;
+                    }
+                    Type::Enum { name, values }
+                }
+                7 => Type::Forward {
+                    union: kind_flag,
+                    name,
+                },
+                8 => Type::Typedef {
+                    name,
+                    ty: size_or_type,
+                },
+                kind @ 9..=11 => Type::Qualified {
+                    qualifier: match kind {
+                        9 => "volatile",
+                        10 => "const",
+                        _ => "restrict",
+                    },
+                    ty: size_or_type,
+                },
+                12 | 18 => Type::Other,
+                13 => {
+                    let mut params = vec![];
+                    for _ in 0..count {
+                        params.push(u32_at(offset + 4)?);
+                        offset += 8;
+                    }
+                    Type::FunctionProto {
+                        returns: size_or_type,
+                        params,
+                    }
+                }
+                14 | 17 => {
+                    offset += 4;
+                    Type::Other
+                }
+                15 => {
+                    offset += 12 * count;
+                    Type::Other
+                }
+                19 => {
+                    let mut values = vec![];
+                    for _ in 0..count {
+                        let low = u32_at(offset + 4)? as u64;
+                        let high = u32_at(offset + 8)? as u64;
+                        values.push((string(u32_at(offset)?), (high << 32 | low) as i64));
+                        offset += 12;
+                    }
+                    Type::Enum { name, values }
+                }
+                kind => bail!("unknown BTF kind {kind}"),
+            };
+            types.push(ty);
+        }
+        Ok(Self { types })
+    }
+
+    fn get(&self, id: u32) -> Option<&Type> {
+        self.types.get((id as usize).checked_sub(1)?)
+    }
+
+    /// The C source defining a struct, union, enum or typedef, `None` if
+    /// the kernel doesn't have it.
+    pub fn source(&self, kind: DefinitionKind, name: &str) -> Option<String> {
+        let ty = self.types.iter().find(|x| match (kind, x) {
+            (DefinitionKind::Struct, Type::Record { union, name: x, .. }) => !union && x == name,
+            (DefinitionKind::Union, Type::Record { union, name: x, .. }) => *union && x == name,
+            (DefinitionKind::Enum, Type::Enum { name: x, .. }) => x == name,
+            (DefinitionKind::Typedef, Type::Typedef { name: x, .. }) => x == name,
+            _ => false,
+        })?;
+        Some(match ty {
+            Type::Typedef { name, ty } => format!("typedef {};\n", self.declare(*ty, name, 0)),
+            ty => format!("{};\n", self.body(ty, 0)),
+        })
+    }
+
+    /// The keyword, name and members of a record or enum.
+    fn body(&self, ty: &Type, depth: usize) -> String {
+        let indent = "\t".repeat(depth + 1);
+        let mut text = String::new();
+        match ty {
+            Type::Record {
+                union,
+                name,
+                members,
+            } => {
+                let keyword = if *union { "union" } else { "struct" };
+                text.push_str(format!("{keyword} {name}").trim_end());
+                text.push_str(" {\n");
+                for member in members {
+                    let declaration = self.declare(member.ty, &member.name, depth + 1);
+                    match member.bits {
+                        Some(bits) => writeln!(text, "{indent}{declaration}: {bits};").unwrap(),
+                        None => writeln!(text, "{indent}{declaration};").unwrap(),
+                    }
+                }
+            }
+            Type::Enum { name, values } => {
+                text.push_str(format!("enum {name}").trim_end());
+                text.push_str(" {\n");
+                for (name, value) in values {
+                    writeln!(text, "{indent}{name} = {value},").unwrap();
+                }
+            }
+            _ => return String::new(),
+        }
+        text + &"\t".repeat(depth) + "}"
+    }
+
+    /// A declaration of `name` as the type `id`, the way C spells it: `char
+    /// *comm[16]` or `void (*func)(int)`. Anonymous records and enums are
+    /// spelled out, indented for `depth`.
+    fn declare(&self, id: u32, name: &str, depth: usize) -> String {
+        let join = |ty: &str| match name {
+            "" => ty.to_string(),
+            name => format!("{ty} {name}"),
+        };
+        let Some(ty) = self.get(id) else {
+            return join("void");
+        };
+        match ty {
+            Type::Named(x) | Type::Typedef { name: x, .. } => join(x),
+            Type::Record { name: x, .. } | Type::Enum { name: x, .. } if x.is_empty() => {
+                join(&self.body(ty, depth))
+            }
+            Type::Record { union, name: x, .. } | Type::Forward { union, name: x } => {
+                join(&format!("{} {x}", if *union { "union" } else { "struct" }))
+            }
+            Type::Enum { name: x, .. } => join(&format!("enum {x}")),
+            Type::Pointer(inner) => {
+                let name = match self.get(*inner) {
+                    Some(Type::Array { .. } | Type::FunctionProto { .. }) => format!("(*{name})"),
+                    _ => format!("*{name}"),
+                };
+                self.declare(*inner, &name, depth)
+            }
+            Type::Array { ty, len } => self.declare(*ty, &format!("{name}[{len}]"), depth),
+            Type::Qualified { qualifier, ty } => match self.get(*ty) {
+                Some(Type::Pointer(_)) => self.declare(*ty, &format!("{qualifier} {name}"), depth),
+                _ => format!("{qualifier} {}", self.declare(*ty, name, depth)),
+            },
+            Type::FunctionProto { returns, params } => {
+                let params = params
+                    .iter()
+                    .map(|x| self.declare(*x, "", depth))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                self.declare(*returns, &format!("{name}({params})"), depth)
+            }
+            Type::Other => join("void"),
+        }
+    }
+}
+
+/// Parsed BTF along with the modification time it was read at.
+type CachedBtf = (Option<SystemTime>, Arc<Btf>);
+
+/// BTF files read so far, reused for as long as they aren't modified.
+#[derive(Default)]
+pub struct BtfCache {
+    files: Mutex<HashMap<PathBuf, CachedBtf>>,
+}
+
+impl BtfCache {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn read(&self, file: &Path) -> Result<Arc<Btf>> {
+        let modified = headers::modified(file);
+        if let Some((cached, btf)) = self.files.lock().unwrap().get(file)
+            && *cached == modified
+        {
+            return Ok(btf.clone());
+        }
+        let btf = Arc::new(Btf::parse(&std::fs::read(file)?)?);
+        self.files
+            .lock()
+            .unwrap()
+            .insert(file.to_path_buf(), (modified, btf.clone()));
+        Ok(btf)
+    }
+
+    /// Writes the definition of a kernel type to a generated header, for
+    /// clients to open like any other, and returns its path.
+    pub fn document(&self, file: &Path, kind: DefinitionKind, name: &str) -> Option<PathBuf> {
+        let source = self.read(file).ok()?.source(kind, name)?;
+        let keyword = kind.keyword().unwrap_or("typedef");
+        let dir = std::env::temp_dir().join("btls-btf");
+        let path = dir.join(format!("{keyword}_{name}.h"));
+        let text = format!("// Generated from the BTF of {}\n{source}", file.display());
+        std::fs::create_dir_all(&dir).ok()?;
+        std::fs::write(&path, text).ok()?;
+        Some(path)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Builds BTF data from `(name, info, size or type, extra words)` types,
+    /// the names being among `names`.
+    fn encode(types: &[(&str, u32, u32, &[u32])], names: &[&str]) -> Vec<u8> {
+        let mut strings = vec![0u8];
+        let mut offsets = HashMap::from([("", 0)]);
+        for name in names {
+            offsets.insert(name, strings.len() as u32);
+            strings.extend(name.as_bytes());
+            strings.push(0);
+        }
+        let mut section = vec![];
+        for (name, info, size, extra) in types {
+            for word in [offsets[name], *info, *size].iter().chain(extra.iter()) {
+                section.extend(word.to_le_bytes());
+            }
+        }
+        let mut data = vec![];
+        data.extend(MAGIC.to_le_bytes());
+        data.extend([1, 0]);
+        for word in [24, 0, section.len() as u32, section.len() as u32] {
+            data.extend(word.to_le_bytes());
+        }
+        data.extend((strings.len() as u32).to_le_bytes());
+        data.extend(section);
+        data.extend(strings);
+        data
+    }
+
+    /// A string offset in the data `encode` builds from the same names.
+    fn name(types: &[&str], name: &str) -> u32 {
+        let mut offset = 1;
+        for x in types {
+            if *x == name {
+                return offset;
+            }
+            offset += x.len() as u32 + 1;
+        }
+        unreachable!()
+    }
+
+    /// `struct task { int pid; char comm[16]; struct task *parent; unsigned
+    /// int flags: 3; }` along with `typedef struct task task_t`.
+    pub(crate) fn task() -> Vec<u8> {
+        let names = [
+            "int", "char", "task", "task_t", "pid", "comm", "parent", "flags",
+        ];
+        let at = |x| name(&names, x);
+        encode(
+            &[
+                ("int", 1 << 24, 4, &[32]),
+                ("char", 1 << 24, 1, &[8]),
+                ("", 3 << 24, 0, &[2, 1, 16]),
+                (
+                    "task",
+                    4 << 24 | 1 << 31 | 4,
+                    32,
+                    &[
+                        at("pid"),
+                        1,
+                        0,
+                        at("comm"),
+                        3,
+                        32,
+                        at("parent"),
+                        5,
+                        192,
+                        at("flags"),
+                        1,
+                        3 << 24 | 256,
+                    ],
+                ),
+                ("", 2 << 24, 4, &[]),
+                ("task_t", 8 << 24, 4, &[]),
+            ],
+            &names,
+        )
+    }
+
+    #[test]
+    fn test_source() {
+        let btf = Btf::parse(&task()).unwrap();
+        assert_eq!(
+            btf.source(DefinitionKind::Struct, "task").unwrap(),
+            "struct task {\n\tint pid;\n\tchar comm[16];\n\tstruct task *parent;\n\tint flags: 3;\n};\n"
+        );
+        assert_eq!(
+            btf.source(DefinitionKind::Typedef, "task_t").unwrap(),
+            "typedef struct task task_t;\n"
+        );
+        assert!(btf.source(DefinitionKind::Union, "task").is_none());
+        assert!(Btf::parse(b"not btf").is_err());
+    }
+}
@@ -1,15 +1,250 @@
+use crate::config::Version;
+
 pub struct BuiltinSymbols {
     pub keywords: &'static [BuiltinSymbol],
+    /// Families of variables like `arg0`, `arg1`, ...
+    pub numbered: &'static [NumberedSymbol],
     pub functions: &'static [BuiltinSymbol],
 }
 
+impl BuiltinSymbols {
+    /// The family a variable like `arg2` belongs to, along with its number.
+    pub fn numbered(&self, name: &str) -> Option<(&'static NumberedSymbol, usize)> {
+        self.numbered
+            .iter()
+            .find_map(|x| Some((x, x.number(name)?)))
+    }
+}
+
 pub struct BuiltinSymbol {
     pub name: &'static str,
     pub detail: &'static str,
     pub documentation: &'static str,
+    /// The first bpftrace release having it, `None` if it's always been there.
+    pub since: Option<Version>,
+    /// The first bpftrace release not having it anymore.
+    pub until: Option<Version>,
+}
+
+impl BuiltinSymbol {
+    /// Whether a bpftrace release has it.
+    pub fn available_in(&self, version: Version) -> bool {
+        self.since.is_none_or(|x| x <= version) && self.until.is_none_or(|x| version < x)
+    }
+
+    /// The documentation, along with the releases having it when it wasn't
+    /// always there.
+    pub fn full_documentation(&self) -> String {
+        let availability = match (self.since, self.until) {
+            (Some(since), Some(until)) => format!("Available from bpftrace {since} until {until}."),
+            (Some(since), None) => format!("Available since bpftrace {since}."),
+            (None, Some(until)) => format!("Removed in bpftrace {until}."),
+            (None, None) => return self.documentation.to_string(),
+        };
+        format!(
+            "{}
+
+{availability}",
+            self.documentation
+        )
+    }
+
+    /// Names of the parameters of a function, taken from its signature in
+    /// `detail`, e.g. `min` for `lhist(int64 n, int64 min, ...)`. Variadic
+    /// parameters and ones without a name are left out.
+    pub fn parameters(&self) -> Vec<&'static str> {
+        let detail = self.detail;
+        let (Some(start), Some(end)) = (detail.find('('), detail.rfind(')')) else {
+            return Vec::new();
+        };
+        detail[start + 1..end]
+            .split(',')
+            .map(|x| x.split('=').next().unwrap_or(x))
+            .map(|x| x.trim_matches(|c: char| c.is_whitespace() || c == '[' || c == ']'))
+            .take_while(|x| !x.contains("..."))
+            .filter_map(|x| {
+                let (_, name) = x.rsplit_once([' ', '*'])?;
+                Some(name.trim_end_matches(['[', ']']))
+            })
+            .filter(|x| !x.is_empty())
+            .collect()
+    }
+}
+
+/// A family of variables named by a prefix and a number, as in `arg0`. The
+/// name of its symbol is the prefix.
+pub struct NumberedSymbol {
+    pub symbol: BuiltinSymbol,
+    /// Providers of the probes having them, unaliased.
+    pub providers: &'static [&'static str],
+    /// How many there are on an architecture, named as in
+    /// `std::env::consts::ARCH`. Unlimited on those left out.
+    pub limits: &'static [(&'static str, usize)],
+}
+
+impl NumberedSymbol {
+    /// The number of a variable of the family, e.g. 2 for `arg2`.
+    pub fn number(&self, name: &str) -> Option<usize> {
+        let digits = name.strip_prefix(self.symbol.name)?;
+        if digits.is_empty()
+            || !digits.bytes().all(|x| x.is_ascii_digit())
+            || (digits.len() > 1 && digits.starts_with('0'))
+        {
+            return None;
+        }
+        digits.parse().ok()
+    }
+
+    pub fn limit(&self, arch: &str) -> Option<usize> {
+        self.limits
+            .iter()
+            .find(|(x, _)| *x == arch)
+            .map(|(_, count)| *count)
+    }
+}
+
+/// Builtin variables and functions, generated from `builtins/bpftrace.toml`.
+pub const BUILTINS: BuiltinSymbols = include!(concat!(env!("OUT_DIR"), "/builtins.rs"));
+
+macro_rules! symbols {
+    ($($name:literal, $detail:literal, $documentation:literal;)*) => {
+        &[$(BuiltinSymbol {
+            name: $name,
+            detail: $detail,
+            documentation: $documentation,
+            since: None,
+            until: None,
+        }),*]
+    };
+}
+
+/// Probe types, which attach points start with.
+pub const PROBE_PROVIDERS: &[BuiltinSymbol] = include!(concat!(env!("OUT_DIR"), "/providers.rs"));
+
+/// Short names of providers, as in `k` for `kprobe`.
+pub const PROVIDER_ALIASES: &[(&str, &str)] =
+    include!(concat!(env!("OUT_DIR"), "/provider_aliases.rs"));
+
+/// The provider an alias stands for, or the provider itself.
+pub fn unalias(provider: &str) -> &str {
+    PROVIDER_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == provider)
+        .map_or(provider, |(_, name)| name)
+}
+
+/// Conversion specifiers of `printf` format strings.
+pub const FORMAT_SPECIFIERS: &[BuiltinSymbol] = symbols! {
+    "%d", "%d", "Signed decimal integer.";
+    "%u", "%u", "Unsigned decimal integer.";
+    "%x", "%x", "Unsigned hexadecimal integer, lowercase.";
+    "%X", "%X", "Unsigned hexadecimal integer, uppercase.";
+    "%o", "%o", "Unsigned octal integer.";
+    "%s", "%s", "String.";
+    "%c", "%c", "Character.";
+    "%p", "%p", "Pointer, in hexadecimal.";
+    "%lu", "%lu", "Unsigned long decimal integer.";
+    "%llu", "%llu", "Unsigned long long decimal integer.";
+    "%ld", "%ld", "Signed long decimal integer.";
+    "%lld", "%lld", "Signed long long decimal integer.";
+    "%lx", "%lx", "Unsigned long hexadecimal integer.";
+    "%llx", "%llx", "Unsigned long long hexadecimal integer.";
+    "%r", "%r", "Buffer, as printed by `buf()`.";
+    "%rx", "%rx", "Buffer, as hex bytes.";
+    "%rh", "%rh", "Buffer, as hex bytes separated by spaces.";
+    "%%", "%%", "A literal `%`.";
+};
+
+/// Statements and preambles that weren't always part of the language, checked
+/// against the targeted bpftrace release. `let` is only recognized when it
+/// initializes a variable: bare and typed declarations like `let $x: int64;`
+/// don't parse yet.
+pub const SYNTAX: &[BuiltinSymbol] = &[
+    BuiltinSymbol {
+        name: "unroll",
+        detail: "unroll (count) { ... }",
+        documentation: "Repeats a block a constant number of times.",
+        since: Some(Version::new(0, 9, 0)),
+        until: None,
+    },
+    BuiltinSymbol {
+        name: "while",
+        detail: "while (condition) { ... }",
+        documentation: "Runs a block for as long as a condition holds.",
+        since: Some(Version::new(0, 13, 0)),
+        until: None,
+    },
+    BuiltinSymbol {
+        name: "for",
+        detail: "for ($kv : @map) { ... }",
+        documentation: "Runs a block for each element of a map.",
+        since: Some(Version::new(0, 21, 0)),
+        until: None,
+    },
+    BuiltinSymbol {
+        name: "fn",
+        detail: "fn name($arg: type): type { ... }",
+        documentation: "Defines a function callable from probes.",
+        since: Some(Version::new(0, 20, 0)),
+        until: None,
+    },
+    BuiltinSymbol {
+        name: "let",
+        detail: "let $name = value;",
+        documentation: "Declares a variable scoped to the enclosing block.",
+        since: Some(Version::new(0, 22, 0)),
+        until: None,
+    },
+];
+
+/// A completion expanding to a whole piece of script. The body is in the
+/// LSP snippet syntax, with tab stops like `${1:default}`.
+pub struct Snippet {
+    pub label: &'static str,
+    pub detail: &'static str,
+    pub body: &'static str,
 }
 
-pub const BUILTINS: BuiltinSymbols = include!(concat!(
-    env!("CARGO_MANIFEST_DIR"),
-    "/target/builtins.gen.rs"
-));
+macro_rules! snippets {
+    ($($label:literal, $detail:literal, $body:expr;)*) => {
+        &[$(Snippet {
+            label: $label,
+            detail: $detail,
+            body: $body,
+        }),*]
+    };
+}
+
+/// Common idioms and a skeleton for each probe provider, offered where a new
+/// probe can start.
+pub const SNIPPETS: &[Snippet] = snippets! {
+    "latency", "Histogram of function latency",
+        "kprobe:${1:vfs_read} {\n\t@start[tid] = nsecs;\n}\n\nkretprobe:${1:vfs_read} /@start[tid]/ {\n\t@${2:ns} = hist(nsecs - @start[tid]);\n\tdelete(@start[tid]);\n}";
+    "syscount", "Count of syscalls per process",
+        "tracepoint:raw_syscalls:sys_enter {\n\t@${1:syscalls}[pid, comm] = count();\n}";
+    "print-interval", "Print and clear a map every second",
+        "interval:s:${1:1} {\n\tprint(@${2});\n\tclear(@${2});\n}";
+    "profile-stacks", "Count of sampled stacks",
+        "profile:hz:${1:99} {\n\t@[${2:kstack}] = count();\n}";
+    "BEGIN", "BEGIN probe", "BEGIN {\n\t$0\n}";
+    "END", "END probe", "END {\n\t$0\n}";
+    "kprobe", "kprobe probe", "kprobe:${1:function} {\n\t$0\n}";
+    "kretprobe", "kretprobe probe", "kretprobe:${1:function} {\n\t$0\n}";
+    "uprobe", "uprobe probe", "uprobe:${1:binary}:${2:function} {\n\t$0\n}";
+    "uretprobe", "uretprobe probe", "uretprobe:${1:binary}:${2:function} {\n\t$0\n}";
+    "tracepoint", "tracepoint probe", "tracepoint:${1:category}:${2:event} {\n\t$0\n}";
+    "rawtracepoint", "rawtracepoint probe", "rawtracepoint:${1:event} {\n\t$0\n}";
+    "usdt", "usdt probe", "usdt:${1:binary}:${2:probe} {\n\t$0\n}";
+    "profile", "profile probe", "profile:hz:${1:99} {\n\t$0\n}";
+    "interval", "interval probe", "interval:s:${1:1} {\n\t$0\n}";
+    "software", "software probe", "software:${1:page-faults}:${2:100} {\n\t$0\n}";
+    "hardware", "hardware probe", "hardware:${1:cache-misses}:${2:1000000} {\n\t$0\n}";
+    "watchpoint", "watchpoint probe", "watchpoint:${1:address}:${2:8}:${3:w} {\n\t$0\n}";
+    "fentry", "fentry probe", "fentry:${1:function} {\n\t$0\n}";
+    "fexit", "fexit probe", "fexit:${1:function} {\n\t$0\n}";
+    "iter", "iter probe", "iter:${1:task} {\n\t$0\n}";
+};
+
+/// Types of the values builtin functions return, functions returning nothing
+/// are left out.
+pub const RETURN_TYPES: &[(&str, &str)] = include!(concat!(env!("OUT_DIR"), "/return_types.rs"));
@@ -0,0 +1,323 @@
+use super::analyzer::options;
+use super::analyzer::semantic_analyzer::AnalyzedFile;
+use super::config::{self, Config};
+use super::diagnostic_provider;
+use super::headers::HeaderCache;
+use super::storage::Storage;
+use anyhow::{Result, bail};
+use serde_json::{Value, json};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tower_lsp::lsp_types::{Diagnostic, DiagnosticSeverity, NumberOrString, Url};
+
+const USAGE: &str = "usage: btls check [--format text|json] <path>...";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    /// One `path:line:column: severity: message` line per diagnostic.
+    Text,
+    /// A JSON array with the diagnostics and `getopt()` options of each
+    /// script, for tools generating `--help` text and the like.
+    Json,
+}
+
+/// What checking a script found.
+struct Report {
+    diagnostics: Vec<Diagnostic>,
+    options: Vec<Value>,
+}
+
+/// Runs `btls check`, reporting diagnostics of the given scripts (and of every
+/// `.bt` file under the given directories) the way the language server would.
+/// Returns the process exit code, non-zero if any error was found.
+pub fn run(args: &[String]) -> i32 {
+    let Some((format, paths)) = parse_args(args) else {
+        eprintln!("{USAGE}");
+        return 2;
+    };
+
+    let mut failed = false;
+    let mut reports = vec![];
+    for path in paths {
+        let mut scripts = vec![];
+        if let Err(err) = collect_scripts(Path::new(path), &mut scripts) {
+            eprintln!("{path}: {err}");
+            failed = true;
+        }
+        for script in scripts {
+            match check(&script) {
+                Ok(report) => {
+                    failed |= report
+                        .diagnostics
+                        .iter()
+                        .any(|x| x.severity == Some(DiagnosticSeverity::ERROR));
+                    match format {
+                        Format::Text => print_text(&script, &report),
+                        Format::Json => reports.push(to_json(&script, &report)),
+                    }
+                }
+                Err(err) => {
+                    eprintln!("{}: {err:#}", script.display());
+                    failed = true;
+                }
+            }
+        }
+    }
+    if format == Format::Json {
+        println!("{}", serde_json::to_string_pretty(&reports).unwrap());
+    }
+    failed as i32
+}
+
+/// The output format and the paths to check, `None` if the arguments are
+/// invalid.
+fn parse_args(args: &[String]) -> Option<(Format, Vec<&String>)> {
+    let mut format = Format::Text;
+    let mut paths = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = match arg.strip_prefix("--format") {
+            Some("") => args.next()?.as_str(),
+            Some(rest) => rest.strip_prefix('=')?,
+            None if arg.starts_with('-') => return None,
+            None => {
+                paths.push(arg);
+                continue;
+            }
+        };
+        format = match value {
+            "text" => Format::Text,
+            "json" => Format::Json,
+            _ => return None,
+        };
+    }
+    (!paths.is_empty()).then_some((format, paths))
+}
+
+/// Collects `path`, or the `.bt` files under it. Symlinks to directories
+/// below it aren't followed, so loops end.
+fn collect_scripts(path: &Path, scripts: &mut Vec<PathBuf>) -> Result<()> {
+    if !path.is_dir() {
+        if !path.is_file() {
+            bail!("no such file or directory");
+        }
+        scripts.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries = std::fs::read_dir(path)?
+        .map(|entry| entry.and_then(|x| Ok((x.path(), x.file_type()?))))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    for (entry, file_type) in entries {
+        if file_type.is_dir() || entry.extension().is_some_and(|x| x == "bt") {
+            collect_scripts(&entry, scripts)?;
+        }
+    }
+    Ok(())
+}
+
+fn check(path: &Path) -> Result<Report> {
+    let absolute = std::path::absolute(path)?;
+    let project_file = config::find_project_file(&absolute);
+    let config = Arc::new(Config::resolve(
+        serde_json::Value::Null,
+        None,
+        project_file.as_deref(),
+    )?);
+
+    let Ok(uri) = Url::from_file_path(&absolute) else {
+        bail!("invalid path");
+    };
+    let document = Storage::new().read(&uri);
+    if document.version.is_error() {
+        bail!("failed to read file");
+    }
+    let analyzed = AnalyzedFile::new(document, &HeaderCache::new(), config.clone())?;
+    let options = options::table(analyzed.ast())
+        .iter()
+        .map(|x| x.to_json())
+        .collect();
+    let mut diagnostics = match config.diagnostics {
+        true => diagnostic_provider::diagnostics(&analyzed, &config),
+        false => vec![],
+    };
+    // diagnostics come in the order of the checks, not of the source
+    diagnostics.sort_by_key(|x| x.range.start);
+    Ok(Report {
+        diagnostics,
+        options,
+    })
+}
+
+fn severity(diag: &Diagnostic) -> &'static str {
+    match diag.severity {
+        Some(DiagnosticSeverity::ERROR) => "error",
+        Some(DiagnosticSeverity::WARNING) => "warning",
+        Some(DiagnosticSeverity::INFORMATION) => "info",
+        _ => "hint",
+    }
+}
+
+fn print_text(path: &Path, report: &Report) {
+    for diag in &report.diagnostics {
+        println!(
+            "{}:{}:{}: {}: {}",
+            path.display(),
+            diag.range.start.line + 1,
+            diag.range.start.character + 1,
+            severity(diag),
+            diag.message
+        );
+    }
+}
+
+fn to_json(path: &Path, report: &Report) -> Value {
+    let diagnostics = report
+        .diagnostics
+        .iter()
+        .map(|diag| {
+            let code = match &diag.code {
+                Some(NumberOrString::String(code)) => Some(code.clone()),
+                _ => None,
+            };
+            json!({
+                "line": diag.range.start.line + 1,
+                "column": diag.range.start.character + 1,
+                "severity": severity(diag),
+                "code": code,
+                "message": diag.message,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "path": path.display().to_string(),
+        "diagnostics": diagnostics,
+        "options": report.options,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|x| x.to_string()).collect()
+    }
+
+    /// A fresh directory for the files of a test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("btls-check-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let parse = |x: &[&str]| {
+            let args = args(x);
+            parse_args(&args).map(|(format, paths)| {
+                let paths = paths
+                    .iter()
+                    .map(|x| x.as_str())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                (format == Format::Json, paths)
+            })
+        };
+        assert_eq!(parse(&["a.bt", "dir"]), Some((false, "a.bt dir".into())));
+        assert_eq!(
+            parse(&["--format", "json", "a.bt"]),
+            Some((true, "a.bt".into()))
+        );
+        assert_eq!(
+            parse(&["a.bt", "--format=text"]),
+            Some((false, "a.bt".into()))
+        );
+        assert_eq!(parse(&[]), None);
+        assert_eq!(parse(&["--format", "json"]), None);
+        assert_eq!(parse(&["a.bt", "--format"]), None);
+        assert_eq!(parse(&["--format=yaml", "a.bt"]), None);
+        assert_eq!(parse(&["--verbose", "a.bt"]), None);
+    }
+
+    #[test]
+    fn test_collect_scripts() {
+        let dir = temp_dir("collect");
+        std::fs::create_dir_all(dir.join("b")).unwrap();
+        for file in ["c.bt", "a.bt", "b/d.bt", "notes.txt"] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        std::os::unix::fs::symlink(&dir, dir.join("b/loop")).unwrap();
+
+        let mut scripts = vec![];
+        collect_scripts(&dir, &mut scripts).unwrap();
+        assert_eq!(scripts, ["a.bt", "b/d.bt", "c.bt"].map(|x| dir.join(x)));
+
+        let mut scripts = vec![];
+        collect_scripts(&dir.join("notes.txt"), &mut scripts).unwrap();
+        assert_eq!(scripts, [dir.join("notes.txt")]);
+        assert!(collect_scripts(&dir.join("missing"), &mut vec![]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = temp_dir("report");
+        let script = dir.join("a.bt");
+        std::fs::write(
+            &script,
+            "BEGIN { $unused = 1; }\nkprobe:f { print($undefined); }\n",
+        )
+        .unwrap();
+
+        let report = check(&script).unwrap();
+        let starts = report
+            .diagnostics
+            .iter()
+            .map(|x| x.range.start.line)
+            .collect::<Vec<_>>();
+        assert_eq!(starts, [0, 1]);
+        assert_eq!(
+            to_json(&script, &report),
+            json!({
+                "path": script.display().to_string(),
+                "diagnostics": [
+                    {
+                        "line": 1,
+                        "column": 10,
+                        "severity": "warning",
+                        "code": "unused-variable",
+                        "message": "Scratch variable \"$unused\" is never read",
+                    },
+                    {
+                        "line": 2,
+                        "column": 19,
+                        "severity": "error",
+                        "code": "undefined-ident",
+                        "message": "Undefined Identifier \"undefined\"",
+                    },
+                ],
+                "options": [],
+            })
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let dir = temp_dir("exit");
+        std::fs::write(dir.join("ok.bt"), "BEGIN { $x = 1; print($x); }\n").unwrap();
+        std::fs::write(dir.join("warning.bt"), "BEGIN { $unused = 1; }\n").unwrap();
+        std::fs::write(dir.join("error.bt"), "BEGIN { print($x); }\n").unwrap();
+        let path = |x: &str| dir.join(x).display().to_string();
+
+        assert_eq!(run(&[path("ok.bt")]), 0);
+        assert_eq!(run(&[path("warning.bt")]), 0);
+        assert_eq!(run(&[path("ok.bt"), path("error.bt")]), 1);
+        assert_eq!(run(&["--format=json".into(), path("error.bt")]), 1);
+        assert_eq!(run(&[path("missing.bt")]), 1);
+        assert_eq!(run(&args(&["--format", "xml", "ok.bt"])), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
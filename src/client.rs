@@ -1,38 +1,84 @@
-use super::config::Config;
 use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
 use tower_lsp::{
     Client as LSPClient,
     lsp_types::{ConfigurationItem, Diagnostic, MessageType, Url},
 };
 
-static BTLS_SECTION: &str = "btls";
+#[cfg(test)]
+use tower_lsp::lsp_types::PublishDiagnosticsParams;
+
+pub static BTLS_SECTION: &str = "btls";
 
 pub struct Client {
     pub inner: Option<LSPClient>,
+    /// Whether the client answers `workspace/configuration` requests.
+    pub supports_configuration: AtomicBool,
+    #[cfg(test)]
+    pub published: std::sync::Mutex<Vec<PublishDiagnosticsParams>>,
+    #[cfg(test)]
+    pub shown: std::sync::Mutex<Vec<(MessageType, String)>>,
+    /// Settings answered to `workspace/configuration`, by scope.
+    #[cfg(test)]
+    pub scoped_settings: std::sync::Mutex<Vec<(Option<Url>, serde_json::Value)>>,
 }
 
 impl Client {
     pub fn new(client: LSPClient) -> Self {
         Self {
             inner: Some(client),
+            supports_configuration: AtomicBool::new(false),
+            #[cfg(test)]
+            published: Default::default(),
+            #[cfg(test)]
+            shown: Default::default(),
+            #[cfg(test)]
+            scoped_settings: Default::default(),
         }
     }
 
     #[cfg(test)]
     pub fn new_test() -> Self {
-        Self { inner: None }
+        Self {
+            inner: None,
+            supports_configuration: AtomicBool::new(false),
+            published: Default::default(),
+            shown: Default::default(),
+            scoped_settings: Default::default(),
+        }
     }
 
     pub async fn log_message<M: Display>(&self, typ: MessageType, message: M) {
         self.inner.as_ref().unwrap().log_message(typ, message).await;
     }
 
+    pub async fn show_message<M: Display>(&self, typ: MessageType, message: M) {
+        #[cfg(test)]
+        if self.inner.is_none() {
+            self.shown.lock().unwrap().push((typ, message.to_string()));
+            return;
+        }
+        self.inner
+            .as_ref()
+            .unwrap()
+            .show_message(typ, message)
+            .await;
+    }
+
     pub async fn publish_diagnostics(
         &self,
         uri: Url,
         diags: Vec<Diagnostic>,
         version: Option<i32>,
     ) {
+        #[cfg(test)]
+        if self.inner.is_none() {
+            self.published
+                .lock()
+                .unwrap()
+                .push(PublishDiagnosticsParams::new(uri, diags, version));
+            return;
+        }
         self.inner
             .as_ref()
             .unwrap()
@@ -40,20 +86,42 @@ impl Client {
             .await;
     }
 
-    pub async fn config(&self) -> Config {
-        let config = self
+    pub async fn workspace_diagnostic_refresh(&self) {
+        // the client may not support refreshing, nothing to do about it
+        let _ = self
             .inner
+            .as_ref()
+            .unwrap()
+            .workspace_diagnostic_refresh()
+            .await;
+    }
+
+    /// Requests the `btls` settings section of a workspace folder, or the
+    /// global one without a folder. `None` if the client can't be asked or
+    /// has no settings for us.
+    pub async fn config(&self, scope_uri: Option<Url>) -> Option<serde_json::Value> {
+        if !self.supports_configuration.load(Ordering::Relaxed) {
+            return None;
+        }
+        #[cfg(test)]
+        if self.inner.is_none() {
+            let settings = self.scoped_settings.lock().unwrap();
+            return settings
+                .iter()
+                .find(|(scope, _)| *scope == scope_uri)
+                .map(|(_, settings)| settings.clone());
+        }
+        self.inner
             .as_ref()
             .unwrap()
             .configuration(vec![ConfigurationItem {
-                scope_uri: None,
+                scope_uri,
                 section: Some(BTLS_SECTION.to_string()),
             }])
             .await
             .ok()
             .filter(|c| c.len() == 1)
             .and_then(|configs| configs.into_iter().next())
-            .unwrap_or_default();
-        Config::from_value(config)
+            .filter(|config| !config.is_null())
     }
 }
@@ -0,0 +1,389 @@
+mod refactor;
+
+use super::analyzer::semantic_analyzer::{self, AnalyzedFile};
+use super::builtins::{BUILTINS, PROBE_PROVIDERS};
+use super::common::utils::edit_distance;
+use super::config::{Config, FormatterConfig};
+use super::diagnostic_provider;
+use super::parser::{
+    ErrorRef, ErrorStatement, IdentKind, PositionalParamKind, Preamble, Program, UndefinedFunc,
+    UndefinedIdent, UnknownField, UnknownStatement,
+};
+use super::server::Context;
+use pest::Span;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tower_lsp::jsonrpc::{Error, ErrorCode, Result};
+use tower_lsp::lsp_types::{
+    CodeAction, CodeActionKind, CodeActionOrCommand, CodeActionParams, CodeActionResponse,
+    Diagnostic, Range, TextEdit, Url, WorkspaceEdit,
+};
+
+/// How many "did you mean" replacements to offer at most.
+const MAX_SUGGESTIONS: usize = 3;
+
+/// A fix for a diagnostic. Fixes are computed along with the diagnostics and
+/// sent in their `data`, so that code actions don't need to analyze again.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Fix {
+    pub title: String,
+    pub edits: Vec<TextEdit>,
+    #[serde(default)]
+    pub preferred: bool,
+}
+
+pub async fn code_actions(
+    context: &Context,
+    params: &CodeActionParams,
+) -> Result<Option<CodeActionResponse>> {
+    let uri = &params.text_document.uri;
+    let analyzed = context
+        .analyzer
+        .analyze(context, uri)
+        .await
+        .map_err(|_| Error::new(ErrorCode::InternalError))?;
+    let config = context.config(uri).await;
+
+    let mut diagnostics = params
+        .context
+        .diagnostics
+        .iter()
+        .filter(|x| x.source.as_deref() == Some("btls"))
+        .cloned()
+        .collect::<Vec<_>>();
+    // clients sending no diagnostics along (or none of ours) get fixes for
+    // the diagnostics in the requested range
+    if diagnostics.is_empty() {
+        diagnostics = diagnostic_provider::diagnostics(&analyzed, &config)
+            .into_iter()
+            .filter(|x| overlaps(x.range, params.range))
+            .collect();
+    }
+    let mut actions = diagnostics
+        .iter()
+        .flat_map(|diagnostic| {
+            let fixes = diagnostic
+                .data
+                .clone()
+                .and_then(|x| serde_json::from_value::<Vec<Fix>>(x).ok())
+                .unwrap_or_default();
+            fixes.into_iter().map(|fix| quick_fix(uri, diagnostic, fix))
+        })
+        .collect::<Vec<_>>();
+
+    let line_index = &analyzed.document.line_index;
+    if let (Some(start), Some(end)) = (
+        line_index.offset(params.range.start),
+        line_index.offset(params.range.end),
+    ) {
+        let editor = Editor {
+            analyzed: &analyzed,
+            formatter: &config.formatter,
+        };
+        actions.extend(
+            editor
+                .refactors(start, end)
+                .into_iter()
+                .map(|refactor| CodeAction {
+                    title: refactor.title,
+                    kind: Some(refactor.kind),
+                    edit: Some(workspace_edit(uri, refactor.edits)),
+                    ..Default::default()
+                }),
+        );
+    }
+
+    // clients may ask for some kinds of actions only
+    if let Some(only) = &params.context.only {
+        actions.retain(|action| {
+            let kind = action.kind.as_ref().map_or("", |x| x.as_str());
+            only.iter()
+                .any(|x| kind == x.as_str() || kind.starts_with(&format!("{}.", x.as_str())))
+        });
+    }
+    Ok(Some(
+        actions
+            .into_iter()
+            .map(CodeActionOrCommand::CodeAction)
+            .collect(),
+    ))
+}
+
+fn quick_fix(uri: &Url, diagnostic: &Diagnostic, fix: Fix) -> CodeAction {
+    CodeAction {
+        title: fix.title,
+        kind: Some(CodeActionKind::QUICKFIX),
+        diagnostics: Some(vec![diagnostic.clone()]),
+        edit: Some(workspace_edit(uri, fix.edits)),
+        is_preferred: fix.preferred.then_some(true),
+        ..Default::default()
+    }
+}
+
+fn workspace_edit(uri: &Url, edits: Vec<TextEdit>) -> WorkspaceEdit {
+    WorkspaceEdit {
+        changes: Some(HashMap::from([(uri.clone(), edits)])),
+        ..Default::default()
+    }
+}
+
+fn overlaps(a: Range, b: Range) -> bool {
+    a.start <= b.end && b.start <= a.end
+}
+
+/// The fixes offered for an error.
+pub fn fixes(analyzed: &AnalyzedFile, error: ErrorRef, config: &Config) -> Vec<Fix> {
+    let ErrorRef::Statement(error) = error else {
+        return vec![];
+    };
+    let fixer = Editor {
+        analyzed,
+        formatter: &config.formatter,
+    };
+    match error {
+        ErrorStatement::UndefinedIdent(e) => fixer.undefined_ident(e),
+        ErrorStatement::UndefinedFunc(e) => fixer.undefined_func(e),
+        ErrorStatement::UnknownStatement(e) => fixer.unknown_statement(e),
+        ErrorStatement::UnknownField(e) => fixer.unknown_field(e),
+        ErrorStatement::UnknownProvider(e) => {
+            let candidates = PROBE_PROVIDERS
+                .iter()
+                .map(|x| x.name.to_string())
+                .collect::<Vec<_>>();
+            fixer.suggestions(e.text, e.span, "", &candidates)
+        }
+        ErrorStatement::PositionalParam(e) if e.kind == PositionalParamKind::StringAsInteger => {
+            let text = format!("str(${})", e.text);
+            vec![Fix {
+                title: format!("Change to `{text}`"),
+                edits: vec![fixer.replace(e.span, &text)],
+                preferred: true,
+            }]
+        }
+        ErrorStatement::MapLeak(e) => e
+            .return_block
+            .and_then(|block| fixer.append_statement(block, &e.delete_statement()))
+            .map(|edit| Fix {
+                title: format!("Insert `{}` in the return probe", e.delete_statement()),
+                edits: vec![edit],
+                preferred: true,
+            })
+            .into_iter()
+            .collect(),
+        ErrorStatement::UnusedVariable(_)
+        | ErrorStatement::WriteOnlyMap(_)
+        | ErrorStatement::MapReadBeforeWrite(_)
+        | ErrorStatement::UnresolvedInclude(_)
+        | ErrorStatement::PositionalParam(_)
+        | ErrorStatement::GetoptConflict(_)
+        | ErrorStatement::UnavailableFeature(_)
+        | ErrorStatement::UnavailableBuiltin(_) => vec![],
+    }
+}
+
+/// Builds the edits of fixes and refactorings of an analyzed document.
+struct Editor<'b> {
+    analyzed: &'b AnalyzedFile,
+    formatter: &'b FormatterConfig,
+}
+
+impl Editor<'_> {
+    fn ast(&self) -> &Program<'_> {
+        self.analyzed.ast()
+    }
+
+    fn text(&self) -> &str {
+        self.analyzed.document.data.as_str()
+    }
+
+    fn undefined_ident(&self, error: &UndefinedIdent) -> Vec<Fix> {
+        let prefix = match error.kind {
+            IdentKind::Scratch | IdentKind::Positional => "$",
+            IdentKind::Map => "@",
+            IdentKind::Bare => "",
+        };
+        let candidates = match error.kind {
+            IdentKind::Bare => BUILTINS
+                .keywords
+                .iter()
+                .map(|x| x.name.to_string())
+                .collect(),
+            _ => semantic_analyzer::variables_at(self.ast(), error.span.start())
+                .into_iter()
+                .filter_map(|x| x.strip_prefix(prefix).map(str::to_string))
+                .collect::<Vec<_>>(),
+        };
+        let mut fixes = self.suggestions(error.text, error.span, prefix, &candidates);
+
+        if error.kind == IdentKind::Scratch {
+            let statement = format!("${} = 0;", error.text);
+            if let Some(edit) = self
+                .probe_block(error.span)
+                .and_then(|block| self.prepend_statement(block, &statement))
+            {
+                fixes.push(Fix {
+                    title: format!("Declare `{statement}` at top of probe"),
+                    edits: vec![edit],
+                    preferred: false,
+                });
+            }
+        }
+        fixes
+    }
+
+    fn undefined_func(&self, error: &UndefinedFunc) -> Vec<Fix> {
+        // the error spans the whole call, the name comes first
+        let start = error.span.start();
+        let Some(span) = Span::new(error.span.get_input(), start, start + error.text.len()) else {
+            return vec![];
+        };
+        let candidates = BUILTINS
+            .functions
+            .iter()
+            .map(|x| x.name.to_string())
+            .collect::<Vec<_>>();
+        self.suggestions(error.text, span, "", &candidates)
+    }
+
+    fn unknown_field(&self, error: &UnknownField) -> Vec<Fix> {
+        let Some(record) = self.analyzed.definitions.record(&error.record) else {
+            return vec![];
+        };
+        let candidates = record
+            .fields
+            .iter()
+            .map(|x| x.name.clone())
+            .collect::<Vec<_>>();
+        self.suggestions(error.text, error.span, "", &candidates)
+    }
+
+    fn unknown_statement(&self, error: &UnknownStatement) -> Vec<Fix> {
+        // the error takes the rest of the line, newline included
+        let start = error.span.start();
+        let end = start + error.span.as_str().trim_end().len();
+        vec![Fix {
+            title: "Remove unknown statement".to_string(),
+            edits: vec![self.remove(start, end)],
+            preferred: false,
+        }]
+    }
+
+    /// "Did you mean" replacements of `name` with the closest candidates.
+    fn suggestions(&self, name: &str, span: Span, prefix: &str, candidates: &[String]) -> Vec<Fix> {
+        let max_distance = (name.chars().count() / 3).max(1);
+        let mut matches = candidates
+            .iter()
+            .filter(|x| x.as_str() != name)
+            .map(|x| (edit_distance(name, x), x))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .collect::<Vec<_>>();
+        matches.sort();
+        matches.dedup();
+
+        matches
+            .into_iter()
+            .take(MAX_SUGGESTIONS)
+            .enumerate()
+            .map(|(i, (_, candidate))| Fix {
+                title: format!("Change to `{prefix}{candidate}`"),
+                edits: vec![self.replace(span, candidate)],
+                preferred: i == 0,
+            })
+            .collect()
+    }
+
+    /// The block of the probe containing `span`.
+    fn probe_block(&self, span: Span) -> Option<Span<'_>> {
+        self.ast().preambles.iter().find_map(|x| match x {
+            Preamble::Probe(probe)
+                if probe.block.span.start() <= span.start()
+                    && span.end() <= probe.block.span.end() =>
+            {
+                Some(probe.block.span)
+            }
+            _ => None,
+        })
+    }
+
+    fn replace(&self, span: Span, text: &str) -> TextEdit {
+        TextEdit {
+            range: self.analyzed.document.line_index.range(span),
+            new_text: text.to_string(),
+        }
+    }
+
+    fn insert(&self, offset: usize, text: String) -> TextEdit {
+        let position = self.analyzed.document.line_index.position(offset);
+        TextEdit {
+            range: Range::new(position, position),
+            new_text: text,
+        }
+    }
+
+    /// Removes the text between two offsets, along with the line it is on
+    /// if nothing else is.
+    fn remove(&self, mut start: usize, mut end: usize) -> TextEdit {
+        let input = self.text();
+        let line_start = input[..start].rfind('\n').map_or(0, |x| x + 1);
+        let line_end = input[end..].find('\n').map_or(input.len(), |x| end + x + 1);
+        if input[line_start..start].trim().is_empty() && input[end..line_end].trim().is_empty() {
+            (start, end) = (line_start, line_end);
+        }
+        self.replace(Span::new(input, start, end).unwrap(), "")
+    }
+
+    /// The whitespace a line starts with, given any offset in the line.
+    fn line_indent(&self, offset: usize) -> &str {
+        let input = self.text();
+        let line = &input[input[..offset].rfind('\n').map_or(0, |x| x + 1)..];
+        &line[..line.len() - line.trim_start().len()]
+    }
+
+    fn indent(&self) -> String {
+        if self.formatter.use_tabs {
+            "\t".to_string()
+        } else {
+            " ".repeat(self.formatter.indent_width as usize)
+        }
+    }
+
+    /// Inserts `statement` as the first statement of a block, on its own
+    /// line unless the block opens on the line its first statement is on.
+    fn prepend_statement(&self, block: Span, statement: &str) -> Option<TextEdit> {
+        let input = block.get_input();
+        let open = block.start() + 1;
+        let line_end = input[open..]
+            .find('\n')
+            .map_or(input.len(), |x| open + x + 1);
+        if !input[open..line_end].trim().is_empty() {
+            return Some(self.insert(open, format!(" {statement}")));
+        }
+
+        let brace_indent = self.line_indent(block.start());
+        Some(self.insert(
+            line_end,
+            format!("{brace_indent}{}{statement}\n", self.indent()),
+        ))
+    }
+
+    /// Inserts `statement` as the last statement of a block, on its own line
+    /// unless the block closes on the line its last statement is on.
+    fn append_statement(&self, block: Span, statement: &str) -> Option<TextEdit> {
+        let input = block.get_input();
+        let close = block.end().checked_sub(1)?;
+        let line_start = input[..close].rfind('\n').map_or(0, |x| x + 1);
+        let brace_indent = &input[line_start..close];
+        if !brace_indent.trim().is_empty() {
+            let separator = if brace_indent.ends_with(char::is_whitespace) {
+                ""
+            } else {
+                " "
+            };
+            return Some(self.insert(close, format!("{separator}{statement} ")));
+        }
+        Some(self.insert(
+            line_start,
+            format!("{brace_indent}{}{statement}\n", self.indent()),
+        ))
+    }
+}
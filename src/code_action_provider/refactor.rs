@@ -0,0 +1,409 @@
+use super::Editor;
+use crate::parser::{
+    AssignOp, Block, Expr, IdentKind, Identifier, Loop, Lvalue, Node, Preamble, Probe, Statement,
+    Walk,
+};
+use pest::Span;
+use std::ptr;
+use tower_lsp::lsp_types::{CodeActionKind, TextEdit};
+
+/// A refactoring offered for a selection. Unlike fixes, refactorings aren't
+/// tied to a diagnostic.
+pub struct Refactor {
+    pub title: String,
+    pub kind: CodeActionKind,
+    pub edits: Vec<TextEdit>,
+}
+
+impl Editor<'_> {
+    /// The refactorings applying to the text between two offsets.
+    pub(super) fn refactors(&self, start: usize, end: usize) -> Vec<Refactor> {
+        let Some(probe) = self.ast().preambles.iter().find_map(|x| match x {
+            Preamble::Probe(probe) if probe.span.start() <= start && end <= probe.span.end() => {
+                Some(probe)
+            }
+            _ => None,
+        }) else {
+            return vec![];
+        };
+
+        [
+            self.extract_expr(probe, start, end),
+            self.inline_variable(probe, start),
+            self.if_to_filter(probe, start),
+            self.filter_to_if(probe, start),
+            self.split_probe(probe, start),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Moves the selected expression into a new scratch variable assigned
+    /// right before the statement using it.
+    fn extract_expr(&self, probe: &Probe, start: usize, end: usize) -> Option<Refactor> {
+        let input = self.text();
+        let selected = &input[start..end];
+        let start = start + selected.len() - selected.trim_start().len();
+        let end = start + selected.trim().len();
+        if start == end {
+            return None;
+        }
+
+        let stmt = enclosing_statement(&probe.block, start, end)?;
+        let expr = Walk::new(stmt.as_node())
+            .filter_map(|x| x.as_expr())
+            .find(|x| x.span().start() == start && x.span().end() == end)?;
+        let extractable = match (stmt, expr) {
+            (_, Expr::Identifier(ident)) if ident.kind == IdentKind::Scratch => false,
+            // the statement itself, or the target of `$x++`
+            (Statement::Expr(e), _) => match e.as_ref() {
+                Expr::UnaryExpr(unary) => {
+                    !ptr::eq(e.as_ref(), expr) && !ptr::eq(&*unary.expr, expr)
+                }
+                e => !ptr::eq(e, expr),
+            },
+            // a loop condition is evaluated on every iteration
+            (Statement::Loop(l), _) => match l.as_ref() {
+                Loop::While(w) => end > w.block.span.start(),
+                Loop::For(f) => !ptr::eq(&*f.lhs, expr),
+                Loop::Unroll(_) => true,
+            },
+            _ => true,
+        };
+        if !extractable {
+            return None;
+        }
+
+        let name = self.unused_name(probe, "value");
+        let stmt_start = stmt.span().start();
+        let declaration = format!("${name} = {};", expr.span().as_str());
+        let text = if self.starts_line(stmt_start) {
+            format!("{declaration}\n{}", self.line_indent(stmt_start))
+        } else {
+            format!("{declaration} ")
+        };
+        Some(Refactor {
+            title: format!("Extract to `${name}`"),
+            kind: CodeActionKind::REFACTOR_EXTRACT,
+            edits: vec![
+                self.insert(stmt_start, text),
+                self.replace(expr.span(), &format!("${name}")),
+            ],
+        })
+    }
+
+    /// Replaces the only read of a scratch variable with the value it is
+    /// assigned, and removes the assignment. Values that may change over
+    /// time or have side effects, like `nsecs`, map reads or calls, are only
+    /// inlined when no statement sits between the assignment and the read.
+    fn inline_variable(&self, probe: &Probe, offset: usize) -> Option<Refactor> {
+        let nodes = Walk::new(probe.block.as_node()).collect::<Vec<_>>();
+        let ident = nodes.iter().filter_map(|x| x.as_identifier()).find(|x| {
+            x.kind == IdentKind::Scratch && x.span.start() <= offset + 1 && offset <= x.span.end()
+        })?;
+        let occurrences = nodes
+            .iter()
+            .filter_map(|x| x.as_identifier())
+            .filter(|x| x.kind == IdentKind::Scratch && x.name == ident.name)
+            .collect::<Vec<_>>();
+        let [first, second] = occurrences[..] else {
+            return None;
+        };
+
+        let (def_stmt, assign) = nodes.iter().find_map(|x| match x.as_statement()? {
+            stmt @ Statement::Assignment(assign) => {
+                let Lvalue::Identifier(lvalue) = &assign.lvalue;
+                ptr::eq(&**lvalue, first).then_some((stmt, assign))
+            }
+            _ => None,
+        })?;
+        if assign.op != AssignOp::Assign {
+            return None;
+        }
+        let read = nodes
+            .iter()
+            .filter_map(|x| x.as_expr())
+            .find(|x| matches!(x, Expr::Identifier(ident) if ptr::eq(&**ident, second)))?;
+        if self.is_write_target(&nodes, read) {
+            return None;
+        }
+
+        let def_end = self.statement_end(def_stmt);
+        let read_start = second.span.start();
+        if read_start < def_end {
+            return None;
+        }
+        // a loop would evaluate the value again on every iteration
+        let in_loop = nodes.iter().any(|x| {
+            matches!(x.as_statement(), Some(Statement::Loop(l))
+                if l.span().start() <= read_start && read_start < l.span().end()
+                    && !(l.span().start() <= def_end && def_end <= l.span().end()))
+        });
+        // nor may anything the value depends on change in between
+        let dependencies = Walk::new(assign.rvalue.as_node())
+            .filter_map(|x| x.as_identifier())
+            .map(|x| (x.kind, x.name))
+            .collect::<Vec<_>>();
+        let changed = nodes
+            .iter()
+            .filter_map(|x| self.written_identifier(x.as_statement()?))
+            .any(|x| {
+                def_end <= x.span.start()
+                    && x.span.start() < read_start
+                    && dependencies.contains(&(x.kind, x.name))
+            });
+        if in_loop || changed {
+            return None;
+        }
+        let between = nodes.iter().any(|x| {
+            x.as_statement()
+                .is_some_and(|x| def_end <= x.span().start() && x.span().end() <= read_start)
+        });
+        if between && !is_pure(&assign.rvalue) {
+            return None;
+        }
+
+        let value = assign.rvalue.span().as_str();
+        let compound = matches!(assign.rvalue.as_ref(), Expr::BinaryExpr(_));
+        let operand = nodes.iter().any(|x| match x.as_expr() {
+            Some(Expr::BinaryExpr(bin)) => ptr::eq(&*bin.lhs, read) || ptr::eq(&*bin.rhs, read),
+            Some(Expr::UnaryExpr(unary)) => ptr::eq(&*unary.expr, read),
+            _ => false,
+        });
+        let value = if compound && operand {
+            format!("({value})")
+        } else {
+            value.to_string()
+        };
+        let read_span = Span::new(self.text(), read_start - 1, second.span.end())?;
+        Some(Refactor {
+            title: format!("Inline `${}`", ident.name),
+            kind: CodeActionKind::REFACTOR_INLINE,
+            edits: vec![
+                self.remove(def_stmt.span().start(), def_end),
+                self.replace(read_span, &value),
+            ],
+        })
+    }
+
+    /// Turns an `if` wrapping the whole probe body into a probe filter.
+    fn if_to_filter(&self, probe: &Probe, offset: usize) -> Option<Refactor> {
+        if probe.condition.is_some() {
+            return None;
+        }
+        let mut statements = statements(&probe.block);
+        let (Some(Statement::IfCond(if_cond)), None) = (statements.next(), statements.next())
+        else {
+            return None;
+        };
+        let input = self.text();
+        let block = probe.block.span;
+        if !input[block.start() + 1..if_cond.span.start()]
+            .trim()
+            .is_empty()
+            || !input[if_cond.span.end()..block.end() - 1].trim().is_empty()
+            || offset > if_cond.block.span.start()
+        {
+            return None;
+        }
+
+        let header = input[probe.span.start()..block.start()].trim_end();
+        let unit = self
+            .line_indent(if_cond.span.start())
+            .strip_prefix(self.line_indent(probe.span.start()))
+            .filter(|x| !x.is_empty())
+            .map(str::to_string)
+            .unwrap_or_else(|| self.indent());
+        let body = reindent(if_cond.block.span.as_str(), |line| {
+            line.strip_prefix(unit.as_str()).unwrap_or(line).to_string()
+        });
+        let condition = if_cond.condition.span().as_str();
+        Some(Refactor {
+            title: "Convert `if` to probe filter".to_string(),
+            kind: CodeActionKind::REFACTOR_REWRITE,
+            edits: vec![self.replace(
+                Span::new(input, probe.span.start(), block.end())?,
+                &format!("{header} /{condition}/ {body}"),
+            )],
+        })
+    }
+
+    /// Turns a probe filter into an `if` wrapping the whole probe body.
+    fn filter_to_if(&self, probe: &Probe, offset: usize) -> Option<Refactor> {
+        let condition = probe.condition.as_ref()?;
+        if offset >= probe.block.span.start() {
+            return None;
+        }
+        let input = self.text();
+        let slash = input[..condition.span().start()].rfind('/')?;
+        let header = input[probe.span.start()..slash].trim_end();
+        let probe_indent = self.line_indent(probe.span.start());
+        let unit = self.indent();
+        let body = reindent(probe.block.span.as_str(), |line| format!("{unit}{line}"));
+        Some(Refactor {
+            title: "Convert probe filter to `if`".to_string(),
+            kind: CodeActionKind::REFACTOR_REWRITE,
+            edits: vec![self.replace(
+                Span::new(input, probe.span.start(), probe.block.span.end())?,
+                &format!(
+                    "{header} {{\n{probe_indent}{unit}if ({}) {body}\n{probe_indent}}}",
+                    condition.span().as_str()
+                ),
+            )],
+        })
+    }
+
+    /// Splits a probe with several attach points into one probe per attach
+    /// point, each with a copy of the filter and body.
+    fn split_probe(&self, probe: &Probe, offset: usize) -> Option<Refactor> {
+        if probe.attach_points.len() < 2 || offset >= probe.block.span.start() {
+            return None;
+        }
+        let input = self.text();
+        let head_end = match &probe.condition {
+            Some(condition) => input[..condition.span().start()].rfind('/')?,
+            None => probe.block.span.start(),
+        };
+        let attach_points = input[probe.span.start()..head_end].trim_end();
+        let rest = &input[probe.span.start() + attach_points.len()..probe.span.end()];
+        let separator = format!("\n\n{}", self.line_indent(probe.span.start()));
+        let probes = probe
+            .attach_points
+            .iter()
+            .map(|x| format!("{x}{rest}"))
+            .collect::<Vec<_>>();
+        Some(Refactor {
+            title: "Split into one probe per attach point".to_string(),
+            kind: CodeActionKind::REFACTOR_REWRITE,
+            edits: vec![self.replace(probe.span, &probes.join(&separator))],
+        })
+    }
+
+    /// A scratch variable name not used in the probe yet.
+    fn unused_name(&self, probe: &Probe, base: &str) -> String {
+        let used = Walk::new(probe.block.as_node())
+            .filter_map(|x| x.as_identifier())
+            .filter(|x| x.kind == IdentKind::Scratch)
+            .map(|x| x.name)
+            .collect::<Vec<_>>();
+        (1..)
+            .map(|i| match i {
+                1 => base.to_string(),
+                i => format!("{base}{i}"),
+            })
+            .find(|x| !used.contains(&x.as_str()))
+            .unwrap()
+    }
+
+    /// Whether an expression is written by `$x++` or is a loop variable.
+    fn is_write_target(&self, nodes: &[&dyn Node], expr: &Expr) -> bool {
+        nodes.iter().any(|x| match x.as_statement() {
+            Some(Statement::Expr(e)) => {
+                matches!(e.as_ref(), Expr::UnaryExpr(unary) if ptr::eq(&*unary.expr, expr))
+            }
+            Some(Statement::Loop(l)) => {
+                matches!(l.as_ref(), Loop::For(f) if ptr::eq(&*f.lhs, expr))
+            }
+            _ => false,
+        })
+    }
+
+    fn written_identifier<'a, 'b>(&self, stmt: &'b Statement<'a>) -> Option<&'b Identifier<'a>> {
+        let target = match stmt {
+            Statement::Assignment(assign) => {
+                let Lvalue::Identifier(ident) = &assign.lvalue;
+                return Some(ident);
+            }
+            Statement::Expr(e) => match e.as_ref() {
+                Expr::UnaryExpr(unary) => unary.expr.as_ref(),
+                _ => return None,
+            },
+            Statement::Loop(l) => match l.as_ref() {
+                Loop::For(f) => f.lhs.as_ref(),
+                Loop::While(_) | Loop::Unroll(_) => return None,
+            },
+            _ => return None,
+        };
+        match target {
+            Expr::Identifier(ident) => Some(ident),
+            _ => None,
+        }
+    }
+
+    /// The offset right after a statement, including its `;`.
+    fn statement_end(&self, stmt: &Statement) -> usize {
+        let end = stmt.span().end();
+        let rest = &self.text()[end..];
+        match rest.trim_start().strip_prefix(';') {
+            Some(after) => self.text().len() - after.len(),
+            None => end,
+        }
+    }
+
+    fn starts_line(&self, offset: usize) -> bool {
+        let input = self.text();
+        input[..offset]
+            .rsplit('\n')
+            .next()
+            .is_some_and(|x| x.trim().is_empty())
+    }
+}
+
+/// The statements of a block, without the errors reported in it.
+fn statements<'a, 'b>(block: &'b Block<'a>) -> impl Iterator<Item = &'b Statement<'a>> {
+    block
+        .statements
+        .iter()
+        .filter(|x| !matches!(x, Statement::Error(_)))
+}
+
+/// The innermost statement containing the text between two offsets.
+fn enclosing_statement<'a, 'b>(
+    block: &'b Block<'a>,
+    start: usize,
+    end: usize,
+) -> Option<&'b Statement<'a>> {
+    let stmt = statements(block).find(|x| x.span().start() <= start && end <= x.span().end())?;
+    let inner = match stmt {
+        Statement::IfCond(if_cond) => Some(&if_cond.block),
+        Statement::Loop(l) => match l.as_ref() {
+            Loop::While(w) => Some(&w.block),
+            Loop::For(f) => Some(&f.block),
+            Loop::Unroll(u) => Some(&u.block),
+        },
+        _ => None,
+    };
+    inner
+        .filter(|x| x.span.start() < start && end <= x.span.end())
+        .and_then(|x| enclosing_statement(x, start, end))
+        .or(Some(stmt))
+}
+
+/// Applies `f` to every non-empty line of a block but the first, which
+/// holds its opening brace.
+fn reindent(text: &str, f: impl Fn(&str) -> String) -> String {
+    text.split('\n')
+        .enumerate()
+        .map(|(i, line)| match i {
+            0 => line.to_string(),
+            _ if line.trim().is_empty() => String::new(),
+            _ => f(line),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Whether evaluating an expression later gives the same value without side
+/// effects: literals, scratch variables and positional parameters.
+fn is_pure(expr: &Expr) -> bool {
+    Walk::new(expr.as_node()).all(|x| match x.as_expr() {
+        Some(Expr::Identifier(ident)) => {
+            matches!(ident.kind, IdentKind::Scratch | IdentKind::Positional)
+        }
+        Some(Expr::UnaryExpr(unary)) => !matches!(unary.operator(), "++" | "--"),
+        Some(Expr::Integer(_) | Expr::String(_) | Expr::BinaryExpr(_) | Expr::Cast(_)) => true,
+        Some(Expr::Call(_) | Expr::Field(_)) => false,
+        None => true,
+    })
+}
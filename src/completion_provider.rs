@@ -1,62 +1,762 @@
-use super::analyzer::semantic_analyzer;
-use super::builtins::BUILTINS;
+use super::analyzer::semantic_analyzer::{self, AnalyzedFile};
+use super::analyzer::{providers, types};
+use super::builtins::{
+    BUILTINS, BuiltinSymbol, FORMAT_SPECIFIERS, PROBE_PROVIDERS, SNIPPETS, unalias,
+};
+use super::config::Config;
+use super::parser::Preamble;
 use super::server::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::path::Path;
 use tower_lsp::jsonrpc::{Error, ErrorCode, Result};
 use tower_lsp::lsp_types::{
-    CompletionItem, CompletionItemKind, CompletionResponse, Documentation, MarkupContent,
-    MarkupKind, Position,
+    CompletionItem, CompletionItemKind, CompletionResponse, CompletionTextEdit, Documentation,
+    InsertTextFormat, MarkupContent, MarkupKind, Position, Range, TextEdit, Url,
 };
 
-macro_rules! builtin_to_completion_item {
-    ($collection:expr, $kind:expr) => {
-        $collection.iter().map(|x| CompletionItem {
+/// Characters that make clients ask for completions right away.
+pub const TRIGGER_CHARACTERS: &[&str] = &["$", "@", ":", ".", ">", "%"];
+
+/// What is being completed, along with the text typed so far.
+#[derive(Debug, PartialEq)]
+enum CompletionContext<'a> {
+    /// Comments and strings other than `printf` formats.
+    Nothing,
+    /// The start of a new probe, where snippets are offered too.
+    Preamble(&'a str),
+    /// The attach points of a probe.
+    AttachPoint(&'a str),
+    Map(&'a str),
+    Scratch(&'a str),
+    /// A field of whatever `base` evaluates to, after `base.` or `base->`.
+    Field {
+        base: &'a str,
+        prefix: &'a str,
+    },
+    /// A conversion specifier in a `printf` format string.
+    FormatSpecifier(&'a str),
+    /// Anything else that can go in an expression.
+    Expression(&'a str),
+}
+
+/// A collection of builtins completion items are made from.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Builtins {
+    Keyword,
+    Function,
+    ProbeProvider,
+    FormatSpecifier,
+}
+
+impl Builtins {
+    fn symbols(self) -> &'static [BuiltinSymbol] {
+        match self {
+            Self::Keyword => BUILTINS.keywords,
+            Self::Function => BUILTINS.functions,
+            Self::ProbeProvider => PROBE_PROVIDERS,
+            Self::FormatSpecifier => FORMAT_SPECIFIERS,
+        }
+    }
+
+    fn item_kind(self) -> CompletionItemKind {
+        match self {
+            Self::Keyword => CompletionItemKind::KEYWORD,
+            Self::Function => CompletionItemKind::FUNCTION,
+            Self::ProbeProvider => CompletionItemKind::MODULE,
+            Self::FormatSpecifier => CompletionItemKind::CONSTANT,
+        }
+    }
+
+    /// Items carrying only their label, see `resolve`.
+    fn items(self) -> impl Iterator<Item = CompletionItem> {
+        self.symbols().iter().map(move |x| CompletionItem {
             label: x.name.to_string(),
-            kind: Some($kind),
-            detail: Some(x.detail.to_string()),
-            documentation: Some(Documentation::MarkupContent(MarkupContent {
-                kind: MarkupKind::Markdown,
-                value: x.documentation.to_string(),
-            })),
+            kind: Some(self.item_kind()),
+            data: Some(serde_json::to_value(ItemData::Builtin(self)).unwrap()),
             ..Default::default()
         })
-    };
+    }
+}
+
+/// Tells `resolve` what an item was made from. The label of an item is the
+/// name of its builtin and the body of a snippet is in `insert_text`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ItemData {
+    Builtin(Builtins),
+    /// One of a family of numbered builtins, like `arg0`.
+    Numbered,
+    Snippet,
 }
 
 pub async fn completion(
     context: &Context,
-    path: &Path,
+    uri: &Url,
     position: Position,
 ) -> Result<Option<CompletionResponse>> {
-    let mut analyzer = context.analyzer.lock().await;
-    let analyzed = analyzer
-        .analyze(context, path)
+    let analyzed = context
+        .analyzer
+        .analyze(context, uri)
         .await
         .map_err(|_| Error::new(ErrorCode::InternalError))?;
 
     let Some(offset) = analyzed.document.line_index.offset(position) else {
         return Ok(None);
     };
+    let text = analyzed.document.data.as_str();
+
+    let completion_context = completion_context(text, offset);
+    let variables = || semantic_analyzer::variables_at(analyzed.ast(), offset);
+    let items: Vec<CompletionItem> = match completion_context {
+        CompletionContext::Nothing => return Ok(None),
+        CompletionContext::Preamble(_) => {
+            let config = context.config(uri).await;
+            Builtins::ProbeProvider
+                .items()
+                .chain(
+                    SNIPPETS
+                        .iter()
+                        .map(|x| snippet_item(x.label, x.detail, x.body)),
+                )
+                .chain(
+                    config
+                        .snippets
+                        .iter()
+                        .map(|x| snippet_item(&x.label, &x.detail, &x.body)),
+                )
+                .chain(kernel_items(&config, completion_context.prefix()))
+                .collect()
+        }
+        CompletionContext::AttachPoint(prefix) => {
+            let config = context.config(uri).await;
+            Builtins::ProbeProvider
+                .items()
+                .chain(kernel_items(&config, prefix))
+                .collect()
+        }
+        CompletionContext::Map(_) | CompletionContext::Scratch(_) => {
+            let sigil = match completion_context {
+                CompletionContext::Map(_) => "@",
+                _ => "$",
+            };
+            let mut items = variables()
+                .into_iter()
+                .filter(|x| x.starts_with(sigil))
+                .map(variable_item)
+                .collect::<Vec<_>>();
+            if sigil == "$" {
+                items.extend(analyzed.params.iter().map(|x| {
+                    CompletionItem {
+                        label: format!("${}", x.number),
+                        kind: Some(CompletionItemKind::VARIABLE),
+                        detail: x.ty.map(|x| x.to_string()),
+                        documentation: Some(x.description.clone())
+                            .filter(|x| !x.is_empty())
+                            .map(Documentation::String),
+                        ..Default::default()
+                    }
+                }));
+            }
+            items
+        }
+        CompletionContext::Field { base, .. } => {
+            let record =
+                base_type(&analyzed, base, offset).and_then(|ty| analyzed.definitions.record(&ty));
+            let mut items = record.map_or(vec![], |record| {
+                record
+                    .fields
+                    .iter()
+                    .map(|x| CompletionItem {
+                        label: x.name.clone(),
+                        kind: Some(CompletionItemKind::FIELD),
+                        detail: Some(x.ty.clone()),
+                        ..Default::default()
+                    })
+                    .collect::<Vec<_>>()
+            });
+            for field in used_fields(text, base) {
+                if !items.iter().any(|x| x.label == field) {
+                    items.push(CompletionItem {
+                        label: field.to_string(),
+                        kind: Some(CompletionItemKind::FIELD),
+                        ..Default::default()
+                    });
+                }
+            }
+            items
+        }
+        CompletionContext::FormatSpecifier(_) => Builtins::FormatSpecifier.items().collect(),
+        CompletionContext::Expression(_) => {
+            let config = context.config(uri).await;
+            variables()
+                .into_iter()
+                .map(variable_item)
+                .chain(Builtins::Keyword.items())
+                .chain(numbered_items(&analyzed, offset, &config.arch))
+                .chain(Builtins::Function.items())
+                .collect()
+        }
+    };
+
+    let prefix = completion_context.prefix();
+    let range = Range::new(
+        analyzed.document.line_index.position(offset - prefix.len()),
+        position,
+    );
+    Ok(Some(CompletionResponse::Array(rank(items, prefix, range))))
+}
+
+fn variable_item(name: String) -> CompletionItem {
+    CompletionItem {
+        label: name,
+        kind: Some(CompletionItemKind::VARIABLE),
+        ..Default::default()
+    }
+}
+
+/// Numbered builtins like `arg0` the probe at `offset` has, as many as there
+/// are on `arch`, only the first one of those without a limit.
+fn numbered_items(analyzed: &AnalyzedFile, offset: usize, arch: &str) -> Vec<CompletionItem> {
+    let probe = analyzed.ast().preambles.iter().find_map(|x| match x {
+        Preamble::Probe(probe) if probe.span.start() <= offset && offset <= probe.span.end() => {
+            Some(probe)
+        }
+        _ => None,
+    });
+    let Some(probe) = probe else {
+        return vec![];
+    };
+    let providers = providers::providers(probe)
+        .map(|x| unalias(x.as_str()))
+        .collect::<Vec<_>>();
+    BUILTINS
+        .numbered
+        .iter()
+        .filter(|family| providers.iter().all(|x| family.providers.contains(x)))
+        .flat_map(|family| {
+            (0..family.limit(arch).unwrap_or(1)).map(move |i| CompletionItem {
+                label: format!("{}{i}", family.symbol.name),
+                kind: Some(CompletionItemKind::KEYWORD),
+                data: Some(serde_json::to_value(ItemData::Numbered).unwrap()),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
 
-    let variables = semantic_analyzer::variables_at(&analyzed.ast, offset)
+/// Tracepoints or kernel functions an attach point being typed can name, as
+/// listed in `tracefs_path` and `kallsyms_path`. Labels are whole attach
+/// points since they replace everything typed so far. Functions are only
+/// offered once their first letter is typed, there are far too many of them.
+fn kernel_items(config: &Config, prefix: &str) -> Vec<CompletionItem> {
+    let Some((provider, target)) = prefix.split_once(':') else {
+        return vec![];
+    };
+    let (names, kind) = match unalias(provider) {
+        "tracepoint" => (tracepoints(&config.tracefs_path), CompletionItemKind::EVENT),
+        "kprobe" | "kretprobe" | "fentry" | "fexit" if !target.is_empty() => (
+            kernel_functions(&config.kallsyms_path, target),
+            CompletionItemKind::FUNCTION,
+        ),
+        _ => return vec![],
+    };
+    names
         .into_iter()
-        .map(|x| CompletionItem {
-            label: x.to_string(),
-            kind: Some(CompletionItemKind::VARIABLE),
+        .map(|name| CompletionItem {
+            label: format!("{provider}:{name}"),
+            kind: Some(kind),
             ..Default::default()
         })
-        .collect::<Vec<_>>();
-
-    let builtin_keywords =
-        builtin_to_completion_item!(BUILTINS.keywords, CompletionItemKind::KEYWORD);
-    let builtin_funcs =
-        builtin_to_completion_item!(BUILTINS.functions, CompletionItemKind::FUNCTION);
+        .collect()
+}
 
-    Ok(Some(CompletionResponse::Array(
-        variables
+/// The `category:event` names of the tracepoints under a tracefs mount.
+fn tracepoints(tracefs: &Path) -> BTreeSet<String> {
+    let dirs = |dir: &Path| {
+        std::fs::read_dir(dir)
             .into_iter()
-            .chain(builtin_keywords)
-            .chain(builtin_funcs)
-            .collect(),
-    )))
+            .flatten()
+            .flatten()
+            .filter(|x| x.file_type().is_ok_and(|x| x.is_dir()))
+            .map(|x| x.file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+    };
+    let events = tracefs.join("events");
+    dirs(&events)
+        .into_iter()
+        .flat_map(|category| {
+            dirs(&events.join(&category))
+                .into_iter()
+                .map(move |event| format!("{category}:{event}"))
+        })
+        .collect()
+}
+
+/// The names of the kernel functions starting with `prefix`, from the text
+/// symbols of a kallsyms file.
+fn kernel_functions(kallsyms: &Path, prefix: &str) -> BTreeSet<String> {
+    let Ok(text) = std::fs::read_to_string(kallsyms) else {
+        return BTreeSet::new();
+    };
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (_, kind, name) = (fields.next()?, fields.next()?, fields.next()?);
+            (matches!(kind, "t" | "T") && name.starts_with(prefix)).then(|| name.to_string())
+        })
+        .collect()
+}
+
+fn snippet_item(label: &str, detail: &str, body: &str) -> CompletionItem {
+    CompletionItem {
+        label: label.to_string(),
+        kind: Some(CompletionItemKind::SNIPPET),
+        detail: Some(detail.to_string()).filter(|x| !x.is_empty()),
+        data: Some(serde_json::to_value(ItemData::Snippet).unwrap()),
+        insert_text: Some(body.to_string()),
+        insert_text_format: Some(InsertTextFormat::SNIPPET),
+        ..Default::default()
+    }
+}
+
+/// Fills in the details left out of completion responses, which would be
+/// sent over again on every keystroke otherwise.
+pub fn resolve(mut item: CompletionItem) -> CompletionItem {
+    let Some(data) = item
+        .data
+        .clone()
+        .and_then(|x| serde_json::from_value::<ItemData>(x).ok())
+    else {
+        return item;
+    };
+    match data {
+        ItemData::Builtin(builtins) => {
+            if let Some(symbol) = builtins.symbols().iter().find(|x| x.name == item.label) {
+                item.detail = Some(symbol.detail.to_string());
+                item.documentation = Some(markdown(symbol.full_documentation()));
+            }
+        }
+        ItemData::Numbered => {
+            if let Some((family, _)) = BUILTINS.numbered(&item.label) {
+                item.detail = Some(family.symbol.detail.to_string());
+                item.documentation = Some(markdown(family.symbol.full_documentation()));
+            }
+        }
+        ItemData::Snippet => {
+            if let Some(body) = &item.insert_text {
+                item.documentation = Some(markdown(format!("```bpftrace\n{body}\n```")));
+            }
+        }
+    }
+    item
+}
+
+fn markdown(value: String) -> Documentation {
+    Documentation::MarkupContent(MarkupContent {
+        kind: MarkupKind::Markdown,
+        value,
+    })
+}
+
+impl<'a> CompletionContext<'a> {
+    /// The text typed so far, which completions replace.
+    fn prefix(&self) -> &'a str {
+        match self {
+            Self::Nothing => "",
+            Self::Preamble(prefix)
+            | Self::AttachPoint(prefix)
+            | Self::Map(prefix)
+            | Self::Scratch(prefix)
+            | Self::FormatSpecifier(prefix)
+            | Self::Expression(prefix)
+            | Self::Field { prefix, .. } => prefix,
+        }
+    }
+}
+
+/// Drops the items not matching the prefix and ranks the rest: items
+/// starting with the prefix come first, then those merely containing it,
+/// each in the order they were given in.
+fn rank(items: Vec<CompletionItem>, prefix: &str, range: Range) -> Vec<CompletionItem> {
+    let lowercase = prefix.to_lowercase();
+    items
+        .into_iter()
+        .filter_map(|item| {
+            let label = item.label.to_lowercase();
+            let rank = if label.starts_with(&lowercase) {
+                0
+            } else if label.contains(&lowercase) {
+                1
+            } else {
+                return None;
+            };
+            Some((rank, item))
+        })
+        .enumerate()
+        .map(|(i, (rank, item))| CompletionItem {
+            sort_text: Some(format!("{rank}{i:04}")),
+            filter_text: Some(item.label.clone()),
+            text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                range,
+                new_text: item.insert_text.clone().unwrap_or(item.label.clone()),
+            })),
+            ..item
+        })
+        .collect()
+}
+
+/// Figures out what is being completed at an offset by scanning the text
+/// before it. The text is likely incomplete while typing, so this doesn't
+/// rely on the AST.
+fn completion_context(text: &str, offset: usize) -> CompletionContext<'_> {
+    let before = &text[..offset];
+    let scan = Scan::new(before);
+    if scan.in_comment {
+        return CompletionContext::Nothing;
+    }
+    if let Some(string_start) = scan.in_string {
+        let call = before[..string_start].trim_end();
+        let is_format = call
+            .strip_suffix('(')
+            .map(str::trim_end)
+            .is_some_and(|x| x.ends_with("printf"));
+        return match before.rfind('%') {
+            Some(percent) if is_format && percent >= string_start => {
+                let prefix = &before[percent..];
+                // `%%` is a literal percent sign
+                if prefix.len() > 1 && prefix[1..].contains(|c: char| !c.is_ascii_alphanumeric()) {
+                    CompletionContext::Nothing
+                } else {
+                    CompletionContext::FormatSpecifier(prefix)
+                }
+            }
+            _ => CompletionContext::Nothing,
+        };
+    }
+    if scan.depth == 0 && !scan.in_filter {
+        let start = before
+            .rfind(|c: char| c.is_whitespace() || c == ',' || c == '}')
+            .map_or(0, |x| x + 1);
+        let prefix = &before[start..];
+        return if Scan::new(&before[..start]).between_probes {
+            CompletionContext::Preamble(prefix)
+        } else {
+            CompletionContext::AttachPoint(prefix)
+        };
+    }
+
+    let word_start = before
+        .rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .map_or(0, |x| x + 1);
+    let word = &before[word_start..];
+    let rest = &before[..word_start];
+    if rest.ends_with('$') {
+        return CompletionContext::Scratch(&before[word_start - 1..]);
+    }
+    if rest.ends_with('@') {
+        return CompletionContext::Map(&before[word_start - 1..]);
+    }
+    if let Some(base) = rest.strip_suffix("->").or_else(|| rest.strip_suffix('.')) {
+        return CompletionContext::Field {
+            base: field_base(base),
+            prefix: word,
+        };
+    }
+    CompletionContext::Expression(word)
+}
+
+/// The expression at the end of a text that a field is accessed on: an
+/// identifier or parenthesized expression, along with the fields accessed on
+/// it, as in `((struct path *)arg0)->dentry`.
+fn field_base(text: &str) -> &str {
+    let mut start = text.len();
+    loop {
+        let before = &text[..start];
+        if before.ends_with(')') || before.ends_with(']') {
+            let Some(open) = opening(before) else {
+                return &text[start..];
+            };
+            // a map's keys follow its name
+            start = if before.ends_with(']') {
+                name_start(&text[..open])
+            } else {
+                open
+            };
+        } else {
+            start = name_start(before);
+        }
+        let before = &text[..start];
+        match before
+            .strip_suffix("->")
+            .or_else(|| before.strip_suffix('.'))
+        {
+            Some(rest) if !rest.is_empty() => start = rest.len(),
+            _ => return &text[start..],
+        }
+    }
+}
+
+fn name_start(text: &str) -> usize {
+    text.rfind(|c: char| !(c.is_ascii_alphanumeric() || "_$@".contains(c)))
+        .map_or(0, |x| x + 1)
+}
+
+/// Offset of the bracket matching the one a text ends with.
+fn opening(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices().rev() {
+        match c {
+            ')' | ']' => depth += 1,
+            '(' | '[' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The C type of a field base, following casts, the types inferred for
+/// variables and maps and the fields of records.
+fn base_type(analyzed: &AnalyzedFile, base: &str, offset: usize) -> Option<String> {
+    let base = base.trim();
+    if let Some(inner) = base.strip_prefix('(')
+        && base.ends_with(')')
+        && opening(base) == Some(0)
+    {
+        return base_type(analyzed, &inner[..inner.len() - 1], offset);
+    }
+    if base.starts_with('(') {
+        // a cast, `(struct path *)arg0`
+        let close = base.find(')')?;
+        return Some(types::normalize(&base[1..close]));
+    }
+    let arrow = base.rfind("->").map(|x| (x, 2));
+    let dot = base.rfind('.').map(|x| (x, 1));
+    if let Some((at, len)) = arrow.max(dot).filter(|(at, _)| {
+        // only at the top level, not inside parentheses
+        let before = &base[..*at];
+        before.matches('(').count() == before.matches(')').count()
+    }) {
+        let ty = base_type(analyzed, &base[..at], offset)?;
+        let record = analyzed.definitions.record(&ty)?;
+        let field = &base[at + len..];
+        return Some(record.fields.iter().find(|x| x.name == field)?.ty.clone());
+    }
+
+    let name = &base[..base.find('[').unwrap_or(base.len())];
+    let inferred = types::infer(analyzed.ast(), &analyzed.definitions);
+    if let Some(name) = name.strip_prefix('$') {
+        return inferred
+            .variables
+            .iter()
+            .rfind(|(ident, _)| ident.name == name && ident.span.start() < offset)
+            .map(|(_, ty)| ty.clone());
+    }
+    if let Some(name) = name.strip_prefix('@') {
+        return inferred
+            .maps
+            .iter()
+            .find(|x| x.ident.name == name)?
+            .value
+            .clone();
+    }
+    BUILTINS
+        .keywords
+        .iter()
+        .find(|x| x.name == name)
+        .map(|x| x.detail.to_string())
+}
+
+/// Fields accessed on `base` elsewhere in the document, in order of first
+/// appearance.
+fn used_fields<'a>(text: &'a str, base: &str) -> Vec<&'a str> {
+    let mut fields = Vec::new();
+    if base.is_empty() {
+        return fields;
+    }
+    for (index, _) in text.match_indices(base) {
+        let boundary = text[..index]
+            .chars()
+            .next_back()
+            .is_none_or(|c| !(c.is_ascii_alphanumeric() || "_$@".contains(c)));
+        let rest = &text[index + base.len()..];
+        let Some(rest) = rest.strip_prefix("->").or_else(|| rest.strip_prefix('.')) else {
+            continue;
+        };
+        let field = &rest[..rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len())];
+        if boundary && !field.is_empty() && !fields.contains(&field) {
+            fields.push(field);
+        }
+    }
+    fields
+}
+
+/// Lexical state at the end of a text.
+#[derive(Debug, Default)]
+struct Scan {
+    in_comment: bool,
+    /// Offset of the opening quote of the string the text ends in.
+    in_string: Option<usize>,
+    /// Nesting of braces, zero outside of probe bodies.
+    depth: usize,
+    /// Whether the text ends in a probe filter `/.../`.
+    in_filter: bool,
+    /// Whether nothing but whitespace and comments follows the last probe.
+    between_probes: bool,
+}
+
+impl Scan {
+    fn new(text: &str) -> Self {
+        let mut scan = Self {
+            between_probes: true,
+            ..Default::default()
+        };
+        let mut block_comment = false;
+        let mut chars = text.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if block_comment {
+                if c == '*' && chars.next_if(|(_, c)| *c == '/').is_some() {
+                    block_comment = false;
+                }
+                continue;
+            }
+            if scan.in_comment {
+                scan.in_comment = c != '\n';
+                continue;
+            }
+            if scan.in_string.is_some() {
+                match c {
+                    '\\' => {
+                        chars.next();
+                    }
+                    '"' | '\n' => scan.in_string = None,
+                    _ => {}
+                }
+                continue;
+            }
+            if c == '#' && scan.depth == 0 {
+                // `#include` and `#define` lines
+                while chars.next_if(|(_, c)| *c != '\n').is_some() {}
+                scan.between_probes = true;
+                continue;
+            }
+            match c {
+                '"' => scan.in_string = Some(i),
+                '/' if chars.next_if(|(_, c)| *c == '/').is_some() => scan.in_comment = true,
+                '/' if chars.next_if(|(_, c)| *c == '*').is_some() => block_comment = true,
+                '/' if scan.depth == 0 => scan.in_filter = !scan.in_filter,
+                '{' => {
+                    scan.depth += 1;
+                    scan.in_filter = false;
+                }
+                '}' => scan.depth = scan.depth.saturating_sub(1),
+                _ => {}
+            }
+            if scan.depth == 0 && !(scan.in_comment || block_comment || c.is_whitespace()) {
+                scan.between_probes = c == '}' || c == ';';
+            }
+        }
+        scan.in_comment |= block_comment;
+        scan
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_completion_context() {
+        let context = |text: &str| {
+            let offset = text.find('|').unwrap();
+            let text = text.replace('|', "");
+            format!("{:?}", completion_context(&text, offset))
+        };
+        assert_eq!(context("kpr|"), r#"Preamble("kpr")"#);
+        assert_eq!(context("BEGIN {} kprobe:f, u|"), r#"AttachPoint("u")"#);
+        assert_eq!(context("BEGIN {} kprobe:f,u|"), r#"AttachPoint("u")"#);
+        assert_eq!(context("BEGIN {}\nkprobe:f\nu|"), r#"AttachPoint("u")"#);
+        assert_eq!(
+            context("BEGIN { @a = 1; } kprobe:vfs_|"),
+            r#"Preamble("kprobe:vfs_")"#
+        );
+        assert_eq!(context("BEGIN /pi|"), r#"Expression("pi")"#);
+        assert_eq!(context("BEGIN { $x = @co| }"), r#"Map("@co")"#);
+        assert_eq!(context("BEGIN { $x = 1; print($|"), r#"Scratch("$")"#);
+        assert_eq!(
+            context("BEGIN { print(args->fi|"),
+            r#"Field { base: "args", prefix: "fi" }"#
+        );
+        assert_eq!(
+            context("BEGIN { print($task.pi|"),
+            r#"Field { base: "$task", prefix: "pi" }"#
+        );
+        assert_eq!(
+            context("BEGIN { print(((struct path *)arg0)->dentry->d_|"),
+            r#"Field { base: "((struct path *)arg0)->dentry", prefix: "d_" }"#
+        );
+        assert_eq!(
+            context("BEGIN { print(@p[tid].m|"),
+            r#"Field { base: "@p[tid]", prefix: "m" }"#
+        );
+        assert_eq!(
+            context("#include <linux/path.h>\nstruct s { int a; };\nkpr|"),
+            r#"Preamble("kpr")"#
+        );
+        assert_eq!(
+            context(r#"BEGIN { printf("%d %|"#),
+            r#"FormatSpecifier("%")"#
+        );
+        assert_eq!(
+            context(r#"BEGIN { printf("%ll|"#),
+            r#"FormatSpecifier("%ll")"#
+        );
+        assert_eq!(context(r#"BEGIN { printf("% |"#), "Nothing");
+        assert_eq!(context(r#"BEGIN { print("%|"#), "Nothing");
+        assert_eq!(
+            context(r#"BEGIN { printf("a\"%|"#),
+            r#"FormatSpecifier("%")"#
+        );
+        assert_eq!(context("BEGIN { // pri|"), "Nothing");
+        assert_eq!(context("BEGIN { /* pri|"), "Nothing");
+        assert_eq!(context("BEGIN { /* */ pri|"), r#"Expression("pri")"#);
+        assert_eq!(context("// kprobe\nkpr|"), r#"Preamble("kpr")"#);
+        assert_eq!(context("BEGIN {} /* } */ |"), r#"Preamble("")"#);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let item = Builtins::Function
+            .items()
+            .find(|x| x.label == "printf")
+            .unwrap();
+        assert!(item.detail.is_none() && item.documentation.is_none());
+        let resolved = resolve(item);
+        assert!(resolved.detail.unwrap().starts_with("printf("));
+        assert!(resolved.documentation.is_some());
+
+        let resolved = resolve(snippet_item("x", "", "BEGIN {}"));
+        let Some(Documentation::MarkupContent(doc)) = resolved.documentation else {
+            panic!("expected markdown");
+        };
+        assert_eq!(doc.value, "```bpftrace\nBEGIN {}\n```");
+
+        let unknown = CompletionItem::new_simple("$x".to_string(), String::new());
+        assert_eq!(resolve(unknown.clone()), unknown);
+    }
+
+    #[test]
+    fn test_used_fields() {
+        let text = "BEGIN { args->a; args->b; $args.c; args->a; xargs->d; }";
+        assert_eq!(used_fields(text, "args"), ["a", "b"]);
+        assert_eq!(used_fields(text, "$args"), ["c"]);
+    }
 }
@@ -1,12 +1,431 @@
+use crate::parser::ErrorRef;
+use anyhow::{Context, Result, anyhow, bail};
 use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tower_lsp::lsp_types::DiagnosticSeverity;
 
-#[derive(Debug, Default, Deserialize)]
+/// Name of the per-project configuration file.
+pub const PROJECT_FILE: &str = ".btls.toml";
+
+/// Settings holding paths, which are relative to the project file they're
+/// read from.
+const PATH_SETTINGS: &[&str] = &["tracefs_path", "btf_path", "kallsyms_path", "include_paths"];
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Information,
+    Hint,
+    Off,
+}
+
+impl Severity {
+    pub fn to_lsp(self) -> Option<DiagnosticSeverity> {
+        match self {
+            Self::Error => Some(DiagnosticSeverity::ERROR),
+            Self::Warning => Some(DiagnosticSeverity::WARNING),
+            Self::Information => Some(DiagnosticSeverity::INFORMATION),
+            Self::Hint => Some(DiagnosticSeverity::HINT),
+            Self::Off => None,
+        }
+    }
+}
+
+/// A bpftrace release, e.g. `0.21` or `0.21.2`.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[serde(try_from = "String")]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("invalid bpftrace version \"{s}\"");
+        let mut parts = s.trim().trim_start_matches('v').split('.');
+        let mut next = |required| match parts.next() {
+            Some(part) => part.parse::<u32>().map_err(|_| invalid()),
+            None if required => Err(invalid()),
+            None => Ok(0),
+        };
+        let version = Self::new(next(true)?, next(true)?, next(false)?);
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(version)
+    }
+}
+
+impl TryFrom<String> for Version {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FormatterConfig {
+    pub indent_width: u32,
+    pub use_tabs: bool,
+}
+
+impl Default for FormatterConfig {
+    fn default() -> Self {
+        Self {
+            indent_width: 4,
+            use_tabs: false,
+        }
+    }
+}
+
+/// Which inlay hints to show.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct InlayHintsConfig {
+    /// Inferred types of scratch variables, e.g. `$lat: uint64`.
+    pub variable_types: bool,
+    /// Key and value types of maps where they are first written.
+    pub map_types: bool,
+    /// Names of the parameters of builtin functions, e.g. `min:`.
+    pub parameter_names: bool,
+}
+
+impl Default for InlayHintsConfig {
+    fn default() -> Self {
+        Self {
+            variable_types: true,
+            map_types: true,
+            parameter_names: true,
+        }
+    }
+}
+
+/// A snippet completion added on top of the builtin ones.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SnippetConfig {
+    pub label: String,
+    #[serde(default)]
+    pub detail: String,
+    /// In the LSP snippet syntax, with tab stops like `${1:default}`.
+    pub body: String,
+}
+
+/// What a positional parameter is expected to hold.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamType {
+    Int,
+    #[serde(alias = "str")]
+    String,
+}
+
+impl FromStr for ParamType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "int" => Ok(Self::Int),
+            "str" | "string" => Ok(Self::String),
+            _ => bail!("invalid parameter type \"{s}\""),
+        }
+    }
+}
+
+impl fmt::Display for ParamType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Int => "int",
+            Self::String => "string",
+        })
+    }
+}
+
+/// A positional parameter scripts expect, `$1` being the first one listed.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ParamConfig {
+    #[serde(rename = "type")]
+    pub ty: Option<ParamType>,
+    pub description: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub diagnostics: bool,
+    /// Overrides the default severity of checks, keyed by check code.
+    pub severities: HashMap<String, Severity>,
+    /// The bpftrace release scripts are written for, latest if unset.
+    pub bpftrace_version: Option<Version>,
+    /// The architecture scripts run on, as in `x86_64`, which limits how
+    /// many of `arg0`, `arg1`, ... there are. The one of the server if unset.
+    pub arch: String,
+    /// Where the tracepoints completed after `tracepoint:` are listed.
+    pub tracefs_path: PathBuf,
+    /// Kernel types not found in included headers are looked up here.
+    pub btf_path: PathBuf,
+    /// Where the functions completed after `kprobe:` and the like are listed.
+    pub kallsyms_path: PathBuf,
+    pub include_paths: Vec<PathBuf>,
+    pub formatter: FormatterConfig,
+    pub inlay_hints: InlayHintsConfig,
+    /// Offered where a new probe can start, along with the builtin snippets.
+    pub snippets: Vec<SnippetConfig>,
+    /// Positional parameters of scripts that don't declare their own.
+    pub positional_params: Vec<ParamConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            diagnostics: true,
+            severities: HashMap::new(),
+            bpftrace_version: None,
+            arch: std::env::consts::ARCH.to_string(),
+            tracefs_path: PathBuf::from("/sys/kernel/tracing"),
+            btf_path: PathBuf::from("/sys/kernel/btf/vmlinux"),
+            kallsyms_path: PathBuf::from("/proc/kallsyms"),
+            include_paths: Vec::new(),
+            formatter: FormatterConfig::default(),
+            inlay_hints: InlayHintsConfig::default(),
+            snippets: Vec::new(),
+            positional_params: Vec::new(),
+        }
+    }
 }
 
 impl Config {
-    pub fn from_value(value: serde_json::Value) -> Self {
-        serde_json::from_value(value).unwrap_or_default()
+    /// Builds the configuration of a document. Settings of the project file
+    /// take precedence over the client's settings, which take precedence
+    /// over the defaults. Relative paths in the client's settings are
+    /// relative to the workspace folder of the document.
+    pub fn resolve(
+        client_settings: Value,
+        folder: Option<&Path>,
+        project_file: Option<&Path>,
+    ) -> Result<Self> {
+        let mut settings = client_settings;
+        if let Some(folder) = folder {
+            absolute_paths(&mut settings, folder);
+        }
+        if let Some(project_file) = project_file {
+            let project = read_project_file(project_file)
+                .with_context(|| format!("in {}", project_file.display()))?;
+            merge(&mut settings, project);
+        }
+        Self::from_value(settings)
+    }
+
+    pub fn from_value(value: Value) -> Result<Self> {
+        if value.is_null() {
+            return Ok(Self::default());
+        }
+        let config: Self = serde_json::from_value(value)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(check) = self
+            .severities
+            .keys()
+            .find(|check| !ErrorRef::CODES.contains(&check.as_str()))
+        {
+            bail!("unknown check \"{check}\" in severities");
+        }
+        if self.formatter.indent_width == 0 {
+            bail!("formatter.indent_width must be greater than zero");
+        }
+        if self.snippets.iter().any(|x| x.label.is_empty()) {
+            bail!("snippets must have a label");
+        }
+        Ok(())
+    }
+
+    /// The severity to report a check with, `None` if it is turned off.
+    pub fn severity(&self, code: &str, default: Severity) -> Option<DiagnosticSeverity> {
+        self.severities
+            .get(code)
+            .copied()
+            .unwrap_or(default)
+            .to_lsp()
+    }
+}
+
+/// Finds the project file closest to `path`, searching its directory and
+/// then each parent directory.
+pub fn find_project_file(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .skip(1)
+        .map(|dir| dir.join(PROJECT_FILE))
+        .find(|file| file.is_file())
+}
+
+fn read_project_file(path: &Path) -> Result<Value> {
+    let content = std::fs::read_to_string(path)?;
+    let mut settings = serde_json::to_value(toml::from_str::<toml::Table>(&content)?)?;
+
+    absolute_paths(&mut settings, path.parent().unwrap_or(Path::new("")));
+    Ok(settings)
+}
+
+/// Makes the paths in settings absolute, relative ones being relative to
+/// `dir`.
+fn absolute_paths(settings: &mut Value, dir: &Path) {
+    let absolute = |value: &mut Value| {
+        if let Value::String(s) = value {
+            *s = dir.join(&*s).to_string_lossy().into_owned();
+        }
+    };
+    for key in PATH_SETTINGS {
+        match settings.get_mut(*key) {
+            Some(Value::Array(values)) => values.iter_mut().for_each(absolute),
+            Some(value) => absolute(value),
+            None => {}
+        }
+    }
+}
+
+/// Merges `overlay` into `base`, tables are merged key by key while any other
+/// value in `overlay` replaces the one in `base`.
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(Config::from_value(json!(null)).unwrap(), Config::default());
+        assert_eq!(Config::from_value(json!({})).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_settings() {
+        let config = Config::from_value(json!({
+            "diagnostics": false,
+            "severities": { "undefined-func": "warning", "unknown-statement": "off" },
+            "bpftrace_version": "0.21",
+            "arch": "aarch64",
+            "include_paths": ["/usr/include"],
+            "formatter": { "use_tabs": true },
+            "inlay_hints": { "parameter_names": false },
+            "snippets": [{ "label": "trace", "body": "kprobe:$1 { $0 }" }],
+            "positional_params": [{ "type": "int", "description": "pid" }, { "type": "str" }],
+        }))
+        .unwrap();
+        assert!(!config.diagnostics);
+        assert_eq!(config.bpftrace_version, Some(Version::new(0, 21, 0)));
+        assert_eq!(config.arch, "aarch64");
+        assert_eq!(
+            config.severity("undefined-func", Severity::Error),
+            Some(DiagnosticSeverity::WARNING)
+        );
+        assert_eq!(config.severity("unknown-statement", Severity::Error), None);
+        assert_eq!(config.formatter.indent_width, 4);
+        assert!(config.inlay_hints.variable_types && !config.inlay_hints.parameter_names);
+        assert_eq!(config.snippets[0].label, "trace");
+        assert_eq!(config.positional_params[0].ty, Some(ParamType::Int));
+        assert_eq!(config.positional_params[1].ty, Some(ParamType::String));
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        assert!(Config::from_value(json!({ "diagnostic": true })).is_err());
+        assert!(Config::from_value(json!({ "diagnostics": "yes" })).is_err());
+        assert!(Config::from_value(json!({ "bpftrace_version": "latest" })).is_err());
+        assert!(Config::from_value(json!({ "severities": { "typo": "error" } })).is_err());
+        assert!(Config::from_value(json!({ "severities": { "undefined-func": "loud" } })).is_err());
+        assert!(Config::from_value(json!({ "snippets": [{ "label": "", "body": "" }] })).is_err());
+        assert!(Config::from_value(json!({ "snippets": [{ "label": "x" }] })).is_err());
+        assert!(Config::from_value(json!({ "positional_params": [{ "type": "u64" }] })).is_err());
+    }
+
+    #[test]
+    fn test_project_file() {
+        let root = std::env::temp_dir().join(format!("btls-config-{}", std::process::id()));
+        let scripts = root.join("tools").join("net");
+        std::fs::create_dir_all(&scripts).unwrap();
+        std::fs::write(
+            root.join(PROJECT_FILE),
+            r#"
+            bpftrace_version = "0.20"
+            include_paths = ["include"]
+
+            [severities]
+            undefined-func = "hint"
+            "#,
+        )
+        .unwrap();
+
+        let script = scripts.join("tcp.bt");
+        let project_file = find_project_file(&script);
+        assert_eq!(project_file, Some(root.join(PROJECT_FILE)));
+
+        let client = json!({
+            "bpftrace_version": "0.21",
+            "severities": { "undefined-ident": "warning" },
+        });
+        let config = Config::resolve(client, None, project_file.as_deref()).unwrap();
+        assert_eq!(config.bpftrace_version, Some(Version::new(0, 20, 0)));
+        assert_eq!(config.include_paths, vec![root.join("include")]);
+        assert_eq!(
+            config.severity("undefined-func", Severity::Error),
+            Some(DiagnosticSeverity::HINT)
+        );
+        assert_eq!(
+            config.severity("undefined-ident", Severity::Error),
+            Some(DiagnosticSeverity::WARNING)
+        );
+
+        std::fs::write(root.join(PROJECT_FILE), "bpftrace_versoin = 1").unwrap();
+        assert!(Config::resolve(json!(null), None, project_file.as_deref()).is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
     }
 }
@@ -0,0 +1,129 @@
+use super::analyzer::semantic_analyzer::AnalyzedFile;
+use super::analyzer::types;
+use super::headers::{self, Definition};
+use super::parser::{Expr, IdentKind, Include, Node, Preamble, Walk};
+use super::server::Context;
+use std::path::{Path, PathBuf};
+use tower_lsp::jsonrpc::{Error, ErrorCode, Result};
+use tower_lsp::lsp_types::{DocumentLink, GotoDefinitionResponse, Location, Position, Range, Url};
+
+/// Where the header an `#include` names, a type in a cast, a field or a
+/// constant is defined. Definitions in the script itself are in its own
+/// document, kernel types only known from BTF in a generated header.
+pub async fn definition(
+    context: &Context,
+    uri: &Url,
+    position: Position,
+) -> Result<Option<GotoDefinitionResponse>> {
+    let analyzed = context
+        .analyzer
+        .analyze(context, uri)
+        .await
+        .map_err(|_| Error::new(ErrorCode::InternalError))?;
+    let Some(offset) = analyzed.document.line_index.offset(position) else {
+        return Ok(None);
+    };
+    let contains = |span: pest::Span| span.start() <= offset && offset <= span.end();
+
+    for (include, file) in includes(&analyzed) {
+        if contains(include.path_span)
+            && let Some(uri) = file.and_then(|x| Url::from_file_path(x).ok())
+        {
+            let start = Position::new(0, 0);
+            let location = Location::new(uri, Range::new(start, start));
+            return Ok(Some(GotoDefinitionResponse::Scalar(location)));
+        }
+    }
+
+    let config = context.config(uri).await;
+    let definitions = &analyzed.definitions;
+    let inferred = types::infer(analyzed.ast(), definitions);
+    let mut found = None;
+    for node in Walk::new(analyzed.ast().as_node()) {
+        let location = match node.as_expr() {
+            Some(Expr::Field(field)) if contains(field.field.span) => inferred
+                .fields
+                .iter()
+                .find(|(x, _)| std::ptr::eq(*x, field.as_ref()))
+                .and_then(|(_, base)| definitions.record(base.as_deref()?))
+                .and_then(|record| {
+                    let member = record.fields.iter().find(|x| x.name == field.field.name)?;
+                    location(uri, record, member.range)
+                }),
+            Some(Expr::Cast(cast)) if contains(cast.ty_span) => match definitions.lookup(cast.ty) {
+                Some(x) => location(uri, x, x.range),
+                None => btf_location(context, &config.btf_path, cast.ty),
+            },
+            Some(Expr::Identifier(ident))
+                if ident.kind == IdentKind::Bare && contains(ident.span) =>
+            {
+                definitions
+                    .constant(ident.name)
+                    .and_then(|x| location(uri, x, x.range))
+            }
+            _ => None,
+        };
+        // the innermost node wins
+        if location.is_some() {
+            found = location;
+        }
+    }
+    Ok(found.map(GotoDefinitionResponse::Scalar))
+}
+
+/// A link to the header of each `#include` that resolved.
+pub async fn document_links(context: &Context, uri: &Url) -> Result<Option<Vec<DocumentLink>>> {
+    let analyzed = context
+        .analyzer
+        .analyze(context, uri)
+        .await
+        .map_err(|_| Error::new(ErrorCode::InternalError))?;
+    let links = includes(&analyzed)
+        .filter_map(|(include, file)| {
+            let file = file?;
+            Some(DocumentLink {
+                range: analyzed.document.line_index.range(include.path_span),
+                target: Some(Url::from_file_path(file).ok()?),
+                tooltip: Some(file.display().to_string()),
+                data: None,
+            })
+        })
+        .collect();
+    Ok(Some(links))
+}
+
+/// The `#include`s of a script along with the headers they resolved to.
+pub fn includes<'b>(
+    analyzed: &'b AnalyzedFile,
+) -> impl Iterator<Item = (&'b Include<'b>, Option<&'b PathBuf>)> {
+    let includes = analyzed.ast().preambles.iter().filter_map(|x| match x {
+        Preamble::Include(include) => Some(include),
+        _ => None,
+    });
+    includes.zip(analyzed.includes.iter().map(Option::as_ref))
+}
+
+/// Where the generated header of a kernel type puts its name.
+fn btf_location(context: &Context, btf_path: &Path, ty: &str) -> Option<Location> {
+    let (kind, name) = headers::named_type(ty)?;
+    let file = context.btf.document(btf_path, kind, name)?;
+    let header = headers::scan(&std::fs::read_to_string(&file).ok()?);
+    let definition = header
+        .definitions
+        .iter()
+        .find(|x| x.kind == kind && x.name == name)?;
+    Some(Location::new(
+        Url::from_file_path(file).ok()?,
+        definition.range,
+    ))
+}
+
+/// A range in the header a definition is from, or in the script for those
+/// it defines itself.
+fn location(script: &Url, definition: &Definition, range: Range) -> Option<Location> {
+    let uri = match &definition.file {
+        Some(file) => Url::from_file_path(file).ok()?,
+        None => script.clone(),
+    };
+    Some(Location::new(uri, range))
+}
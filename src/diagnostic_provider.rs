@@ -1,42 +1,246 @@
+use super::analyzer::semantic_analyzer::AnalyzedFile;
+use super::code_action_provider;
+use super::config::{Config, Severity};
 use super::parser::Node;
 use super::server::Context;
-use tower_lsp::jsonrpc::{Error, ErrorCode};
-use tower_lsp::lsp_types::{Diagnostic, DiagnosticSeverity, Url};
+use super::storage::DocumentVersion;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tower_lsp::jsonrpc::{Error, ErrorCode, Result};
+use tower_lsp::lsp_types::{
+    Diagnostic, DiagnosticTag, DocumentDiagnosticReport, FullDocumentDiagnosticReport,
+    NumberOrString, RelatedFullDocumentDiagnosticReport, RelatedUnchangedDocumentDiagnosticReport,
+    UnchangedDocumentDiagnosticReport, Url, WorkspaceDiagnosticReport,
+    WorkspaceDocumentDiagnosticReport, WorkspaceFullDocumentDiagnosticReport,
+    WorkspaceUnchangedDocumentDiagnosticReport,
+};
+
+/// How long a document has to stay unchanged before it gets analyzed.
+pub const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Runs diagnostics in the background, at most one pending run per document.
+/// Scheduling a document again cancels whatever run is still pending for it.
+///
+/// Clients that pull diagnostics themselves get nothing pushed.
+#[derive(Default)]
+pub struct DiagnosticScheduler {
+    pending: Mutex<HashMap<Url, JoinHandle<()>>>,
+    pull: AtomicBool,
+    generation: AtomicU64,
+}
+
+impl DiagnosticScheduler {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn set_pull(&self, pull: bool) {
+        self.pull.store(pull, Ordering::Relaxed);
+    }
+
+    pub fn is_pull(&self) -> bool {
+        self.pull.load(Ordering::Relaxed)
+    }
+
+    /// Invalidates every result id handed out so far, e.g. because the
+    /// configuration changed.
+    pub fn invalidate_results(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Changes along with the document, its configuration and the headers
+    /// it includes, as the analysis is made again then.
+    fn result_id(&self, analyzed: &AnalyzedFile) -> String {
+        let generation = self.generation.load(Ordering::Relaxed);
+        format!("{generation}:{}", analyzed.id)
+    }
+
+    pub fn schedule(&self, context: &Arc<Context>, uri: Url, delay: Duration) {
+        if self.is_pull() {
+            return;
+        }
+        let context = context.clone();
+        let key = uri.clone();
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            publish_diagnostics(&context, uri).await;
+        });
+        if let Some(previous) = self.pending.lock().unwrap().insert(key, task) {
+            previous.abort();
+        }
+    }
+
+    pub fn cancel(&self, uri: &Url) {
+        if let Some(task) = self.pending.lock().unwrap().remove(uri) {
+            task.abort();
+        }
+    }
+}
 
 pub async fn publish_diagnostics(context: &Context, uri: Url) {
-    let Ok(path) = uri.to_file_path() else {
+    let config = context.config(&uri).await;
+    if !config.diagnostics {
+        context.client.publish_diagnostics(uri, vec![], None).await;
+        return;
+    }
+
+    let Ok(analyzed_file) = context.analyzer.analyze(context, &uri).await else {
         return;
     };
 
-    let config = context.client.config().await;
-    if !config.diagnostics {
+    // the document changed while it was being analyzed, a newer run will
+    // publish for it
+    let version = context.storage.lock().await.read_version(&uri);
+    if version != analyzed_file.document.version {
         return;
     }
 
-    let mut analyzer = context.analyzer.lock().await;
-    let analyzed_file = match analyzer
-        .analyze(context, &path)
-        .await
-        .map_err(|_| Error::new(ErrorCode::InternalError))
-    {
-        Ok(f) => f,
-        _ => return,
+    let digs = diagnostics(&analyzed_file, &config);
+
+    let version = match version {
+        DocumentVersion::InMemory { revision } => Some(revision),
+        _ => None,
     };
+    context.client.publish_diagnostics(uri, digs, version).await;
+}
 
-    let digs = analyzed_file
-        .ast
+pub fn diagnostics(analyzed_file: &AnalyzedFile, config: &Config) -> Vec<Diagnostic> {
+    analyzed_file
+        .ast()
         .as_node()
         .errors()
-        .map(|e| Diagnostic {
-            range: analyzed_file.document.line_index.range(e.span()),
-            severity: Some(DiagnosticSeverity::ERROR),
-            message: e.diagnosis(),
-            ..Default::default()
+        .filter_map(|e| {
+            let severity = config.severity(e.code(), default_severity(e.code()))?;
+            Some(Diagnostic {
+                range: analyzed_file.document.line_index.range(e.span()),
+                severity: Some(severity),
+                tags: tags(e.code()),
+                code: Some(NumberOrString::String(e.code().to_string())),
+                source: Some("btls".to_string()),
+                message: e.diagnosis(),
+                data: Some(code_action_provider::fixes(analyzed_file, e, config))
+                    .filter(|x| !x.is_empty())
+                    .and_then(|x| serde_json::to_value(x).ok()),
+                ..Default::default()
+            })
         })
-        .collect();
+        .collect()
+}
+
+fn default_severity(code: &str) -> Severity {
+    match code {
+        "unused-variable"
+        | "write-only-map"
+        | "map-read-before-write"
+        | "map-leak"
+        | "unresolved-include"
+        | "positional-param"
+        | "getopt-conflict"
+        // the table of providers can't know those of every release
+        | "unknown-provider" => Severity::Warning,
+        _ => Severity::Error,
+    }
+}
+
+fn tags(code: &str) -> Option<Vec<DiagnosticTag>> {
+    match code {
+        "unused-variable" | "write-only-map" => Some(vec![DiagnosticTag::UNNECESSARY]),
+        _ => None,
+    }
+}
+
+/// Answers a `textDocument/diagnostic` request. Documents whose analysis
+/// matches `previous_result_id` are reported as unchanged, the analysis being
+/// cached as long as neither the document, its configuration nor its headers
+/// change.
+pub async fn document_diagnostic(
+    context: &Context,
+    uri: &Url,
+    previous_result_id: Option<String>,
+) -> Result<DocumentDiagnosticReport> {
+    let analyzed = analyze(context, uri).await?;
+    let result_id = context.diagnostics.result_id(&analyzed);
+    if previous_result_id.as_ref() == Some(&result_id) {
+        return Ok(DocumentDiagnosticReport::Unchanged(
+            RelatedUnchangedDocumentDiagnosticReport {
+                related_documents: None,
+                unchanged_document_diagnostic_report: UnchangedDocumentDiagnosticReport {
+                    result_id,
+                },
+            },
+        ));
+    }
 
+    let report = full_report(context, &analyzed).await;
+    Ok(DocumentDiagnosticReport::Full(
+        RelatedFullDocumentDiagnosticReport {
+            related_documents: None,
+            full_document_diagnostic_report: report,
+        },
+    ))
+}
+
+/// Answers a `workspace/diagnostic` request for every open document.
+pub async fn workspace_diagnostic(
+    context: &Context,
+    previous_result_ids: HashMap<Url, String>,
+) -> Result<WorkspaceDiagnosticReport> {
+    let docs = context.storage.lock().await.memory_docs();
+    let mut items = vec![];
+    for doc in docs {
+        let uri = doc.uri.clone();
+        let version = match doc.version {
+            DocumentVersion::InMemory { revision } => Some(revision as i64),
+            _ => None,
+        };
+        let Ok(analyzed) = analyze(context, &uri).await else {
+            continue;
+        };
+        let result_id = context.diagnostics.result_id(&analyzed);
+        if previous_result_ids.get(&uri) == Some(&result_id) {
+            items.push(WorkspaceDocumentDiagnosticReport::Unchanged(
+                WorkspaceUnchangedDocumentDiagnosticReport {
+                    uri,
+                    version,
+                    unchanged_document_diagnostic_report: UnchangedDocumentDiagnosticReport {
+                        result_id,
+                    },
+                },
+            ));
+            continue;
+        }
+        let report = full_report(context, &analyzed).await;
+        items.push(WorkspaceDocumentDiagnosticReport::Full(
+            WorkspaceFullDocumentDiagnosticReport {
+                uri,
+                version,
+                full_document_diagnostic_report: report,
+            },
+        ));
+    }
+    Ok(WorkspaceDiagnosticReport { items })
+}
+
+async fn analyze(context: &Context, uri: &Url) -> Result<Arc<AnalyzedFile>> {
     context
-        .client
-        .publish_diagnostics(uri.clone(), digs, None)
-        .await;
+        .analyzer
+        .analyze(context, uri)
+        .await
+        .map_err(|_| Error::new(ErrorCode::InternalError))
+}
+
+async fn full_report(context: &Context, analyzed: &AnalyzedFile) -> FullDocumentDiagnosticReport {
+    let config = context.config(&analyzed.document.uri).await;
+    let items = if config.diagnostics {
+        diagnostics(analyzed, &config)
+    } else {
+        vec![]
+    };
+    FullDocumentDiagnosticReport {
+        result_id: Some(context.diagnostics.result_id(analyzed)),
+        items,
+    }
 }
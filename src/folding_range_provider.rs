@@ -0,0 +1,143 @@
+use super::parser::{Node, Preamble, Statement, Walk};
+use super::server::Context;
+use crate::common::utils::OwnedLineIndex;
+use tower_lsp::jsonrpc::{Error, ErrorCode, Result};
+use tower_lsp::lsp_types::{FoldingRange, FoldingRangeKind, Url};
+
+pub async fn folding_ranges(context: &Context, uri: &Url) -> Result<Option<Vec<FoldingRange>>> {
+    let analyzed = context
+        .analyzer
+        .analyze(context, uri)
+        .await
+        .map_err(|_| Error::new(ErrorCode::InternalError))?;
+    let line_index = &analyzed.document.line_index;
+    let text = analyzed.document.data.as_str();
+
+    let mut ranges = Vec::new();
+    for preamble in &analyzed.ast().preambles {
+        let Preamble::Probe(probe) = preamble else {
+            continue;
+        };
+        ranges.extend(fold(text, line_index, probe.span.start(), probe.span.end()));
+        let blocks = Walk::new(probe.block.as_node()).filter_map(|x| match x.as_statement()? {
+            Statement::IfCond(_) | Statement::Loop(_) => Some(x.span()),
+            _ => None,
+        });
+        for block in blocks {
+            ranges.extend(fold(text, line_index, block.start(), block.end()));
+        }
+    }
+    ranges.extend(
+        comment_runs(text)
+            .into_iter()
+            .filter(|(start, end)| start < end)
+            .map(|(start, end)| FoldingRange {
+                start_line: start,
+                end_line: end,
+                kind: Some(FoldingRangeKind::Comment),
+                ..Default::default()
+            }),
+    );
+    ranges.sort_by_key(|x| (x.start_line, std::cmp::Reverse(x.end_line)));
+    ranges.dedup_by_key(|x| x.start_line);
+    Ok(Some(ranges))
+}
+
+/// Folds the lines from `start` to `end`, leaving the closing brace visible
+/// if it starts its own line.
+fn fold(text: &str, line_index: &OwnedLineIndex, start: usize, end: usize) -> Option<FoldingRange> {
+    let start_line = line_index.position(start).line;
+    let mut end_line = line_index.position(end).line;
+    let line_start = text[..end].rfind('\n').map_or(0, |x| x + 1);
+    if text[line_start..end].trim() == "}" {
+        end_line = end_line.checked_sub(1)?;
+    }
+    (start_line < end_line).then(|| FoldingRange {
+        start_line,
+        end_line,
+        kind: Some(FoldingRangeKind::Region),
+        ..Default::default()
+    })
+}
+
+/// First and last lines of each block comment and of each run of line
+/// comments on consecutive lines. Comments trailing code are left out.
+fn comment_runs(text: &str) -> Vec<(u32, u32)> {
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    // the line a run of line comments continues on
+    let mut continues = None;
+    let mut line = 0;
+    let mut code = false;
+    let mut in_string = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => {
+                line += 1;
+                code = false;
+            }
+            '\\' if in_string => {
+                chars.next();
+            }
+            '"' => {
+                in_string = !in_string;
+                code = true;
+            }
+            '/' if !in_string && chars.next_if_eq(&'/').is_some() => {
+                match runs.last_mut() {
+                    _ if code => {}
+                    Some(run) if continues == Some(line) => run.1 = line,
+                    _ => runs.push((line, line)),
+                }
+                continues = (!code).then_some(line + 1);
+                code = false;
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+                        break;
+                    }
+                }
+            }
+            '/' if !in_string && chars.next_if_eq(&'*').is_some() => {
+                let start = line;
+                let mut star = false;
+                for c in chars.by_ref() {
+                    match c {
+                        '/' if star => break,
+                        '\n' => line += 1,
+                        _ => {}
+                    }
+                    star = c == '*';
+                }
+                runs.push((start, line));
+                continues = None;
+            }
+            c if c.is_whitespace() => {}
+            _ => {
+                continues = None;
+                code = true;
+            }
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comment_runs() {
+        let text = r#"// one
+// two
+
+BEGIN { // trailing
+    // three
+    printf("// not a comment");
+    /* four
+       five */
+}
+"#;
+        assert_eq!(comment_runs(text), [(0, 1), (4, 4), (6, 7)]);
+    }
+}
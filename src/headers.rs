@@ -0,0 +1,953 @@
+use crate::common::utils::LineIndex;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tower_lsp::lsp_types::Range;
+
+/// Where headers are looked up after the configured include paths, the way
+/// a C compiler would, `{}` being the multiarch directory of the architecture.
+const SYSTEM_INCLUDE_PATHS: &[&str] = &[
+    "/usr/local/include",
+    "/usr/include/{}-linux-gnu",
+    "/usr/include",
+];
+
+/// Kernel headers pull in a lot of others, includes stop being followed
+/// after this many headers.
+const MAX_HEADERS: usize = 512;
+
+/// Words of a declaration that say nothing about the type, left out of the
+/// types shown.
+const ANNOTATIONS: &[&str] = &[
+    "extern",
+    "static",
+    "inline",
+    "register",
+    "__extension__",
+    "__rcu",
+    "__user",
+    "__iomem",
+    "__percpu",
+    "__force",
+    "__bitwise",
+    "__private",
+    "__randomize_layout",
+    "__packed",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DefinitionKind {
+    Struct,
+    Union,
+    Enum,
+    Typedef,
+    Macro,
+    Enumerator,
+}
+
+impl DefinitionKind {
+    /// The keyword in front of the name, for records and enums.
+    pub fn keyword(self) -> Option<&'static str> {
+        match self {
+            Self::Struct => Some("struct"),
+            Self::Union => Some("union"),
+            Self::Enum => Some("enum"),
+            Self::Typedef | Self::Macro | Self::Enumerator => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Definition {
+    pub kind: DefinitionKind,
+    pub name: String,
+    /// The aliased type of a typedef, or the value of a macro or enumerator.
+    pub detail: String,
+    /// Members of a struct or union, or of the anonymous one a typedef names.
+    pub fields: Vec<Field>,
+    /// The C source of the definition.
+    pub source: String,
+    /// The header defining it, `None` for definitions in the script.
+    pub file: Option<PathBuf>,
+    /// Where the name is.
+    pub range: Range,
+}
+
+impl Definition {
+    /// The name as it's used as a type, e.g. `struct path`.
+    pub fn type_name(&self) -> String {
+        match self.kind.keyword() {
+            Some(keyword) => format!("{keyword} {}", self.name),
+            None => self.name.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    pub name: String,
+    /// The type as in `struct dentry *` or `char[16]`.
+    pub ty: String,
+    pub range: Range,
+}
+
+/// What a header or script defines, along with what it includes.
+#[derive(Debug, Default)]
+pub struct Header {
+    pub definitions: Vec<Definition>,
+    /// Included paths, and whether they are in angle brackets.
+    pub includes: Vec<(String, bool)>,
+}
+
+/// The definitions visible to a script, its own first and then those of the
+/// headers it includes in order.
+#[derive(Debug, Default)]
+pub struct Definitions {
+    headers: Vec<Arc<Header>>,
+}
+
+impl Definitions {
+    pub fn new(headers: Vec<Arc<Header>>) -> Self {
+        Self { headers }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Definition> {
+        self.headers.iter().flat_map(|x| &x.definitions)
+    }
+
+    pub fn find(&self, kind: DefinitionKind, name: &str) -> Option<&Definition> {
+        self.iter().find(|x| x.kind == kind && x.name == name)
+    }
+
+    /// A macro or enumerator, which can be used like a constant.
+    pub fn constant(&self, name: &str) -> Option<&Definition> {
+        self.iter().find(|x| {
+            matches!(x.kind, DefinitionKind::Macro | DefinitionKind::Enumerator) && x.name == name
+        })
+    }
+
+    /// The definition a type refers to, e.g. `struct path` for
+    /// `const struct path *`, looking through typedefs.
+    pub fn lookup(&self, ty: &str) -> Option<&Definition> {
+        let mut ty = ty.to_string();
+        let mut found = None;
+        // typedefs may refer to each other, but not forever
+        for _ in 0..8 {
+            let Some(definition) = named_type(&ty).and_then(|(kind, name)| self.find(kind, name))
+            else {
+                break;
+            };
+            found = Some(definition);
+            if definition.kind != DefinitionKind::Typedef || !definition.fields.is_empty() {
+                break;
+            }
+            ty = definition.detail.clone();
+        }
+        found
+    }
+
+    /// The struct or union a type refers to, if it has any fields.
+    pub fn record(&self, ty: &str) -> Option<&Definition> {
+        self.lookup(ty).filter(|x| !x.fields.is_empty())
+    }
+}
+
+/// The kind and name of what a type refers to, e.g. `(Struct, "path")` for
+/// `const struct path *`.
+pub fn named_type(ty: &str) -> Option<(DefinitionKind, &str)> {
+    let words = ty
+        .split(|c: char| c.is_whitespace() || c == '*')
+        .filter(|x| !x.is_empty() && !matches!(*x, "const" | "volatile"))
+        .collect::<Vec<_>>();
+    match words.as_slice() {
+        ["struct", name] => Some((DefinitionKind::Struct, name)),
+        ["union", name] => Some((DefinitionKind::Union, name)),
+        ["enum", name] => Some((DefinitionKind::Enum, name)),
+        [name] => Some((DefinitionKind::Typedef, name)),
+        _ => None,
+    }
+}
+
+/// A scanned header along with the modification time it was read at.
+type CachedHeader = (Option<SystemTime>, Arc<Header>);
+
+/// Headers scanned so far, reused for as long as they aren't modified.
+#[derive(Default)]
+pub struct HeaderCache {
+    headers: Mutex<HashMap<PathBuf, CachedHeader>>,
+}
+
+/// The headers a script includes, directly or not.
+#[derive(Debug, Default)]
+pub struct Resolved {
+    pub headers: Vec<Arc<Header>>,
+    /// The file each include of the script resolved to, in order.
+    pub files: Vec<Option<PathBuf>>,
+    /// Every header read along with its modification time.
+    pub versions: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl HeaderCache {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Resolves the includes of a script in `dir` and everything they include
+    /// in turn, for scripts running on `arch`.
+    pub fn resolve(
+        &self,
+        includes: &[(String, bool)],
+        dir: Option<&Path>,
+        include_paths: &[PathBuf],
+        arch: &str,
+    ) -> Resolved {
+        let include_paths = include_paths
+            .iter()
+            .cloned()
+            .chain(system_include_paths(arch))
+            .collect::<Vec<_>>();
+        let mut resolved = Resolved::default();
+        let mut seen = HashSet::new();
+        let mut pending = includes
+            .iter()
+            .map(|(path, system)| {
+                let file = find_header(path, *system, dir, &include_paths);
+                resolved.files.push(file.clone());
+                file
+            })
+            .collect::<Vec<_>>();
+        pending.reverse();
+        while let Some(file) = pending.pop() {
+            let Some(file) = file.filter(|x| seen.insert(x.clone())) else {
+                continue;
+            };
+            if resolved.versions.len() == MAX_HEADERS {
+                break;
+            }
+            let (modified, header) = self.read(&file);
+            resolved.versions.push((file.clone(), modified));
+            // included headers come right after the one including them
+            let dir = file.parent();
+            pending.extend(
+                header
+                    .includes
+                    .iter()
+                    .rev()
+                    .map(|(path, system)| find_header(path, *system, dir, &include_paths)),
+            );
+            resolved.headers.push(header);
+        }
+        resolved
+    }
+
+    fn read(&self, file: &Path) -> (Option<SystemTime>, Arc<Header>) {
+        let modified = modified(file);
+        if let Some((cached, header)) = self.headers.lock().unwrap().get(file)
+            && *cached == modified
+        {
+            return (modified, header.clone());
+        }
+        let text = std::fs::read_to_string(file).unwrap_or_default();
+        let mut header = scan(&text);
+        for definition in &mut header.definitions {
+            definition.file = Some(file.to_path_buf());
+        }
+        let header = Arc::new(header);
+        self.headers
+            .lock()
+            .unwrap()
+            .insert(file.to_path_buf(), (modified, header.clone()));
+        (modified, header)
+    }
+}
+
+pub fn modified(file: &Path) -> Option<SystemTime> {
+    std::fs::metadata(file).and_then(|x| x.modified()).ok()
+}
+
+/// The system include paths on `arch`, in the order they're looked up in.
+fn system_include_paths(arch: &str) -> impl Iterator<Item = PathBuf> {
+    SYSTEM_INCLUDE_PATHS
+        .iter()
+        .map(move |x| PathBuf::from(x.replace("{}", arch)))
+}
+
+/// Finds an included header. Quoted paths are looked up next to the file
+/// including them first.
+pub fn find_header(
+    include: &str,
+    system: bool,
+    dir: Option<&Path>,
+    include_paths: &[PathBuf],
+) -> Option<PathBuf> {
+    let local = dir.filter(|_| !system).map(Path::to_path_buf);
+    local
+        .into_iter()
+        .chain(include_paths.iter().cloned())
+        .map(|x| x.join(include))
+        .find(|x| x.is_file())
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Token<'a> {
+    text: &'a str,
+    offset: usize,
+}
+
+impl Token<'_> {
+    fn is_ident(&self) -> bool {
+        self.text
+            .starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+    }
+}
+
+/// Scans C source for struct, union, enum, typedef and macro definitions.
+/// This is no C parser: conditional compilation is ignored and macros aren't
+/// expanded, which is good enough for finding what types look like.
+pub fn scan(text: &str) -> Header {
+    let line_index = LineIndex::new(text);
+    let (code, directives) = preprocess(text);
+    let mut scanner = Scanner {
+        text,
+        line_index: &line_index,
+        definitions: Vec::new(),
+    };
+    let mut includes = Vec::new();
+    for (offset, line) in directives {
+        scanner.directive(offset, line, &mut includes);
+    }
+    let tokens = tokenize(&code);
+    let mut start = 0;
+    while start < tokens.len() {
+        let end = statement_end(&tokens, start);
+        scanner.statement(&tokens[start..end]);
+        start = end;
+    }
+    Header {
+        definitions: scanner.definitions,
+        includes,
+    }
+}
+
+/// Blanks out comments and preprocessor directives, keeping offsets intact,
+/// and returns the directives separately.
+fn preprocess(text: &str) -> (String, Vec<(usize, String)>) {
+    let mut code = text.as_bytes().to_vec();
+    let mut i = 0;
+    while i < code.len() {
+        match (code[i], code.get(i + 1)) {
+            (b'/', Some(b'/')) => {
+                while i < code.len() && code[i] != b'\n' {
+                    code[i] = b' ';
+                    i += 1;
+                }
+            }
+            (b'/', Some(b'*')) => {
+                let end = text[i + 2..].find("*/").map_or(code.len(), |x| i + x + 4);
+                for byte in &mut code[i..end] {
+                    if *byte != b'\n' {
+                        *byte = b' ';
+                    }
+                }
+                i = end;
+            }
+            (quote @ (b'"' | b'\''), _) => {
+                i += 1;
+                while i < code.len() && code[i] != quote && code[i] != b'\n' {
+                    i += if code[i] == b'\\' { 2 } else { 1 };
+                }
+                i += 1;
+            }
+            _ => i += 1,
+        }
+    }
+
+    let mut directives = Vec::new();
+    let mut start = 0;
+    while start < code.len() {
+        let mut end = code[start..]
+            .iter()
+            .position(|x| *x == b'\n')
+            .map_or(code.len(), |x| start + x);
+        let line = &code[start..end];
+        if line.trim_ascii_start().starts_with(b"#") {
+            // directives go on after a trailing backslash
+            while code[start..end].trim_ascii_end().ends_with(b"\\") && end < code.len() {
+                end = code[end + 1..]
+                    .iter()
+                    .position(|x| *x == b'\n')
+                    .map_or(code.len(), |x| end + 1 + x);
+            }
+            let directive = String::from_utf8_lossy(&code[start..end]).replace("\\\n", "  ");
+            directives.push((start, directive));
+            for byte in &mut code[start..end] {
+                if *byte != b'\n' {
+                    *byte = b' ';
+                }
+            }
+        }
+        start = end + 1;
+    }
+    (String::from_utf8_lossy(&code).into_owned(), directives)
+}
+
+fn tokenize(code: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut chars = code.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        let mut end = start + c.len_utf8();
+        if c.is_ascii_alphanumeric() || c == '_' {
+            while let Some((i, c)) = chars.next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '_')
+            {
+                end = i + c.len_utf8();
+            }
+        } else if c == '"' || c == '\'' {
+            let mut escaped = false;
+            for (i, next) in chars.by_ref() {
+                end = i + next.len_utf8();
+                if next == c && !escaped {
+                    break;
+                }
+                escaped = next == '\\' && !escaped;
+            }
+        }
+        tokens.push(Token {
+            text: &code[start..end],
+            offset: start,
+        });
+    }
+    tokens
+}
+
+/// The end of the top-level statement starting at `start`: after its `;`,
+/// or after the body of a function definition.
+fn statement_end(tokens: &[Token], start: usize) -> usize {
+    let mut depth = 0usize;
+    for i in start..tokens.len() {
+        match tokens[i].text {
+            "(" | "[" | "{" => depth += 1,
+            ")" | "]" => depth = depth.saturating_sub(1),
+            "}" => {
+                depth = depth.saturating_sub(1);
+                if depth == 0 && is_function_body(tokens, start, i) {
+                    return i + 1;
+                }
+            }
+            ";" if depth == 0 => return i + 1,
+            _ => {}
+        }
+    }
+    tokens.len()
+}
+
+/// Whether the braces closed at `close` follow a parameter list.
+fn is_function_body(tokens: &[Token], start: usize, close: usize) -> bool {
+    matching(tokens, start, close).is_some_and(|open| open > start && tokens[open - 1].text == ")")
+}
+
+/// The opening bracket matching the closing one at `close`.
+fn matching(tokens: &[Token], start: usize, close: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    for i in (start..=close).rev() {
+        match tokens[i].text {
+            ")" | "]" | "}" => depth += 1,
+            "(" | "[" | "{" => {
+                depth = depth.saturating_sub(1);
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The closing bracket matching the opening one at `open`.
+fn closing(tokens: &[Token], open: usize) -> usize {
+    let mut depth = 0usize;
+    for (i, token) in tokens.iter().enumerate().skip(open) {
+        match token.text {
+            "(" | "[" | "{" => depth += 1,
+            ")" | "]" | "}" => {
+                depth = depth.saturating_sub(1);
+                if depth == 0 {
+                    return i;
+                }
+            }
+            _ => {}
+        }
+    }
+    tokens.len()
+}
+
+/// Splits tokens at a separator outside of brackets.
+fn split<'t, 'a>(tokens: &'t [Token<'a>], separator: &str) -> Vec<&'t [Token<'a>]> {
+    let mut parts = Vec::new();
+    let (mut depth, mut start) = (0usize, 0);
+    for (i, token) in tokens.iter().enumerate() {
+        match token.text {
+            "(" | "[" | "{" => depth += 1,
+            ")" | "]" | "}" => depth = depth.saturating_sub(1),
+            text if text == separator && depth == 0 => {
+                parts.push(&tokens[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&tokens[start..]);
+    parts.retain(|x| !x.is_empty());
+    parts
+}
+
+/// Joins the tokens of a type, e.g. `struct dentry *` or `char[16]`.
+fn type_text(tokens: &[Token]) -> String {
+    let mut text = String::new();
+    for token in tokens {
+        let glued = matches!(token.text, "[" | "]" | "(" | ")" | ",")
+            || text.ends_with(['[', '(', '*'])
+            || text.is_empty();
+        if !glued || (token.text == "(" && !text.ends_with('(')) {
+            text.push(' ');
+        }
+        text.push_str(token.text);
+    }
+    text.trim().to_string()
+}
+
+/// Drops annotations and attributes from a declaration.
+fn strip_annotations<'a>(tokens: &[Token<'a>]) -> Vec<Token<'a>> {
+    let mut stripped = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let text = tokens[i].text;
+        if matches!(text, "__attribute__" | "__aligned" | "__attribute") {
+            i += 1;
+            if tokens.get(i).is_some_and(|x| x.text == "(") {
+                i = closing(tokens, i) + 1;
+            }
+            continue;
+        }
+        if !ANNOTATIONS.contains(&text) {
+            stripped.push(tokens[i]);
+        }
+        i += 1;
+    }
+    stripped
+}
+
+struct Scanner<'s> {
+    text: &'s str,
+    line_index: &'s LineIndex<'s>,
+    definitions: Vec<Definition>,
+}
+
+impl Scanner<'_> {
+    fn range(&self, token: &Token) -> Range {
+        Range::new(
+            self.line_index.position(token.offset),
+            self.line_index.position(token.offset + token.text.len()),
+        )
+    }
+
+    fn source(&self, tokens: &[Token]) -> String {
+        match (tokens.first(), tokens.last()) {
+            (Some(first), Some(last)) => {
+                self.text[first.offset..last.offset + last.text.len()].to_string()
+            }
+            _ => String::new(),
+        }
+    }
+
+    fn define(&mut self, kind: DefinitionKind, name: &Token, detail: String, source: String) {
+        self.definitions.push(Definition {
+            kind,
+            name: name.text.to_string(),
+            detail,
+            fields: Vec::new(),
+            source,
+            file: None,
+            range: self.range(name),
+        });
+    }
+
+    fn directive(&mut self, offset: usize, line: String, includes: &mut Vec<(String, bool)>) {
+        let tokens = tokenize(&line);
+        let rest = match tokens.as_slice() {
+            [hash, directive, rest @ ..] if hash.text == "#" => (
+                directive.text,
+                rest,
+                directive.offset + directive.text.len(),
+            ),
+            _ => return,
+        };
+        match rest {
+            ("include", _, end) => {
+                let path = line[end..].trim();
+                if let Some(path) = path.strip_prefix('<').and_then(|x| x.split_once('>')) {
+                    includes.push((path.0.to_string(), true));
+                } else if let Some(path) = path.strip_prefix('"').and_then(|x| x.split_once('"')) {
+                    includes.push((path.0.to_string(), false));
+                }
+            }
+            ("define", [name, ..], _) if name.is_ident() => {
+                let mut value_start = name.offset + name.text.len();
+                // function-like macros have their parameters right after the name
+                if line[value_start..].starts_with('(') {
+                    value_start += line[value_start..].find(')').map_or(0, |x| x + 1);
+                }
+                let name = Token {
+                    text: name.text,
+                    offset: offset + name.offset,
+                };
+                self.define(
+                    DefinitionKind::Macro,
+                    &name,
+                    line[value_start..].trim().to_string(),
+                    line.trim().to_string(),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    fn statement(&mut self, tokens: &[Token]) {
+        let tokens = strip_annotations(tokens);
+        match tokens.first().map(|x| x.text) {
+            Some("typedef") => self.typedef(&tokens),
+            Some("struct" | "union" | "enum") => {
+                self.specifier(&tokens, 0);
+            }
+            _ => {}
+        }
+    }
+
+    fn typedef(&mut self, tokens: &[Token]) {
+        let declaration = &tokens[1..tokens.len() - usize::from(is_semicolon(tokens.last()))];
+        let (ty, fields, rest) = self.specifier(declaration, 0);
+        for declarator in split(&declaration[rest..], ",") {
+            let Some((name, suffix)) = declarator_name(declarator) else {
+                continue;
+            };
+            let mut definition = Definition {
+                kind: DefinitionKind::Typedef,
+                name: name.text.to_string(),
+                detail: declarator_type(&ty, declarator, suffix),
+                fields: Vec::new(),
+                source: self.source(tokens),
+                file: None,
+                range: self.range(&name),
+            };
+            // `typedef struct { ... } name_t;` names an otherwise anonymous struct
+            if ty.ends_with('}') {
+                definition.fields = fields.clone();
+            }
+            self.definitions.push(definition);
+        }
+    }
+
+    /// Reads the type a declaration starts with, defining the records and
+    /// enums it contains. Returns the type, the fields of a record defined
+    /// there, and where the declarators start.
+    fn specifier(&mut self, tokens: &[Token], start: usize) -> (String, Vec<Field>, usize) {
+        let mut i = start;
+        while tokens
+            .get(i)
+            .is_some_and(|x| matches!(x.text, "const" | "volatile"))
+        {
+            i += 1;
+        }
+        let Some(keyword) = tokens.get(i) else {
+            return (String::new(), Vec::new(), i);
+        };
+        let kind = match keyword.text {
+            "struct" => DefinitionKind::Struct,
+            "union" => DefinitionKind::Union,
+            "enum" => DefinitionKind::Enum,
+            _ => {
+                let mut end = i;
+                while tokens.get(end).is_some_and(Token::is_ident) {
+                    end += 1;
+                }
+                // the last word is the name being declared, unless pointers
+                // or a function pointer follow
+                if end > i + 1
+                    && tokens
+                        .get(end)
+                        .is_none_or(|x| x.text != "*" && x.text != "(")
+                {
+                    end -= 1;
+                }
+                return (type_text(&tokens[start..end]), Vec::new(), end);
+            }
+        };
+        let name = tokens.get(i + 1).filter(|x| x.is_ident()).copied();
+        let open = i + 1 + usize::from(name.is_some());
+        if tokens.get(open).is_none_or(|x| x.text != "{") {
+            let ty = type_text(&tokens[start..open]);
+            return (ty, Vec::new(), open);
+        }
+        let close = closing(tokens, open);
+        let body = &tokens[open + 1..close.min(tokens.len())];
+        let source = self.source(&tokens[i..=close.min(tokens.len() - 1)]);
+        let fields = match kind {
+            DefinitionKind::Enum => {
+                self.enumerators(body, name.map(|x| x.text));
+                Vec::new()
+            }
+            _ => self.fields(body),
+        };
+        let ty = match name {
+            Some(name) => {
+                self.definitions.push(Definition {
+                    kind,
+                    name: name.text.to_string(),
+                    detail: String::new(),
+                    fields: fields.clone(),
+                    source,
+                    file: None,
+                    range: self.range(&name),
+                });
+                format!("{} {}", keyword.text, name.text)
+            }
+            None => format!("{} {{ ... }}", keyword.text),
+        };
+        (ty, fields, close + 1)
+    }
+
+    fn fields(&mut self, body: &[Token]) -> Vec<Field> {
+        let mut fields = Vec::new();
+        for member in split(body, ";") {
+            let member = strip_annotations(member);
+            let (ty, nested, rest) = self.specifier(&member, 0);
+            let declarators = split(&member[rest.min(member.len())..], ",");
+            // members of anonymous structs and unions are accessed directly
+            if declarators.is_empty() && ty.ends_with('}') {
+                fields.extend(nested);
+                continue;
+            }
+            for declarator in declarators {
+                if let Some((name, suffix)) = declarator_name(declarator) {
+                    fields.push(Field {
+                        name: name.text.to_string(),
+                        ty: declarator_type(&ty, declarator, suffix),
+                        range: self.range(&name),
+                    });
+                }
+            }
+        }
+        fields
+    }
+
+    fn enumerators(&mut self, body: &[Token], enum_name: Option<&str>) {
+        let mut next = Some(0i64);
+        for enumerator in split(body, ",") {
+            let Some(name) = enumerator.first().filter(|x| x.is_ident()) else {
+                continue;
+            };
+            let value = match enumerator.get(1) {
+                Some(x) if x.text == "=" => {
+                    let text = self.source(&enumerator[2..]);
+                    next = parse_integer(&text);
+                    text
+                }
+                _ => next.map(|x| x.to_string()).unwrap_or_default(),
+            };
+            next = next.map(|x| x + 1);
+            let source = match enum_name {
+                Some(enum_name) => format!("enum {enum_name} {{ {} }}", self.source(enumerator)),
+                None => self.source(enumerator),
+            };
+            self.define(DefinitionKind::Enumerator, name, value, source);
+        }
+    }
+}
+
+fn is_semicolon(token: Option<&Token>) -> bool {
+    token.is_some_and(|x| x.text == ";")
+}
+
+/// The name a declarator declares, along with where its array dimensions or
+/// parameters start. Function pointers are named within parentheses.
+fn declarator_name<'a>(declarator: &[Token<'a>]) -> Option<(Token<'a>, usize)> {
+    if let Some(open) = declarator.iter().position(|x| x.text == "(")
+        && declarator.get(open + 1).is_some_and(|x| x.text == "*")
+    {
+        let close = closing(declarator, open);
+        let name = declarator[open..close]
+            .iter()
+            .rev()
+            .find(|x| x.is_ident())?;
+        return Some((*name, close + 1));
+    }
+    let end = declarator
+        .iter()
+        .position(|x| matches!(x.text, "[" | ":" | "="))
+        .unwrap_or(declarator.len());
+    let name = declarator[..end].iter().rev().find(|x| x.is_ident())?;
+    Some((*name, end))
+}
+
+/// The type a declarator gives the name it declares, e.g. `char[16]` for
+/// `char comm[16]`.
+fn declarator_type(ty: &str, declarator: &[Token], suffix: usize) -> String {
+    let stars = declarator.iter().take_while(|x| x.text == "*").count();
+    let mut text = ty.to_string();
+    if stars > 0 {
+        text.push(' ');
+        text.push_str(&"*".repeat(stars));
+    }
+    if declarator.get(stars).is_some_and(|x| x.text == "(") {
+        // function pointers
+        let params = &declarator[suffix.min(declarator.len())..];
+        text.push_str(" (*)");
+        text.push_str(&type_text(params));
+        return text;
+    }
+    let dimensions = declarator[suffix.min(declarator.len())..]
+        .iter()
+        .take_while(|x| x.text != ":" && x.text != "=")
+        .map(|x| x.text)
+        .collect::<String>();
+    text.push_str(&dimensions);
+    text
+}
+
+fn parse_integer(text: &str) -> Option<i64> {
+    let text = text.trim_end_matches(['u', 'U', 'l', 'L']);
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(definition: &Definition) -> Vec<(&str, &str)> {
+        definition
+            .fields
+            .iter()
+            .map(|x| (x.name.as_str(), x.ty.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_include_order() {
+        assert_eq!(
+            system_include_paths("aarch64").collect::<Vec<_>>(),
+            [
+                Path::new("/usr/local/include"),
+                Path::new("/usr/include/aarch64-linux-gnu"),
+                Path::new("/usr/include"),
+            ]
+        );
+
+        let root = std::env::temp_dir().join(format!("btls-include-order-{}", std::process::id()));
+        let dirs = ["script", "first", "second"].map(|x| root.join(x));
+        for dir in &dirs {
+            std::fs::create_dir_all(dir.join("asm")).unwrap();
+            std::fs::write(dir.join("asm/types.h"), "").unwrap();
+        }
+        let [script, first, second] = &dirs;
+        let paths = [first.clone(), second.clone()];
+        let find = |system| find_header("asm/types.h", system, Some(script), &paths);
+        assert_eq!(find(false), Some(script.join("asm/types.h")));
+        assert_eq!(find(true), Some(first.join("asm/types.h")));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_scan() {
+        let header = scan(
+            r#"
+#include <linux/types.h>
+#include "local.h"
+#define PATH_MAX 4096 /* bytes */
+#define MIN(a, b) ((a) < (b) ? (a) : (b))
+
+struct dentry;
+
+/* a path */
+struct path {
+    struct vfsmount *mnt;
+    struct dentry *dentry; // the dentry
+} __randomize_layout;
+
+struct qstr {
+    union {
+        struct {
+            u32 hash;
+            u32 len;
+        };
+        u64 hash_len;
+    };
+    const unsigned char *name;
+    char inline_name[32], **names;
+    unsigned int flags : 4;
+    void (*release)(struct qstr *, int);
+};
+
+typedef struct {
+    int counter;
+} atomic_t;
+typedef unsigned int u32, *u32p;
+typedef struct path path_t;
+
+enum state { STATE_IDLE, STATE_BUSY = 4, STATE_DONE };
+
+static inline int helper(struct path *p) { return p->mnt != 0; }
+"#,
+        );
+        assert_eq!(
+            header.includes,
+            [
+                ("linux/types.h".to_string(), true),
+                ("local.h".to_string(), false)
+            ]
+        );
+        let definitions = Definitions::new(vec![Arc::new(header)]);
+
+        let path = definitions.find(DefinitionKind::Struct, "path").unwrap();
+        assert_eq!(
+            fields(path),
+            [("mnt", "struct vfsmount *"), ("dentry", "struct dentry *")]
+        );
+        assert_eq!(path.range.start.line, 9);
+        assert!(path.source.starts_with("struct path {"));
+        assert_eq!(
+            fields(definitions.find(DefinitionKind::Struct, "qstr").unwrap()),
+            [
+                ("hash", "u32"),
+                ("len", "u32"),
+                ("hash_len", "u64"),
+                ("name", "const unsigned char *"),
+                ("inline_name", "char[32]"),
+                ("names", "char **"),
+                ("flags", "unsigned int"),
+                ("release", "void (*)(struct qstr *, int)"),
+            ]
+        );
+
+        assert_eq!(
+            fields(definitions.record("atomic_t").unwrap()),
+            [("counter", "int")]
+        );
+        assert_eq!(definitions.record("const path_t *").unwrap().name, "path");
+        assert_eq!(definitions.lookup("u32p").unwrap().detail, "unsigned int *");
+        assert!(definitions.record("struct dentry *").is_none());
+
+        let constant = |name| definitions.constant(name).map(|x| x.detail.as_str());
+        assert_eq!(constant("PATH_MAX"), Some("4096"));
+        assert_eq!(constant("MIN"), Some("((a) < (b) ? (a) : (b))"));
+        assert_eq!(constant("STATE_IDLE"), Some("0"));
+        assert_eq!(constant("STATE_DONE"), Some("5"));
+        assert_eq!(constant("helper"), None);
+    }
+}
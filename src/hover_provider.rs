@@ -0,0 +1,193 @@
+use super::analyzer::options;
+use super::analyzer::params::{self, Param};
+use super::analyzer::types;
+use super::builtins::{BUILTINS, BuiltinSymbol};
+use super::definition_provider;
+use super::headers::{Definition, DefinitionKind};
+use super::parser::{Expr, Function, IdentKind, Identifier, Node, Preamble, Walk};
+use super::server::Context;
+use pest::Span;
+use tower_lsp::jsonrpc::{Error, ErrorCode, Result};
+use tower_lsp::lsp_types::{Hover, HoverContents, MarkupContent, MarkupKind, Position, Url};
+
+/// Lines of a definition shown at most, the rest of a large struct is left
+/// out.
+const MAX_SOURCE_LINES: usize = 30;
+
+pub async fn hover(context: &Context, uri: &Url, position: Position) -> Result<Option<Hover>> {
+    let analyzed = context
+        .analyzer
+        .analyze(context, uri)
+        .await
+        .map_err(|_| Error::new(ErrorCode::InternalError))?;
+    let line_index = &analyzed.document.line_index;
+    let Some(offset) = line_index.offset(position) else {
+        return Ok(None);
+    };
+    let contains = |span: Span| span.start() <= offset && offset <= span.end();
+    let definitions = &analyzed.definitions;
+
+    let mut found: Option<(Span, String)> = None;
+    for (include, file) in definition_provider::includes(&analyzed) {
+        if contains(include.path_span) {
+            let value = match file {
+                Some(file) => format!("`{}`", file.display()),
+                None => "Not found in the include paths".to_string(),
+            };
+            found = Some((include.path_span, value));
+        }
+    }
+
+    let functions = analyzed
+        .ast()
+        .preambles
+        .iter()
+        .filter_map(|x| match x {
+            Preamble::Function(function) => Some(function),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    for function in &functions {
+        if contains(function.name.span) {
+            found = Some((function.name.span, user_function(function)));
+        }
+    }
+
+    let inferred = types::infer(analyzed.ast(), definitions);
+    for node in Walk::new(analyzed.ast().as_node()) {
+        let Some(expr) = node.as_expr() else {
+            continue;
+        };
+        let value = match expr {
+            Expr::Field(field) if contains(field.field.span) => {
+                let base = inferred
+                    .fields
+                    .iter()
+                    .find(|(x, _)| std::ptr::eq(*x, field.as_ref()))
+                    .and_then(|(_, base)| base.as_deref());
+                let Some(record) = base.and_then(|x| definitions.record(x)) else {
+                    continue;
+                };
+                let Some(member) = record.fields.iter().find(|x| x.name == field.field.name) else {
+                    continue;
+                };
+                // `struct dentry *d_parent` rather than `struct dentry * d_parent`
+                let separator = if member.ty.ends_with('*') { "" } else { " " };
+                Some((
+                    field.field.span,
+                    format!(
+                        "```c\n{}{separator}{}\n```\nField of `{}`",
+                        member.ty,
+                        member.name,
+                        record.type_name()
+                    ),
+                ))
+            }
+            Expr::Cast(cast) if contains(cast.ty_span) => definitions
+                .lookup(cast.ty)
+                .map(|x| (cast.ty_span, definition(x))),
+            Expr::Identifier(ident) if ident.kind == IdentKind::Bare && contains(ident.span) => {
+                let keyword = BUILTINS
+                    .keywords
+                    .iter()
+                    .find(|x| x.name == ident.name)
+                    .or_else(|| BUILTINS.numbered(ident.name).map(|(x, _)| &x.symbol));
+                match keyword {
+                    Some(keyword) => Some((ident.span, builtin(keyword))),
+                    None => definitions
+                        .constant(ident.name)
+                        .map(|x| (ident.span, definition(x))),
+                }
+            }
+            Expr::Identifier(ident)
+                if ident.kind == IdentKind::Positional && contains(ident.sigil_span()) =>
+            {
+                Some((ident.sigil_span(), positional(ident, &analyzed.params)))
+            }
+            Expr::Call(call)
+                if call.func.name == "getopt"
+                    && call.args.first().is_some_and(|x| contains(x.span())) =>
+            {
+                let name = call.args[0].span();
+                options::table(analyzed.ast())
+                    .into_iter()
+                    .find(|x| x.span.as_str() == name.as_str())
+                    .map(|x| {
+                        (
+                            name,
+                            format!("```\n{}\n```\nRead with `getopt()`", x.describe()),
+                        )
+                    })
+            }
+            Expr::Call(call) if contains(call.func.span) => {
+                match functions.iter().find(|x| x.name.name == call.func.name) {
+                    Some(function) => Some((call.func.span, user_function(function))),
+                    None => BUILTINS
+                        .functions
+                        .iter()
+                        .find(|x| x.name == call.func.name)
+                        .map(|x| (call.func.span, builtin(x))),
+                }
+            }
+            _ => None,
+        };
+        // the innermost node wins
+        if value.is_some() {
+            found = value;
+        }
+    }
+
+    Ok(found.map(|(span, value)| Hover {
+        contents: HoverContents::Markup(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value,
+        }),
+        range: Some(line_index.range(span)),
+    }))
+}
+
+fn positional(ident: &Identifier, params: &[Param]) -> String {
+    let Some(number) = params::number(ident) else {
+        return "```\n$#: int64\n```\nThe number of positional parameters".to_string();
+    };
+    match params.iter().find(|x| x.number == number) {
+        Some(param) => {
+            let ty = param.ty.map_or("?".to_string(), |x| x.to_string());
+            format!("```\n${number}: {ty}\n```\n{}", param.description)
+                .trim_end()
+                .to_string()
+        }
+        None => format!("```\n${number}\n```\nPositional parameter {number}, not declared"),
+    }
+}
+
+fn user_function(function: &Function) -> String {
+    format!("```\n{}\n```\nDefined in this script", function.signature())
+}
+
+fn builtin(symbol: &BuiltinSymbol) -> String {
+    format!(
+        "```\n{}\n```\n{}",
+        symbol.detail,
+        symbol.full_documentation()
+    )
+}
+
+/// The source of a definition, along with the header it's from.
+fn definition(definition: &Definition) -> String {
+    let source = match definition.kind {
+        DefinitionKind::Enumerator => format!("{} = {}", definition.name, definition.detail),
+        _ => {
+            let lines = definition.source.lines().collect::<Vec<_>>();
+            if lines.len() > MAX_SOURCE_LINES {
+                format!("{}\n    ...", lines[..MAX_SOURCE_LINES].join("\n"))
+            } else {
+                definition.source.clone()
+            }
+        }
+    };
+    match &definition.file {
+        Some(file) => format!("```c\n{source}\n```\nFrom `{}`", file.display()),
+        None => format!("```c\n{source}\n```"),
+    }
+}
@@ -0,0 +1,96 @@
+use super::analyzer::types;
+use super::builtins::BUILTINS;
+use super::parser::{Expr, Identifier, Node, Walk};
+use super::server::Context;
+use tower_lsp::jsonrpc::{Error, ErrorCode, Result};
+use tower_lsp::lsp_types::{InlayHint, InlayHintKind, InlayHintLabel, Range, Url};
+
+pub async fn inlay_hints(
+    context: &Context,
+    uri: &Url,
+    range: Range,
+) -> Result<Option<Vec<InlayHint>>> {
+    let analyzed = context
+        .analyzer
+        .analyze(context, uri)
+        .await
+        .map_err(|_| Error::new(ErrorCode::InternalError))?;
+    let config = context.config(uri).await;
+    let line_index = &analyzed.document.line_index;
+    let text = analyzed.document.data.as_str();
+    let start = line_index.offset(range.start).unwrap_or(0);
+    let end = line_index.offset(range.end).unwrap_or(text.len());
+
+    // offset, label and kind of each hint
+    let mut hints = Vec::new();
+    let types = types::infer(analyzed.ast(), &analyzed.definitions);
+    if config.inlay_hints.variable_types {
+        for (ident, ty) in &types.variables {
+            hints.push((ident.span.end(), format!(": {ty}"), InlayHintKind::TYPE));
+        }
+    }
+    if config.inlay_hints.map_types {
+        for map in &types.maps {
+            if let Some(ty) = map.describe() {
+                hints.push((
+                    lvalue_end(text, map.ident),
+                    format!(": {ty}"),
+                    InlayHintKind::TYPE,
+                ));
+            }
+        }
+    }
+    if config.inlay_hints.parameter_names {
+        let calls = Walk::new(analyzed.ast().as_node()).filter_map(|x| match x.as_expr()? {
+            Expr::Call(call) => Some(call),
+            _ => None,
+        });
+        for call in calls {
+            let Some(func) = BUILTINS.functions.iter().find(|x| x.name == call.func.name) else {
+                continue;
+            };
+            // optional parameters make it ambiguous which argument is which
+            let parameters = func.parameters();
+            if parameters.len() < 2 || parameters.len() != call.args.len() {
+                continue;
+            }
+            for (arg, name) in call.args.iter().zip(parameters) {
+                // a variable usually says what it is already
+                if !matches!(arg, Expr::Identifier(_)) {
+                    hints.push((
+                        arg.span().start(),
+                        format!("{name}:"),
+                        InlayHintKind::PARAMETER,
+                    ));
+                }
+            }
+        }
+    }
+
+    hints.retain(|(offset, ..)| (start..=end).contains(offset));
+    hints.sort_by_key(|(offset, ..)| *offset);
+    Ok(Some(
+        hints
+            .into_iter()
+            .map(|(offset, label, kind)| InlayHint {
+                position: line_index.position(offset),
+                label: InlayHintLabel::String(label),
+                padding_right: Some(kind == InlayHintKind::PARAMETER),
+                kind: Some(kind),
+                text_edits: None,
+                tooltip: None,
+                padding_left: None,
+                data: None,
+            })
+            .collect(),
+    ))
+}
+
+/// Where the written variable ends, after the keys of a map.
+fn lvalue_end(text: &str, ident: &Identifier) -> usize {
+    let Some(last) = ident.keys.last() else {
+        return ident.span.end();
+    };
+    let end = last.span().end();
+    text[end..].find(']').map_or(end, |x| end + x + 1)
+}
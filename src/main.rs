@@ -1,15 +1,29 @@
 mod analyzer;
+mod btf;
 mod builtins;
+mod check;
 mod client;
+mod code_action_provider;
 mod common;
 mod completion_provider;
 mod config;
+mod definition_provider;
 mod diagnostic_provider;
+mod folding_range_provider;
+mod headers;
+mod hover_provider;
+mod inlay_hint_provider;
 mod parser;
+mod selection_range_provider;
 mod server;
 mod storage;
+mod workspace;
 
 #[tokio::main]
 async fn main() {
-    server::run().await;
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.first().map(String::as_str) {
+        Some("check") => std::process::exit(check::run(&args[1..])),
+        _ => server::run().await,
+    }
 }
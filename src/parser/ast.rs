@@ -7,9 +7,10 @@ use pest::{
 };
 
 use super::{
-    AssignOp, Assignment, BinaryExpr, Block, Call, ErrorPreamble, ErrorStatement, Expr, For,
-    IdentKind, Identifier, If, IntegerLiteral, Loop, Lvalue, Node, Preamble, Probe, Program,
-    Statement, StringLiteral, UnaryExpr, UnknownPreamble, UnknownStatement, UnmatchedBrace, While,
+    AssignOp, Assignment, BinaryExpr, Block, CDefinition, Call, Cast, ErrorPreamble,
+    ErrorStatement, Expr, FieldAccess, For, Function, FunctionParam, IdentKind, Identifier, If,
+    Include, IntegerLiteral, Loop, Lvalue, Node, Preamble, Probe, Program, Return, Statement,
+    StringLiteral, UnaryExpr, UnknownPreamble, UnknownStatement, UnmatchedBrace, Unroll, While,
 };
 
 #[derive(pest_derive::Parser)]
@@ -19,7 +20,6 @@ struct BPFTraceParser;
 fn convert_int(pair: Pair<Rule>) -> IntegerLiteral {
     assert!(matches!(pair.as_rule(), Rule::number));
     IntegerLiteral {
-        value: pair.as_str().parse().unwrap(),
         span: pair.as_span(),
     }
 }
@@ -39,23 +39,47 @@ fn convert_ident(pair: Pair<Rule>) -> Identifier {
         name: pair.as_str(),
         span: pair.as_span(),
         kind: IdentKind::Bare,
+        keys: Vec::new(),
     }
 }
 
 fn convert_var(pair: Pair<Rule>) -> Identifier {
     assert!(matches!(pair.as_rule(), Rule::variable));
     let var_str = pair.as_str();
-    let kind = if var_str.starts_with('$') {
-        IdentKind::Scratch
-    } else {
-        IdentKind::Map
+    let kind = match var_str.strip_prefix('$') {
+        // `$1` and the parameter count `$#`
+        Some(name) if name == "#" || name.bytes().all(|x| x.is_ascii_digit()) => {
+            IdentKind::Positional
+        }
+        Some(_) => IdentKind::Scratch,
+        None => IdentKind::Map,
+    };
+    let span = pair.as_span();
+    let mut ident = match pair.into_inner().at_most_one().unwrap() {
+        Some(pair) if pair.as_rule() == Rule::param_count => Identifier {
+            name: pair.as_str(),
+            span: pair.as_span(),
+            kind,
+            keys: Vec::new(),
+        },
+        Some(pair) => convert_ident(pair),
+        // the unnamed map `@`
+        None => Identifier {
+            name: "",
+            span,
+            kind,
+            keys: Vec::new(),
+        },
     };
-    let pair = pair.into_inner().exactly_one().unwrap();
-    let mut ident = convert_ident(pair);
     ident.kind = kind;
     ident
 }
 
+fn convert_map_key(pair: Pair<Rule>) -> Vec<Expr> {
+    assert!(matches!(pair.as_rule(), Rule::map_key));
+    convert_expr_list(pair.into_inner().exactly_one().unwrap())
+}
+
 fn convert_var_expr(pair: Pair<Rule>) -> Expr {
     assert!(matches!(pair.as_rule(), Rule::var_expr));
 
@@ -63,7 +87,8 @@ fn convert_var_expr(pair: Pair<Rule>) -> Expr {
 
     let parser = PrattParser::new()
         .op(Op::prefix(Rule::dec) | Op::prefix(Rule::inc))
-        .op(Op::postfix(Rule::dec) | Op::postfix(Rule::inc));
+        .op(Op::postfix(Rule::dec) | Op::postfix(Rule::inc))
+        .op(Op::postfix(Rule::map_key));
 
     parser
         .map_primary(|p| Expr::Identifier(Box::new(convert_var(p))))
@@ -80,6 +105,13 @@ fn convert_var_expr(pair: Pair<Rule>) -> Expr {
             }))
         })
         .map_postfix(|lhs, op| {
+            let lhs = match (op.as_rule(), lhs) {
+                (Rule::map_key, Expr::Identifier(mut ident)) => {
+                    ident.keys = convert_map_key(op);
+                    return Expr::Identifier(ident);
+                }
+                (_, lhs) => lhs,
+            };
             let span = Span::new(
                 lhs.span().get_input(),
                 lhs.span().start(),
@@ -106,21 +138,13 @@ fn convert_assign_op(pair: Pair<Rule>) -> AssignOp {
 
 fn convert_expr_list(pair: Pair<Rule>) -> Vec<Expr> {
     assert!(matches!(pair.as_rule(), Rule::expr_list));
-    let mut pairs = pair.into_inner();
-    let Some(first) = pairs.next() else {
-        return Vec::new();
-    };
-
-    let mut exprs = vec![convert_expr(first)];
-    pairs.tuples().for_each(|(_, expr)| {
-        exprs.push(convert_expr(expr));
-    });
-
-    exprs
+    // the separating commas are literals, so only the expressions are left
+    pair.into_inner().map(convert_expr).collect()
 }
 
 fn convert_primary_expr(pair: Pair<Rule>) -> Expr {
     assert!(matches!(pair.as_rule(), Rule::primary));
+    let span = pair.as_span();
     let pair = pair.into_inner().exactly_one().unwrap();
     match pair.as_rule() {
         Rule::identifier => Expr::Identifier(Box::new(convert_ident(pair))),
@@ -128,6 +152,19 @@ fn convert_primary_expr(pair: Pair<Rule>) -> Expr {
         Rule::string => Expr::String(Box::new(convert_str(pair))),
         Rule::call => Expr::Call(Box::new(convert_call(pair))),
         Rule::var_expr => convert_var_expr(pair),
+        Rule::expr => {
+            // operations in parentheses span the parentheses as well, so
+            // that operations containing them cover them
+            let mut expr = convert_expr(pair);
+            match &mut expr {
+                Expr::BinaryExpr(bin) => bin.span = span,
+                Expr::UnaryExpr(unary) => unary.span = span,
+                Expr::Field(field) => field.span = span,
+                Expr::Cast(cast) => cast.span = span,
+                _ => {}
+            }
+            expr
+        }
         _ => unreachable!(),
     }
 }
@@ -137,7 +174,10 @@ fn convert_expr(pair: Pair<Rule>) -> Expr {
     let pairs = pair.into_inner();
 
     let parser = PrattParser::new()
-        .op(Op::prefix(Rule::not) | Op::prefix(Rule::neg) | Op::prefix(Rule::pos))
+        .op(Op::prefix(Rule::not)
+            | Op::prefix(Rule::neg)
+            | Op::prefix(Rule::pos)
+            | Op::prefix(Rule::cast))
         .op(Op::infix(Rule::add, Assoc::Left)
             | Op::infix(Rule::sub, Assoc::Left)
             | Op::infix(Rule::mul, Assoc::Left)
@@ -148,7 +188,8 @@ fn convert_expr(pair: Pair<Rule>) -> Expr {
             | Op::infix(Rule::lt, Assoc::Left)
             | Op::infix(Rule::eq, Assoc::Left)
             | Op::infix(Rule::ne, Assoc::Left))
-        .op(Op::infix(Rule::and, Assoc::Left) | Op::infix(Rule::or, Assoc::Left));
+        .op(Op::infix(Rule::and, Assoc::Left) | Op::infix(Rule::or, Assoc::Left))
+        .op(Op::postfix(Rule::field));
 
     parser
         .map_primary(|p| convert_primary_expr(p))
@@ -159,11 +200,34 @@ fn convert_expr(pair: Pair<Rule>) -> Expr {
                 rhs.span().end(),
             )
             .unwrap();
+            if op.as_rule() == Rule::cast {
+                let ty = op.into_inner().exactly_one().unwrap();
+                return Expr::Cast(Box::new(Cast {
+                    ty: ty.as_str(),
+                    ty_span: ty.as_span(),
+                    expr: Box::new(rhs),
+                    span,
+                }));
+            }
             Expr::UnaryExpr(Box::new(UnaryExpr {
                 expr: Box::new(rhs),
                 span,
             }))
         })
+        .map_postfix(|lhs, op| {
+            let span = Span::new(
+                lhs.span().get_input(),
+                lhs.span().start(),
+                op.as_span().end(),
+            )
+            .unwrap();
+            let field = convert_ident(op.into_inner().exactly_one().unwrap());
+            Expr::Field(Box::new(FieldAccess {
+                expr: Box::new(lhs),
+                field,
+                span,
+            }))
+        })
         .map_infix(|lhs, _op, rhs| {
             let span =
                 Span::new(lhs.span().get_input(), lhs.span().start(), rhs.span().end()).unwrap();
@@ -176,25 +240,25 @@ fn convert_expr(pair: Pair<Rule>) -> Expr {
         .parse(pairs)
 }
 
-fn convert_lvalue(pair: Pair<Rule>) -> Lvalue {
-    assert!(matches!(pair.as_rule(), Rule::identifier));
-    let pair = pair.into_inner().exactly_one().unwrap();
-    match pair.as_rule() {
-        Rule::identifier => Lvalue::Identifier(Box::new(convert_ident(pair))),
-        _ => unreachable!(),
-    }
-}
-
 fn convert_assignment(pair: Pair<Rule>) -> Assignment {
     assert!(matches!(pair.as_rule(), Rule::assignment));
     let span = pair.as_span();
-    let (lvalue, op, rvalue) = pair.into_inner().collect_tuple().unwrap();
-    // let lvalue = convert_lvalue(lvalue);
-    let lvalue = convert_var(lvalue);
-    let _op = convert_assign_op(op);
-    let rvalue = convert_expr(rvalue);
+    let mut pairs = pair.into_inner().peekable();
+    let declaration = pairs
+        .next_if(|x| x.as_rule() == Rule::let_kw)
+        .map(|x| x.as_span());
+    let mut lvalue = convert_var(pairs.next().unwrap());
+    let mut op = pairs.next().unwrap();
+    if op.as_rule() == Rule::map_key {
+        lvalue.keys = convert_map_key(op);
+        op = pairs.next().unwrap();
+    }
+    let op = convert_assign_op(op);
+    let rvalue = convert_expr(pairs.next().unwrap());
     Assignment {
+        declaration,
         lvalue: Lvalue::Identifier(Box::new(lvalue)),
+        op,
         rvalue: Box::new(rvalue),
         span,
     }
@@ -256,6 +320,21 @@ fn convert_for(pair: Pair<Rule>) -> Loop {
     }))
 }
 
+fn convert_unroll(pair: Pair<Rule>) -> Loop {
+    assert!(matches!(pair.as_rule(), Rule::unroll));
+    let span = pair.as_span();
+    let mut pairs = pair.into_inner();
+
+    let count = convert_expr(pairs.next().unwrap());
+    let block = convert_block(pairs.next().unwrap());
+
+    Loop::Unroll(Box::new(Unroll {
+        count: Box::new(count),
+        block,
+        span,
+    }))
+}
+
 fn convert_statement(pair: Pair<Rule>) -> Statement {
     assert!(matches!(pair.as_rule(), Rule::statement));
     let pair = pair.into_inner().exactly_one().unwrap();
@@ -264,7 +343,16 @@ fn convert_statement(pair: Pair<Rule>) -> Statement {
         Rule::r#if => Statement::IfCond(Box::new(convert_if(pair))),
         Rule::r#while => Statement::Loop(Box::new(convert_while(pair))),
         Rule::r#for => Statement::Loop(Box::new(convert_for(pair))),
+        Rule::unroll => Statement::Loop(Box::new(convert_unroll(pair))),
         Rule::expr => Statement::Expr(Box::new(convert_expr(pair))),
+        Rule::r#return => Statement::Return(Box::new(Return {
+            value: pair
+                .clone()
+                .into_inner()
+                .nth(1)
+                .map(|x| Box::new(convert_expr(x))),
+            span: pair.as_span(),
+        })),
         _ => unreachable!(),
     }
 }
@@ -288,19 +376,9 @@ fn convert_block(pair: Pair<Rule>) -> Block {
     Block { statements, span }
 }
 
-fn convert_attach_points(pair: Pair<Rule>) -> Vec<&str> {
+fn convert_attach_points(pair: Pair<'_, Rule>) -> Vec<&str> {
     assert!(matches!(pair.as_rule(), Rule::attach_point_list));
-    let mut pairs = pair.into_inner();
-    let Some(first) = pairs.next() else {
-        return Vec::new();
-    };
-
-    let mut attach_points = vec![first.as_str()];
-    pairs.tuples().for_each(|(_, ap)| {
-        attach_points.push(ap.as_str());
-    });
-
-    attach_points
+    pair.into_inner().map(|ap| ap.as_str()).collect()
 }
 
 fn convert_probe(pair: Pair<Rule>) -> Probe {
@@ -330,10 +408,65 @@ fn convert_probe(pair: Pair<Rule>) -> Probe {
     }
 }
 
+fn convert_include(pair: Pair<Rule>) -> Include {
+    assert!(matches!(pair.as_rule(), Rule::include));
+    let span = pair.as_span();
+    let path = pair.into_inner().exactly_one().unwrap();
+    let system = span.as_str()[..path.as_span().start() - span.start()].ends_with('<');
+    Include {
+        path: path.as_str(),
+        path_span: path.as_span(),
+        system,
+        span,
+    }
+}
+
+fn convert_function(pair: Pair<Rule>) -> Function {
+    assert!(matches!(pair.as_rule(), Rule::function));
+    let span = pair.as_span();
+    let mut pairs = pair.into_inner();
+
+    let name = convert_ident(pairs.next().unwrap());
+    let params = pairs
+        .next()
+        .unwrap()
+        .into_inner()
+        .map(|param| {
+            let span = param.as_span();
+            let mut pairs = param.into_inner();
+            FunctionParam {
+                ident: convert_var(pairs.next().unwrap()),
+                ty: pairs.next().unwrap().as_str(),
+                span,
+            }
+        })
+        .collect();
+    let next = pairs.next().unwrap();
+    let (return_type, next) = match next.as_rule() {
+        Rule::fn_type => (Some(next.as_str()), pairs.next().unwrap()),
+        _ => (None, next),
+    };
+    let block = convert_block(next);
+
+    Function {
+        name,
+        params,
+        return_type,
+        block,
+        span,
+    }
+}
+
 fn convert_preamble(pair: Pair<Rule>) -> Preamble {
     assert!(matches!(pair.as_rule(), Rule::preamble));
     let pair = pair.into_inner().exactly_one().unwrap();
     match pair.as_rule() {
+        Rule::include => Preamble::Include(convert_include(pair)),
+        Rule::define | Rule::c_definition => Preamble::Definition(CDefinition {
+            text: pair.as_str(),
+            span: pair.as_span(),
+        }),
+        Rule::function => Preamble::Function(convert_function(pair)),
         Rule::probe => Preamble::Probe(convert_probe(pair)),
         _ => unreachable!(),
     }
@@ -363,7 +496,7 @@ fn convert_prog(pair: Pair<Rule>) -> Program {
     Program { preambles, span }
 }
 
-pub fn parse(input: &str) -> Result<Program> {
+pub fn parse(input: &str) -> Result<Program<'_>> {
     let pair = BPFTraceParser::parse(Rule::program, input)?
         .exactly_one()
         .map_err(|_| anyhow::anyhow!("failed to consume"))?;
@@ -1,6 +1,7 @@
 pub mod ast;
 mod tests;
 
+use crate::config::Version;
 use pest::Span;
 use std::iter::FilterMap;
 
@@ -21,6 +22,10 @@ pub trait Node<'a> {
         None
     }
 
+    fn as_identifier(&self) -> Option<&Identifier<'a>> {
+        None
+    }
+
     fn errors<'b>(&'b self) -> FilterWalk<'a, 'b, ErrorRef<'a, 'b>> {
         FilterWalk::new(self.as_node(), |node| node.as_error())
     }
@@ -46,12 +51,14 @@ impl<'a, 'b> Iterator for Walk<'a, 'b> {
     }
 }
 
+type NodeFilter<'a, 'b, T> = fn(&'b dyn Node<'a>) -> Option<T>;
+
 pub struct FilterWalk<'a, 'b, T> {
-    inner: FilterMap<Walk<'a, 'b>, fn(&'b dyn Node<'a>) -> Option<T>>,
+    inner: FilterMap<Walk<'a, 'b>, NodeFilter<'a, 'b, T>>,
 }
 
 impl<'a, 'b, T> FilterWalk<'a, 'b, T> {
-    pub fn new(node: &'b dyn Node<'a>, filter: fn(&'b dyn Node<'a>) -> Option<T>) -> Self {
+    pub fn new(node: &'b dyn Node<'a>, filter: NodeFilter<'a, 'b, T>) -> Self {
         FilterWalk {
             inner: Walk::new(node).filter_map(filter),
         }
@@ -73,6 +80,7 @@ pub struct UndefinedFunc<'a> {
 }
 
 impl<'a> UndefinedFunc<'a> {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(text: &'a str, span: Span<'a>) -> Statement<'a> {
         Statement::Error(Box::new(ErrorStatement::UndefinedFunc(Box::new(Self {
             text,
@@ -103,13 +111,16 @@ impl<'a> Node<'a> for UndefinedFunc<'a> {
 pub struct UndefinedIdent<'a> {
     pub text: &'a str,
     pub span: Span<'a>,
+    pub kind: IdentKind,
 }
 
 impl<'a> UndefinedIdent<'a> {
-    pub fn new(text: &'a str, span: Span<'a>) -> Statement<'a> {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(ident: &Identifier<'a>) -> Statement<'a> {
         Statement::Error(Box::new(ErrorStatement::UndefinedIdent(Box::new(Self {
-            text,
-            span,
+            text: ident.name,
+            span: ident.span,
+            kind: ident.kind,
         }))))
     }
 
@@ -158,11 +169,545 @@ impl<'a> Node<'a> for UnknownStatement<'a> {
     }
 }
 
+#[derive(Debug)]
+pub struct UnusedVariable<'a> {
+    pub text: &'a str,
+    pub span: Span<'a>,
+}
+
+impl<'a> UnusedVariable<'a> {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(text: &'a str, span: Span<'a>) -> Statement<'a> {
+        Statement::Error(Box::new(ErrorStatement::UnusedVariable(Box::new(Self {
+            text,
+            span,
+        }))))
+    }
+
+    pub fn diagnosis(&self) -> String {
+        format!("Scratch variable \"${}\" is never read", self.text.trim())
+    }
+}
+
+impl<'a> Node<'a> for UnusedVariable<'a> {
+    fn as_node(&self) -> &dyn Node<'a> {
+        self
+    }
+
+    fn children(&self) -> Vec<&dyn Node<'a>> {
+        Vec::new()
+    }
+
+    fn span(&self) -> Span<'a> {
+        self.span
+    }
+}
+
+#[derive(Debug)]
+pub struct WriteOnlyMap<'a> {
+    pub text: &'a str,
+    pub span: Span<'a>,
+}
+
+impl<'a> WriteOnlyMap<'a> {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(text: &'a str, span: Span<'a>) -> Statement<'a> {
+        Statement::Error(Box::new(ErrorStatement::WriteOnlyMap(Box::new(Self {
+            text,
+            span,
+        }))))
+    }
+
+    pub fn diagnosis(&self) -> String {
+        format!("Map \"@{}\" is written but never read", self.text.trim())
+    }
+}
+
+impl<'a> Node<'a> for WriteOnlyMap<'a> {
+    fn as_node(&self) -> &dyn Node<'a> {
+        self
+    }
+
+    fn children(&self) -> Vec<&dyn Node<'a>> {
+        Vec::new()
+    }
+
+    fn span(&self) -> Span<'a> {
+        self.span
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MapReadKind {
+    /// Read in `BEGIN`, which runs before any probe writing the map.
+    Begin,
+    /// Read without a filter or condition checking that the key was written.
+    Unguarded,
+    /// Read in a return probe, but no matching entry probe writes the key.
+    UnmatchedEntry,
+}
+
+#[derive(Debug)]
+pub struct MapReadBeforeWrite<'a> {
+    pub text: &'a str,
+    pub key: Option<&'a str>,
+    pub span: Span<'a>,
+    pub kind: MapReadKind,
+}
+
+impl<'a> MapReadBeforeWrite<'a> {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(ident: &Identifier<'a>, kind: MapReadKind) -> Statement<'a> {
+        Statement::Error(Box::new(ErrorStatement::MapReadBeforeWrite(Box::new(
+            Self {
+                text: ident.name,
+                key: ident.key_text(),
+                span: ident.span,
+                kind,
+            },
+        ))))
+    }
+
+    pub fn diagnosis(&self) -> String {
+        let map = match self.key {
+            Some(key) => format!("@{}[{}]", self.text, key),
+            None => format!("@{}", self.text),
+        };
+        match self.kind {
+            MapReadKind::Begin => {
+                format!("Map \"{map}\" is read in BEGIN, before any probe writing it runs")
+            }
+            MapReadKind::Unguarded => format!(
+                "Map \"{map}\" may be read before it is written, consider a filter like /{map}/"
+            ),
+            MapReadKind::UnmatchedEntry => {
+                format!("No entry probe matching this return probe writes \"{map}\"")
+            }
+        }
+    }
+}
+
+impl<'a> Node<'a> for MapReadBeforeWrite<'a> {
+    fn as_node(&self) -> &dyn Node<'a> {
+        self
+    }
+
+    fn children(&self) -> Vec<&dyn Node<'a>> {
+        Vec::new()
+    }
+
+    fn span(&self) -> Span<'a> {
+        self.span
+    }
+}
+
+#[derive(Debug)]
+pub struct MapLeak<'a> {
+    pub text: &'a str,
+    pub key: &'a str,
+    pub span: Span<'a>,
+    /// Block of the return probe matching the probe writing the map, where
+    /// the quick-fix inserts the `delete`.
+    pub return_block: Option<Span<'a>>,
+}
+
+impl<'a> MapLeak<'a> {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(ident: &Identifier<'a>, return_block: Option<Span<'a>>) -> Statement<'a> {
+        Statement::Error(Box::new(ErrorStatement::MapLeak(Box::new(Self {
+            text: ident.name,
+            key: ident.key_text().unwrap_or_default(),
+            span: ident.span,
+            return_block,
+        }))))
+    }
+
+    pub fn diagnosis(&self) -> String {
+        format!(
+            "Map \"@{}\" is keyed by {} but never deleted, it grows until max_map_keys is hit",
+            self.text, self.key
+        )
+    }
+
+    /// The statement deleting the key written to the map.
+    pub fn delete_statement(&self) -> String {
+        format!("delete(@{}[{}]);", self.text, self.key)
+    }
+}
+
+impl<'a> Node<'a> for MapLeak<'a> {
+    fn as_node(&self) -> &dyn Node<'a> {
+        self
+    }
+
+    fn children(&self) -> Vec<&dyn Node<'a>> {
+        Vec::new()
+    }
+
+    fn span(&self) -> Span<'a> {
+        self.span
+    }
+}
+
+#[derive(Debug)]
+pub struct UnresolvedInclude<'a> {
+    pub text: &'a str,
+    pub span: Span<'a>,
+}
+
+impl<'a> UnresolvedInclude<'a> {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(text: &'a str, span: Span<'a>) -> Statement<'a> {
+        Statement::Error(Box::new(ErrorStatement::UnresolvedInclude(Box::new(
+            Self { text, span },
+        ))))
+    }
+
+    pub fn diagnosis(&self) -> String {
+        format!(
+            "Cannot find header \"{}\" in the include paths",
+            self.text.trim()
+        )
+    }
+}
+
+impl<'a> Node<'a> for UnresolvedInclude<'a> {
+    fn as_node(&self) -> &dyn Node<'a> {
+        self
+    }
+
+    fn children(&self) -> Vec<&dyn Node<'a>> {
+        Vec::new()
+    }
+
+    fn span(&self) -> Span<'a> {
+        self.span
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownField<'a> {
+    pub text: &'a str,
+    pub span: Span<'a>,
+    /// The record the field was looked up in, e.g. `struct path`.
+    pub record: String,
+}
+
+impl<'a> UnknownField<'a> {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(field: &Identifier<'a>, record: String) -> Statement<'a> {
+        Statement::Error(Box::new(ErrorStatement::UnknownField(Box::new(Self {
+            text: field.name,
+            span: field.span,
+            record,
+        }))))
+    }
+
+    pub fn diagnosis(&self) -> String {
+        format!("{} has no field \"{}\"", self.record, self.text)
+    }
+}
+
+impl<'a> Node<'a> for UnknownField<'a> {
+    fn as_node(&self) -> &dyn Node<'a> {
+        self
+    }
+
+    fn children(&self) -> Vec<&dyn Node<'a>> {
+        Vec::new()
+    }
+
+    fn span(&self) -> Span<'a> {
+        self.span
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PositionalParamKind {
+    /// Used while a parameter before it isn't, e.g. `$3` without `$2`.
+    Gap(usize),
+    /// Used beyond the parameters the script declares.
+    Undeclared,
+    /// Declared as a string but used as an integer, without `str()`.
+    StringAsInteger,
+    /// Declared as an integer but used as a string.
+    IntegerAsString,
+    /// Used both as an integer and as a string.
+    Mixed,
+    /// Assigned to, which bpftrace doesn't allow.
+    Assigned,
+}
+
+#[derive(Debug)]
+pub struct PositionalParam<'a> {
+    /// The number of the parameter, e.g. `1` for `$1`.
+    pub text: &'a str,
+    /// Includes the `$`.
+    pub span: Span<'a>,
+    pub kind: PositionalParamKind,
+}
+
+impl<'a> PositionalParam<'a> {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(ident: &Identifier<'a>, kind: PositionalParamKind) -> Statement<'a> {
+        Statement::Error(Box::new(ErrorStatement::PositionalParam(Box::new(Self {
+            text: ident.name,
+            span: ident.sigil_span(),
+            kind,
+        }))))
+    }
+
+    pub fn diagnosis(&self) -> String {
+        let param = format!("${}", self.text);
+        match self.kind {
+            PositionalParamKind::Gap(missing) => {
+                format!("Positional parameter {param} is used but ${missing} is not")
+            }
+            PositionalParamKind::Undeclared => {
+                format!("Positional parameter {param} is not declared")
+            }
+            PositionalParamKind::StringAsInteger => {
+                format!("Positional parameter {param} is declared as a string, use str({param})")
+            }
+            PositionalParamKind::IntegerAsString => {
+                format!(
+                    "Positional parameter {param} is declared as an integer but used as a string"
+                )
+            }
+            PositionalParamKind::Mixed => {
+                format!("Positional parameter {param} is used both as an integer and as a string")
+            }
+            PositionalParamKind::Assigned => {
+                format!("Positional parameter {param} can't be assigned to")
+            }
+        }
+    }
+}
+
+impl<'a> Node<'a> for PositionalParam<'a> {
+    fn as_node(&self) -> &dyn Node<'a> {
+        self
+    }
+
+    fn children(&self) -> Vec<&dyn Node<'a>> {
+        Vec::new()
+    }
+
+    fn span(&self) -> Span<'a> {
+        self.span
+    }
+}
+
+#[derive(Debug)]
+pub struct GetoptConflict<'a> {
+    pub text: &'a str,
+    pub span: Span<'a>,
+    /// The option where it's first read, as in `--name: int64 = 10`.
+    pub first: String,
+}
+
+impl<'a> GetoptConflict<'a> {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(text: &'a str, span: Span<'a>, first: String) -> Statement<'a> {
+        Statement::Error(Box::new(ErrorStatement::GetoptConflict(Box::new(Self {
+            text,
+            span,
+            first,
+        }))))
+    }
+
+    pub fn diagnosis(&self) -> String {
+        format!(
+            "Option \"--{}\" has another default than where it is first read ({})",
+            self.text, self.first
+        )
+    }
+}
+
+impl<'a> Node<'a> for GetoptConflict<'a> {
+    fn as_node(&self) -> &dyn Node<'a> {
+        self
+    }
+
+    fn children(&self) -> Vec<&dyn Node<'a>> {
+        Vec::new()
+    }
+
+    fn span(&self) -> Span<'a> {
+        self.span
+    }
+}
+
+#[derive(Debug)]
+pub struct UnavailableFeature<'a> {
+    pub text: &'a str,
+    pub span: Span<'a>,
+    pub since: Option<Version>,
+    pub until: Option<Version>,
+    /// The targeted version, from the `bpftrace_version` setting.
+    pub target: Version,
+}
+
+impl<'a> UnavailableFeature<'a> {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(
+        text: &'a str,
+        span: Span<'a>,
+        since: Option<Version>,
+        until: Option<Version>,
+        target: Version,
+    ) -> Statement<'a> {
+        Statement::Error(Box::new(ErrorStatement::UnavailableFeature(Box::new(
+            Self {
+                text,
+                span,
+                since,
+                until,
+                target,
+            },
+        ))))
+    }
+
+    pub fn diagnosis(&self) -> String {
+        match (self.since, self.until) {
+            (Some(since), _) if self.target < since => format!(
+                "`{}` requires bpftrace {since} or later, but {} is targeted",
+                self.text, self.target
+            ),
+            (_, Some(until)) => format!(
+                "`{}` was removed in bpftrace {until}, but {} is targeted",
+                self.text, self.target
+            ),
+            _ => format!(
+                "`{}` is not available in bpftrace {}",
+                self.text, self.target
+            ),
+        }
+    }
+}
+
+impl<'a> Node<'a> for UnavailableFeature<'a> {
+    fn as_node(&self) -> &dyn Node<'a> {
+        self
+    }
+
+    fn children(&self) -> Vec<&dyn Node<'a>> {
+        Vec::new()
+    }
+
+    fn span(&self) -> Span<'a> {
+        self.span
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownProvider<'a> {
+    pub text: &'a str,
+    pub span: Span<'a>,
+}
+
+impl<'a> UnknownProvider<'a> {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(text: &'a str, span: Span<'a>) -> Statement<'a> {
+        Statement::Error(Box::new(ErrorStatement::UnknownProvider(Box::new(Self {
+            text,
+            span,
+        }))))
+    }
+
+    pub fn diagnosis(&self) -> String {
+        format!("Unknown probe provider `{}`", self.text.trim())
+    }
+}
+
+impl<'a> Node<'a> for UnknownProvider<'a> {
+    fn as_node(&self) -> &dyn Node<'a> {
+        self
+    }
+
+    fn children(&self) -> Vec<&dyn Node<'a>> {
+        Vec::new()
+    }
+
+    fn span(&self) -> Span<'a> {
+        self.span
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnavailableBuiltinKind {
+    /// Numbered beyond those of the architecture, e.g. `arg6` on x86_64.
+    OutOfRange { arch: String, count: usize },
+    /// Used in a probe of a provider not having it.
+    Provider(String),
+}
+
+#[derive(Debug)]
+pub struct UnavailableBuiltin<'a> {
+    pub text: &'a str,
+    pub span: Span<'a>,
+    pub kind: UnavailableBuiltinKind,
+}
+
+impl<'a> UnavailableBuiltin<'a> {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(ident: &Identifier<'a>, kind: UnavailableBuiltinKind) -> Statement<'a> {
+        Statement::Error(Box::new(ErrorStatement::UnavailableBuiltin(Box::new(
+            Self {
+                text: ident.name,
+                span: ident.span,
+                kind,
+            },
+        ))))
+    }
+
+    pub fn diagnosis(&self) -> String {
+        match &self.kind {
+            UnavailableBuiltinKind::OutOfRange { arch, count } => {
+                let prefix = self.text.trim_end_matches(|x: char| x.is_ascii_digit());
+                format!(
+                    "`{}` is out of range, {arch} has `{prefix}0` to `{prefix}{}`",
+                    self.text,
+                    count.saturating_sub(1)
+                )
+            }
+            UnavailableBuiltinKind::Provider(provider) => {
+                format!("`{}` is not available in `{provider}` probes", self.text)
+            }
+        }
+    }
+}
+
+impl<'a> Node<'a> for UnavailableBuiltin<'a> {
+    fn as_node(&self) -> &dyn Node<'a> {
+        self
+    }
+
+    fn children(&self) -> Vec<&dyn Node<'a>> {
+        Vec::new()
+    }
+
+    fn span(&self) -> Span<'a> {
+        self.span
+    }
+}
+
 #[derive(Debug)]
 pub enum ErrorStatement<'a> {
     UnknownStatement(Box<UnknownStatement<'a>>),
     UndefinedIdent(Box<UndefinedIdent<'a>>),
     UndefinedFunc(Box<UndefinedFunc<'a>>),
+    UnusedVariable(Box<UnusedVariable<'a>>),
+    WriteOnlyMap(Box<WriteOnlyMap<'a>>),
+    MapReadBeforeWrite(Box<MapReadBeforeWrite<'a>>),
+    MapLeak(Box<MapLeak<'a>>),
+    UnresolvedInclude(Box<UnresolvedInclude<'a>>),
+    UnknownField(Box<UnknownField<'a>>),
+    PositionalParam(Box<PositionalParam<'a>>),
+    GetoptConflict(Box<GetoptConflict<'a>>),
+    UnavailableFeature(Box<UnavailableFeature<'a>>),
+    UnknownProvider(Box<UnknownProvider<'a>>),
+    UnavailableBuiltin(Box<UnavailableBuiltin<'a>>),
 }
 
 impl<'a> ErrorStatement<'a> {
@@ -171,6 +716,39 @@ impl<'a> ErrorStatement<'a> {
             Self::UnknownStatement(e) => e.diagnosis(),
             Self::UndefinedIdent(e) => e.diagnosis(),
             Self::UndefinedFunc(e) => e.diagnosis(),
+            Self::UnusedVariable(e) => e.diagnosis(),
+            Self::WriteOnlyMap(e) => e.diagnosis(),
+            Self::MapReadBeforeWrite(e) => e.diagnosis(),
+            Self::MapLeak(e) => e.diagnosis(),
+            Self::UnresolvedInclude(e) => e.diagnosis(),
+            Self::UnknownField(e) => e.diagnosis(),
+            Self::PositionalParam(e) => e.diagnosis(),
+            Self::GetoptConflict(e) => e.diagnosis(),
+            Self::UnavailableFeature(e) => e.diagnosis(),
+            Self::UnknownProvider(e) => e.diagnosis(),
+            Self::UnavailableBuiltin(e) => e.diagnosis(),
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::UnknownStatement(_) => "unknown-statement",
+            Self::UndefinedIdent(_) => "undefined-ident",
+            Self::UndefinedFunc(_) => "undefined-func",
+            Self::UnusedVariable(_) => "unused-variable",
+            Self::WriteOnlyMap(_) => "write-only-map",
+            Self::MapReadBeforeWrite(_) => "map-read-before-write",
+            Self::MapLeak(_) => "map-leak",
+            Self::UnresolvedInclude(_) => "unresolved-include",
+            Self::UnknownField(_) => "unknown-field",
+            Self::PositionalParam(e) if e.kind == PositionalParamKind::Assigned => {
+                "assigned-positional-param"
+            }
+            Self::PositionalParam(_) => "positional-param",
+            Self::GetoptConflict(_) => "getopt-conflict",
+            Self::UnavailableFeature(_) => "unavailable-feature",
+            Self::UnknownProvider(_) => "unknown-provider",
+            Self::UnavailableBuiltin(_) => "unavailable-builtin",
         }
     }
 }
@@ -185,6 +763,17 @@ impl<'a> Node<'a> for ErrorStatement<'a> {
             Self::UnknownStatement(e) => vec![e.as_node()],
             Self::UndefinedIdent(e) => vec![e.as_node()],
             Self::UndefinedFunc(e) => vec![e.as_node()],
+            Self::UnusedVariable(e) => vec![e.as_node()],
+            Self::WriteOnlyMap(e) => vec![e.as_node()],
+            Self::MapReadBeforeWrite(e) => vec![e.as_node()],
+            Self::MapLeak(e) => vec![e.as_node()],
+            Self::UnresolvedInclude(e) => vec![e.as_node()],
+            Self::UnknownField(e) => vec![e.as_node()],
+            Self::PositionalParam(e) => vec![e.as_node()],
+            Self::GetoptConflict(e) => vec![e.as_node()],
+            Self::UnavailableFeature(e) => vec![e.as_node()],
+            Self::UnknownProvider(e) => vec![e.as_node()],
+            Self::UnavailableBuiltin(e) => vec![e.as_node()],
         }
     }
 
@@ -193,6 +782,17 @@ impl<'a> Node<'a> for ErrorStatement<'a> {
             Self::UnknownStatement(e) => e.span(),
             Self::UndefinedIdent(e) => e.span(),
             Self::UndefinedFunc(e) => e.span(),
+            Self::UnusedVariable(e) => e.span(),
+            Self::WriteOnlyMap(e) => e.span(),
+            Self::MapReadBeforeWrite(e) => e.span(),
+            Self::MapLeak(e) => e.span(),
+            Self::UnresolvedInclude(e) => e.span(),
+            Self::UnknownField(e) => e.span(),
+            Self::PositionalParam(e) => e.span(),
+            Self::GetoptConflict(e) => e.span(),
+            Self::UnavailableFeature(e) => e.span(),
+            Self::UnknownProvider(e) => e.span(),
+            Self::UnavailableBuiltin(e) => e.span(),
         }
     }
 
@@ -201,11 +801,13 @@ impl<'a> Node<'a> for ErrorStatement<'a> {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum IdentKind {
     Bare,
     Scratch,
     Map,
+    /// A positional parameter like `$1`, or their count `$#`.
+    Positional,
 }
 
 #[derive(Debug)]
@@ -213,6 +815,35 @@ pub struct Identifier<'a> {
     pub name: &'a str,
     pub span: Span<'a>,
     pub kind: IdentKind,
+    /// Keys of a map access like `@m[tid, comm]`, empty for everything else.
+    pub keys: Vec<Expr<'a>>,
+}
+
+impl<'a> Identifier<'a> {
+    /// The span along with the sigil of variables, `span` leaves it out
+    /// except for the unnamed map `@`.
+    pub fn sigil_span(&self) -> Span<'a> {
+        if self.kind == IdentKind::Bare || self.name.is_empty() {
+            return self.span;
+        }
+        Span::new(
+            self.span.get_input(),
+            self.span.start() - 1,
+            self.span.end(),
+        )
+        .unwrap()
+    }
+
+    /// The source text of the keys, e.g. `tid, comm` for `@m[tid, comm]`.
+    pub fn key_text(&self) -> Option<&'a str> {
+        let (first, last) = (self.keys.first()?, self.keys.last()?);
+        Span::new(
+            self.span.get_input(),
+            first.span().start(),
+            last.span().end(),
+        )
+        .map(|x| x.as_str())
+    }
 }
 
 impl<'a> Node<'a> for Identifier<'a> {
@@ -220,8 +851,12 @@ impl<'a> Node<'a> for Identifier<'a> {
         self
     }
 
+    fn as_identifier(&self) -> Option<&Identifier<'a>> {
+        Some(self)
+    }
+
     fn children(&self) -> Vec<&dyn Node<'a>> {
-        Vec::new()
+        self.keys.iter().map(|x| x.as_node()).collect()
     }
 
     fn span(&self) -> Span<'a> {
@@ -251,7 +886,6 @@ impl<'a> Node<'a> for StringLiteral<'a> {
 
 #[derive(Debug)]
 pub struct IntegerLiteral<'a> {
-    pub value: i64,
     pub span: Span<'a>,
 }
 
@@ -299,6 +933,13 @@ pub struct BinaryExpr<'a> {
     pub span: Span<'a>,
 }
 
+impl<'a> BinaryExpr<'a> {
+    /// The operator, e.g. `+` or `==`.
+    pub fn operator(&self) -> &'a str {
+        operator(&self.span, self.lhs.span().end(), self.rhs.span().start())
+    }
+}
+
 impl<'a> Node<'a> for BinaryExpr<'a> {
     fn as_node(&self) -> &dyn Node<'a> {
         self
@@ -319,6 +960,29 @@ pub struct UnaryExpr<'a> {
     pub span: Span<'a>,
 }
 
+impl<'a> UnaryExpr<'a> {
+    /// The operator, e.g. `!` or `++`, which may come before or after the
+    /// operand.
+    pub fn operator(&self) -> &'a str {
+        let expr = self.expr.span();
+        if self.span.start() < expr.start() {
+            operator(&self.span, self.span.start(), expr.start())
+        } else {
+            operator(&self.span, expr.end(), self.span.end())
+        }
+    }
+}
+
+/// The operator between two offsets. Identifier spans leave out the sigil
+/// and the keys of a map, so those are skipped along with parentheses.
+fn operator<'a>(span: &Span<'a>, start: usize, end: usize) -> &'a str {
+    let text = &span.get_input()[start..end];
+    text.rsplit(']')
+        .next()
+        .unwrap_or(text)
+        .trim_matches(|c: char| c.is_whitespace() || "()$@".contains(c))
+}
+
 impl<'a> Node<'a> for UnaryExpr<'a> {
     fn as_node(&self) -> &dyn Node<'a> {
         self
@@ -333,7 +997,54 @@ impl<'a> Node<'a> for UnaryExpr<'a> {
     }
 }
 
+/// A field of a record, `expr->field` or `expr.field`.
 #[derive(Debug)]
+pub struct FieldAccess<'a> {
+    pub expr: Box<Expr<'a>>,
+    pub field: Identifier<'a>,
+    pub span: Span<'a>,
+}
+
+impl<'a> Node<'a> for FieldAccess<'a> {
+    fn as_node(&self) -> &dyn Node<'a> {
+        self
+    }
+
+    fn children(&self) -> Vec<&dyn Node<'a>> {
+        vec![&*self.expr, &self.field]
+    }
+
+    fn span(&self) -> Span<'a> {
+        self.span
+    }
+}
+
+/// A cast like `(struct path *)arg0`.
+#[derive(Debug)]
+pub struct Cast<'a> {
+    /// The type as written, e.g. `struct path *`.
+    pub ty: &'a str,
+    pub ty_span: Span<'a>,
+    pub expr: Box<Expr<'a>>,
+    pub span: Span<'a>,
+}
+
+impl<'a> Node<'a> for Cast<'a> {
+    fn as_node(&self) -> &dyn Node<'a> {
+        self
+    }
+
+    fn children(&self) -> Vec<&dyn Node<'a>> {
+        vec![&*self.expr]
+    }
+
+    fn span(&self) -> Span<'a> {
+        self.span
+    }
+}
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Expr<'a> {
     Identifier(Box<Identifier<'a>>),
     Integer(Box<IntegerLiteral<'a>>),
@@ -341,6 +1052,8 @@ pub enum Expr<'a> {
     Call(Box<Call<'a>>),
     BinaryExpr(Box<BinaryExpr<'a>>),
     UnaryExpr(Box<UnaryExpr<'a>>),
+    Field(Box<FieldAccess<'a>>),
+    Cast(Box<Cast<'a>>),
 }
 
 impl<'a> Node<'a> for Expr<'a> {
@@ -360,6 +1073,8 @@ impl<'a> Node<'a> for Expr<'a> {
             Self::Call(func) => vec![func.as_node()],
             Self::BinaryExpr(expr) => vec![expr.as_node()],
             Self::UnaryExpr(expr) => vec![expr.as_node()],
+            Self::Field(field) => vec![field.as_node()],
+            Self::Cast(cast) => vec![cast.as_node()],
         }
     }
 
@@ -371,6 +1086,8 @@ impl<'a> Node<'a> for Expr<'a> {
             Self::Call(func) => func.span(),
             Self::BinaryExpr(expr) => expr.span(),
             Self::UnaryExpr(expr) => expr.span(),
+            Self::Field(field) => field.span(),
+            Self::Cast(cast) => cast.span(),
         }
     }
 }
@@ -398,7 +1115,7 @@ impl<'a> Node<'a> for Call<'a> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum AssignOp {
     Assign,
     AddAssign,
@@ -407,7 +1124,10 @@ pub enum AssignOp {
 
 #[derive(Debug)]
 pub struct Assignment<'a> {
+    /// The `let` keyword, when declaring a variable.
+    pub declaration: Option<Span<'a>>,
     pub lvalue: Lvalue<'a>,
+    pub op: AssignOp,
     pub rvalue: Box<Expr<'a>>,
     pub span: Span<'a>,
 }
@@ -430,6 +1150,7 @@ impl<'a> Node<'a> for Assignment<'a> {
 pub enum Loop<'a> {
     While(Box<While<'a>>),
     For(Box<For<'a>>),
+    Unroll(Box<Unroll<'a>>),
 }
 
 impl<'a> Node<'a> for Loop<'a> {
@@ -441,6 +1162,7 @@ impl<'a> Node<'a> for Loop<'a> {
         match self {
             Self::While(w) => w.children(),
             Self::For(f) => f.children(),
+            Self::Unroll(u) => u.children(),
         }
     }
 
@@ -448,6 +1170,7 @@ impl<'a> Node<'a> for Loop<'a> {
         match self {
             Self::While(w) => w.span(),
             Self::For(f) => f.span(),
+            Self::Unroll(u) => u.span(),
         }
     }
 }
@@ -495,6 +1218,28 @@ impl<'a> Node<'a> for For<'a> {
     }
 }
 
+/// Repeats a block a constant number of times.
+#[derive(Debug)]
+pub struct Unroll<'a> {
+    pub count: Box<Expr<'a>>,
+    pub block: Block<'a>,
+    pub span: Span<'a>,
+}
+
+impl<'a> Node<'a> for Unroll<'a> {
+    fn as_node(&self) -> &dyn Node<'a> {
+        self
+    }
+
+    fn children(&self) -> Vec<&dyn Node<'a>> {
+        vec![&*self.count, &self.block]
+    }
+
+    fn span(&self) -> Span<'a> {
+        self.span
+    }
+}
+
 #[derive(Debug)]
 pub struct If<'a> {
     pub condition: Box<Expr<'a>>,
@@ -523,6 +1268,7 @@ pub enum Statement<'a> {
     IfCond(Box<If<'a>>),
     Loop(Box<Loop<'a>>),
     Expr(Box<Expr<'a>>),
+    Return(Box<Return<'a>>),
 }
 
 impl<'a> Node<'a> for Statement<'a> {
@@ -541,6 +1287,7 @@ impl<'a> Node<'a> for Statement<'a> {
             Self::IfCond(c) => vec![c.as_node()],
             Self::Loop(c) => vec![c.as_node()],
             Self::Expr(e) => vec![e.as_node()],
+            Self::Return(r) => vec![r.as_node()],
         }
     }
 
@@ -551,10 +1298,32 @@ impl<'a> Node<'a> for Statement<'a> {
             Self::IfCond(c) => c.span(),
             Self::Loop(c) => c.span(),
             Self::Expr(e) => e.span(),
+            Self::Return(r) => r.span(),
         }
     }
 }
 
+/// Leaves a function, with the value it returns if any.
+#[derive(Debug)]
+pub struct Return<'a> {
+    pub value: Option<Box<Expr<'a>>>,
+    pub span: Span<'a>,
+}
+
+impl<'a> Node<'a> for Return<'a> {
+    fn as_node(&self) -> &dyn Node<'a> {
+        self
+    }
+
+    fn children(&self) -> Vec<&dyn Node<'a>> {
+        self.value.iter().map(|x| x.as_node()).collect()
+    }
+
+    fn span(&self) -> Span<'a> {
+        self.span
+    }
+}
+
 #[derive(Debug)]
 pub struct UnmatchedBrace<'a> {
     pub span: Span<'a>,
@@ -619,6 +1388,13 @@ impl<'a> ErrorPreamble<'a> {
             Self::UnmatchedBrace(e) => e.diagnosis(),
         }
     }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::UnknownPreamble(_) => "unknown-preamble",
+            Self::UnmatchedBrace(_) => "unmatched-brace",
+        }
+    }
 }
 
 impl<'a> Node<'a> for ErrorPreamble<'a> {
@@ -645,8 +1421,122 @@ impl<'a> Node<'a> for ErrorPreamble<'a> {
     }
 }
 
+/// An `#include <path>` or `#include "path"` directive.
+#[derive(Debug)]
+pub struct Include<'a> {
+    pub path: &'a str,
+    pub path_span: Span<'a>,
+    /// Whether the path is in angle brackets, which aren't looked up next
+    /// to the script.
+    pub system: bool,
+    pub span: Span<'a>,
+}
+
+impl<'a> Node<'a> for Include<'a> {
+    fn as_node(&self) -> &dyn Node<'a> {
+        self
+    }
+
+    fn children(&self) -> Vec<&dyn Node<'a>> {
+        Vec::new()
+    }
+
+    fn span(&self) -> Span<'a> {
+        self.span
+    }
+}
+
+/// A struct, union or enum definition, or a `#define`, written in C.
+#[derive(Debug)]
+pub struct CDefinition<'a> {
+    pub text: &'a str,
+    pub span: Span<'a>,
+}
+
+impl<'a> Node<'a> for CDefinition<'a> {
+    fn as_node(&self) -> &dyn Node<'a> {
+        self
+    }
+
+    fn children(&self) -> Vec<&dyn Node<'a>> {
+        Vec::new()
+    }
+
+    fn span(&self) -> Span<'a> {
+        self.span
+    }
+}
+
+/// A user-defined function like `fn add($a: int64, $b: int64): int64 { ... }`.
+#[derive(Debug)]
+pub struct Function<'a> {
+    pub name: Identifier<'a>,
+    pub params: Vec<FunctionParam<'a>>,
+    /// The type returned, if any.
+    pub return_type: Option<&'a str>,
+    pub block: Block<'a>,
+    pub span: Span<'a>,
+}
+
+impl Function<'_> {
+    /// The function as declared, without its body.
+    pub fn signature(&self) -> String {
+        let params = self
+            .params
+            .iter()
+            .map(|x| format!("${}: {}", x.ident.name, x.ty))
+            .collect::<Vec<_>>()
+            .join(", ");
+        match self.return_type {
+            Some(ty) => format!("fn {}({params}): {ty}", self.name.name),
+            None => format!("fn {}({params})", self.name.name),
+        }
+    }
+}
+
+impl<'a> Node<'a> for Function<'a> {
+    fn as_node(&self) -> &dyn Node<'a> {
+        self
+    }
+
+    fn children(&self) -> Vec<&dyn Node<'a>> {
+        let mut children: Vec<&dyn Node> = vec![&self.name];
+        children.extend(self.params.iter().map(|x| x.as_node()));
+        children.push(&self.block);
+        children
+    }
+
+    fn span(&self) -> Span<'a> {
+        self.span
+    }
+}
+
+#[derive(Debug)]
+pub struct FunctionParam<'a> {
+    pub ident: Identifier<'a>,
+    pub ty: &'a str,
+    pub span: Span<'a>,
+}
+
+impl<'a> Node<'a> for FunctionParam<'a> {
+    fn as_node(&self) -> &dyn Node<'a> {
+        self
+    }
+
+    fn children(&self) -> Vec<&dyn Node<'a>> {
+        vec![&self.ident]
+    }
+
+    fn span(&self) -> Span<'a> {
+        self.span
+    }
+}
+
 #[derive(Debug)]
 pub enum Preamble<'a> {
+    Include(Include<'a>),
+    Definition(CDefinition<'a>),
+    Function(Function<'a>),
     Probe(Probe<'a>),
     Error(Box<ErrorPreamble<'a>>),
 }
@@ -658,6 +1548,9 @@ impl<'a> Node<'a> for Preamble<'a> {
 
     fn children(&self) -> Vec<&dyn Node<'a>> {
         match self {
+            Self::Include(i) => vec![i.as_node()],
+            Self::Definition(d) => vec![d.as_node()],
+            Self::Function(f) => vec![f.as_node()],
             Self::Probe(p) => p.children(),
             Self::Error(e) => vec![e.as_node()],
         }
@@ -665,6 +1558,9 @@ impl<'a> Node<'a> for Preamble<'a> {
 
     fn span(&self) -> Span<'a> {
         match self {
+            Self::Include(i) => i.span(),
+            Self::Definition(d) => d.span(),
+            Self::Function(f) => f.span(),
             Self::Probe(p) => p.span(),
             Self::Error(e) => e.span(),
         }
@@ -685,7 +1581,9 @@ impl<'a> Node<'a> for Probe<'a> {
     }
 
     fn children(&self) -> Vec<&dyn Node<'a>> {
-        self.block.children()
+        let mut children: Vec<&dyn Node> = self.condition.iter().map(|x| x.as_node()).collect();
+        children.push(&self.block);
+        children
     }
 
     fn span(&self) -> Span<'a> {
@@ -727,12 +1625,41 @@ pub enum ErrorRef<'a, 'b> {
 }
 
 impl<'a, 'b> ErrorRef<'a, 'b> {
+    /// Every code returned by [`ErrorRef::code`], the names users refer to
+    /// checks by in their settings.
+    pub const CODES: &'static [&'static str] = &[
+        "unknown-statement",
+        "undefined-ident",
+        "undefined-func",
+        "unknown-preamble",
+        "unmatched-brace",
+        "unused-variable",
+        "write-only-map",
+        "map-read-before-write",
+        "map-leak",
+        "unresolved-include",
+        "unknown-field",
+        "positional-param",
+        "assigned-positional-param",
+        "getopt-conflict",
+        "unavailable-feature",
+        "unknown-provider",
+        "unavailable-builtin",
+    ];
+
     pub fn diagnosis(&self) -> String {
         match self {
             Self::Statement(stmt) => stmt.diagnosis(),
             Self::Preamble(pream) => pream.diagnosis(),
         }
     }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Statement(stmt) => stmt.code(),
+            Self::Preamble(pream) => pream.code(),
+        }
+    }
 }
 
 impl<'a, 'b> Node<'a> for ErrorRef<'a, 'b> {
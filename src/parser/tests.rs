@@ -19,6 +19,7 @@ fn test_sanity() {
     parse_no_errors("tracepoint:sched:* { $x = 1; }");
     parse_no_errors("tracepoint:sched:* { $x  = 1   ; }");
     parse_no_errors("END, BEGIN { $x  = 1   ; }");
+    parse_no_errors("kprobe:a, kprobe:b, kprobe:c { }");
     parse_no_errors("END, BEGIN / 1 / {}");
     parse_no_errors("BEGIN { $x = 1 + 2 - 3 * 4; }");
     parse_no_errors("BEGIN { $x = 1 + 2 - 3 * func($y, $z); }");
@@ -28,6 +29,10 @@ fn test_sanity() {
     parse_no_errors("BEGIN { for ($x : $y) { $var += 1; } }");
     parse_no_errors("BEGIN { @map = 1 + 2; $var = -1; $var = +2; $var2 = @map + -1; }");
     parse_no_errors("BEGIN { $var++; --$var; }");
+    parse_no_errors("BEGIN { @ = count(); @[comm] = count(); print(@); }");
+    parse_no_errors("kprobe:f { @start[tid] = nsecs; @m[pid, comm]++; delete(@start[tid]); }");
+    parse_no_errors("kretprobe:f / @start[tid] / { $x = nsecs - @start[tid]; }");
+    parse_no_errors("BEGIN { $x = (1 + 2) * 3; if ((($x))) {} }");
 
     // should fail
     // variable outside probe
@@ -69,6 +74,12 @@ fn test_probe() {
     };
     assert_eq!(probe.attach_points[0], "tracepoint:sched:*");
     assert_eq!(probe.block.statements.len(), 0);
+
+    let prog = parse("kprobe:a, kprobe:b, kprobe:c { }").unwrap();
+    let Preamble::Probe(probe) = &prog.preambles[0] else {
+        panic!("not a probe!");
+    };
+    assert_eq!(probe.attach_points, ["kprobe:a", "kprobe:b", "kprobe:c"]);
 }
 
 #[test]
@@ -114,6 +125,13 @@ fn test_calls() {
         panic!("not an expression!");
     };
     assert!(matches!(call.as_ref(), Expr::Call(_)));
+    let Statement::Expr(call) = &probe.block.statements[4] else {
+        panic!("not an expression!");
+    };
+    let Expr::Call(call) = call.as_ref() else {
+        panic!("not a call!");
+    };
+    assert_eq!(call.args.len(), 3);
 }
 
 #[test]
@@ -138,3 +156,88 @@ fn test_loops() {
 
     assert_eq!(loops, 2);
 }
+
+#[test]
+fn test_declarations() {
+    let prog = parse(
+        r#"fn add($a: int64, $b: int64): int64 { return $a + $b; }
+        BEGIN {
+            let $x = 1;
+            unroll (3) { $x += 1; }
+        }"#,
+    )
+    .unwrap();
+    assert!(prog.errors().next().is_none(), "parse failed!");
+    let Preamble::Function(function) = &prog.preambles[0] else {
+        panic!("not a function!");
+    };
+    assert_eq!(function.signature(), "fn add($a: int64, $b: int64): int64");
+    assert!(matches!(
+        function.block.statements[..],
+        [Statement::Return(ref ret)] if ret.value.is_some()
+    ));
+    let Preamble::Probe(probe) = &prog.preambles[1] else {
+        panic!("not a probe!");
+    };
+    let Statement::Assignment(assign) = &probe.block.statements[0] else {
+        panic!("not an assignment!");
+    };
+    assert_eq!(assign.declaration.map(|x| x.as_str()), Some("let"));
+    let Statement::Loop(unroll) = &probe.block.statements[1] else {
+        panic!("not a loop!");
+    };
+    assert!(matches!(unroll.as_ref(), Loop::Unroll(_)));
+}
+
+#[test]
+fn test_map_keys() {
+    let prog = parse("BEGIN { @m[tid, comm] = @n[1]; }").unwrap();
+    let Preamble::Probe(probe) = &prog.preambles[0] else {
+        panic!("not a probe!");
+    };
+    let Statement::Assignment(assign) = &probe.block.statements[0] else {
+        panic!("not an assignment!");
+    };
+    let Lvalue::Identifier(lvalue) = &assign.lvalue;
+    assert_eq!(lvalue.name, "m");
+    assert_eq!(lvalue.key_text(), Some("tid, comm"));
+    let Expr::Identifier(rvalue) = assign.rvalue.as_ref() else {
+        panic!("not an identifier!");
+    };
+    assert_eq!(rvalue.name, "n");
+    assert_eq!(rvalue.keys.len(), 1);
+}
+
+#[test]
+fn test_parens() {
+    let prog = parse("BEGIN { $x += (1 + 2) * 3; }").unwrap();
+    let Preamble::Probe(probe) = &prog.preambles[0] else {
+        panic!("not a probe!");
+    };
+    let Statement::Assignment(assign) = &probe.block.statements[0] else {
+        panic!("not an assignment!");
+    };
+    assert_eq!(assign.op, AssignOp::AddAssign);
+    let Expr::BinaryExpr(mul) = assign.rvalue.as_ref() else {
+        panic!("not a binary expression!");
+    };
+    assert_eq!(mul.span.as_str(), "(1 + 2) * 3");
+    assert_eq!(mul.lhs.span().as_str(), "(1 + 2)");
+    assert_eq!(mul.rhs.span().as_str(), "3");
+}
+
+#[test]
+fn test_operators() {
+    let prog = parse("BEGIN { $x = @m[tid] >= -$y; @m[tid]++; }").unwrap();
+    let Preamble::Probe(probe) = &prog.preambles[0] else {
+        panic!("not a probe!");
+    };
+    let operators = Walk::new(probe.as_node())
+        .filter_map(|node| match node.as_expr()? {
+            Expr::BinaryExpr(bin) => Some(bin.operator()),
+            Expr::UnaryExpr(unary) => Some(unary.operator()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(operators, [">=", "-", "++"]);
+}
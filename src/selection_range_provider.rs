@@ -0,0 +1,84 @@
+use super::parser::{Node, Statement};
+use super::server::Context;
+use tower_lsp::jsonrpc::{Error, ErrorCode, Result};
+use tower_lsp::lsp_types::{Position, Range, SelectionRange, Url};
+
+pub async fn selection_ranges(
+    context: &Context,
+    uri: &Url,
+    positions: Vec<Position>,
+) -> Result<Option<Vec<SelectionRange>>> {
+    let analyzed = context
+        .analyzer
+        .analyze(context, uri)
+        .await
+        .map_err(|_| Error::new(ErrorCode::InternalError))?;
+    let line_index = &analyzed.document.line_index;
+
+    let mut ranges = Vec::new();
+    for position in positions {
+        let Some(offset) = line_index.offset(position) else {
+            return Ok(None);
+        };
+        let mut selection: Option<SelectionRange> = None;
+        for (start, end) in enclosing(analyzed.ast().as_node(), offset) {
+            let range = Range::new(line_index.position(start), line_index.position(end));
+            if selection.as_ref().is_some_and(|x| x.range == range) {
+                continue;
+            }
+            selection = Some(SelectionRange {
+                range,
+                parent: selection.map(Box::new),
+            });
+        }
+        ranges.push(selection.unwrap_or(SelectionRange {
+            range: Range::new(position, position),
+            parent: None,
+        }));
+    }
+    Ok(Some(ranges))
+}
+
+/// Spans of the nodes containing an offset, outermost first.
+fn enclosing<'a>(root: &dyn Node<'a>, offset: usize) -> Vec<(usize, usize)> {
+    let mut spans = vec![extent(root)];
+    let mut node = root;
+    loop {
+        let child = node.children().into_iter().find_map(|child| {
+            // errors are attached to the first block, wherever they are
+            if let Some(Statement::Error(_)) = child.as_statement() {
+                return None;
+            }
+            let (start, end) = extent(child);
+            (start <= offset && offset <= end).then_some((child, (start, end)))
+        });
+        let Some((child, span)) = child else {
+            return spans;
+        };
+        spans.push(span);
+        node = child;
+    }
+}
+
+/// The text a node covers. Spans of identifiers leave out their sigil and
+/// the keys of maps, which are included here, along with everything the
+/// children of a node cover.
+fn extent<'a>(node: &dyn Node<'a>) -> (usize, usize) {
+    let span = node.as_identifier().map_or(node.span(), |x| x.sigil_span());
+    let (mut start, mut end) = (span.start(), span.end());
+    if let Some(last) = node.as_identifier().and_then(|x| x.keys.last()) {
+        let keys_end = last.span().end();
+        end = span.get_input()[keys_end..]
+            .find(']')
+            .map_or(keys_end, |x| keys_end + x + 1);
+    }
+    for child in node.children() {
+        if let Some(Statement::Error(_)) = child.as_statement() {
+            continue;
+        }
+        let (child_start, child_end) = extent(child);
+        start = start.min(child_start);
+        end = end.max(child_end);
+    }
+    (start, end)
+}
@@ -1,32 +1,200 @@
-use super::{analyzer::semantic_analyzer::SemanticAnalyzer, client::Client, storage::Storage};
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use super::{
+    analyzer::semantic_analyzer::SemanticAnalyzer,
+    btf::BtfCache,
+    client::{BTLS_SECTION, Client},
+    completion_provider::TRIGGER_CHARACTERS,
+    config::{self, Config},
+    diagnostic_provider::{DEBOUNCE, DiagnosticScheduler},
+    storage::Storage,
+    workspace::WorkspaceIndex,
+};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, atomic::Ordering},
+    time::{Duration, SystemTime},
+};
+use tokio::sync::{Mutex, RwLock};
 use tower_lsp::{
     LanguageServer, LspService, Server,
     jsonrpc::Result,
     lsp_types::{
-        CompletionOptions, CompletionParams, CompletionResponse, DidChangeTextDocumentParams,
-        DidOpenTextDocumentParams, InitializeParams, InitializeResult, InitializedParams,
-        MessageType, ServerCapabilities,
+        CodeActionParams, CodeActionProviderCapability, CodeActionResponse, CompletionItem,
+        CompletionOptions, CompletionParams, CompletionResponse, DiagnosticOptions,
+        DiagnosticServerCapabilities, DidChangeConfigurationParams, DidChangeTextDocumentParams,
+        DidChangeWorkspaceFoldersParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
+        DocumentDiagnosticParams, DocumentDiagnosticReportResult, DocumentLink,
+        DocumentLinkOptions, DocumentLinkParams, DocumentSymbolParams, DocumentSymbolResponse,
+        FoldingRange, FoldingRangeParams, FoldingRangeProviderCapability, GotoDefinitionParams,
+        GotoDefinitionResponse, Hover, HoverParams, HoverProviderCapability, InitializeParams,
+        InitializeResult, InitializedParams, InlayHint, InlayHintParams, MessageType, OneOf,
+        SelectionRange, SelectionRangeParams, SelectionRangeProviderCapability, ServerCapabilities,
+        SymbolInformation, Url, WorkspaceDiagnosticParams, WorkspaceDiagnosticReportResult,
+        WorkspaceFolder, WorkspaceFoldersServerCapabilities, WorkspaceServerCapabilities,
+        WorkspaceSymbolParams,
     },
 };
 
-struct Backend {
-    context: Context,
+pub struct Backend {
+    pub context: Arc<Context>,
 }
 
 pub struct Context {
     pub client: Client,
     pub storage: Arc<Mutex<Storage>>,
-    pub analyzer: Mutex<SemanticAnalyzer>,
+    pub analyzer: SemanticAnalyzer,
+    pub btf: BtfCache,
+    pub diagnostics: DiagnosticScheduler,
+    /// Resolved configurations, keyed by the project file they were read
+    /// from along with its modification time.
+    pub configs: RwLock<HashMap<ConfigKey, Arc<Config>>>,
+    /// Settings sent along with `initialize` or pushed by
+    /// `workspace/didChangeConfiguration`, for clients that can't be asked.
+    pub settings: RwLock<serde_json::Value>,
+    pub workspace: WorkspaceIndex,
+}
+
+/// The workspace folder whose settings apply, along with the project file and
+/// its modification time.
+type ConfigKey = (Option<Url>, Option<(PathBuf, Option<SystemTime>)>);
+
+impl Context {
+    /// Returns the configuration applying to the document at `uri`, asking
+    /// the client for the settings of its workspace folder only if it isn't
+    /// cached yet. Only documents on disk can have a project file.
+    pub async fn config(&self, uri: &Url) -> Arc<Config> {
+        let folder = self.workspace.folder(uri);
+        let project_file = uri
+            .to_file_path()
+            .ok()
+            .and_then(|path| config::find_project_file(&path));
+        let key = (
+            folder.clone(),
+            project_file.as_ref().map(|file| {
+                let modified = std::fs::metadata(file).and_then(|x| x.modified()).ok();
+                (file.clone(), modified)
+            }),
+        );
+        if let Some(config) = self.configs.read().await.get(&key) {
+            return config.clone();
+        }
+
+        let settings = match self.client.config(folder.clone()).await {
+            Some(settings) => settings,
+            None => self.settings.read().await.clone(),
+        };
+        let folder_path = folder.and_then(|x| x.to_file_path().ok());
+        let config =
+            match Config::resolve(settings, folder_path.as_deref(), project_file.as_deref()) {
+                Ok(config) => config,
+                Err(err) => {
+                    self.client
+                        .show_message(
+                            MessageType::ERROR,
+                            format!("btls: invalid settings, using defaults: {err:#}"),
+                        )
+                        .await;
+                    Config::default()
+                }
+            };
+        let config = Arc::new(config);
+        self.configs.write().await.insert(key, config.clone());
+        config
+    }
+}
+
+impl Backend {
+    /// Drops the cached configurations and checks open documents again.
+    async fn settings_changed(&self) {
+        self.context.configs.write().await.clear();
+        self.context.diagnostics.invalidate_results();
+        if self.context.diagnostics.is_pull() {
+            self.context.client.workspace_diagnostic_refresh().await;
+            return;
+        }
+        let docs = self.context.storage.lock().await.memory_docs();
+        for doc in docs {
+            self.context
+                .diagnostics
+                .schedule(&self.context, doc.uri.clone(), Duration::ZERO);
+        }
+    }
 }
 
 #[tower_lsp::async_trait]
 impl LanguageServer for Backend {
-    async fn initialize(&self, _: InitializeParams) -> Result<InitializeResult> {
+    async fn initialize(&self, params: InitializeParams) -> Result<InitializeResult> {
+        let pull_diagnostics = params
+            .capabilities
+            .text_document
+            .is_some_and(|x| x.diagnostic.is_some());
+        self.context.diagnostics.set_pull(pull_diagnostics);
+
+        let supports_configuration = params
+            .capabilities
+            .workspace
+            .and_then(|x| x.configuration)
+            .unwrap_or(false);
+        self.context
+            .client
+            .supports_configuration
+            .store(supports_configuration, Ordering::Relaxed);
+        if let Some(settings) = params.initialization_options {
+            *self.context.settings.write().await = settings;
+        }
+        // clients not supporting multiple folders send just the root
+        #[allow(deprecated)]
+        let folders = params.workspace_folders.unwrap_or_else(|| {
+            let root = params.root_uri.into_iter();
+            root.map(|uri| WorkspaceFolder {
+                name: uri
+                    .path()
+                    .rsplit('/')
+                    .next()
+                    .unwrap_or_default()
+                    .to_string(),
+                uri,
+            })
+            .collect()
+        });
+        self.context.workspace.set_folders(folders);
+
         Ok(InitializeResult {
             capabilities: ServerCapabilities {
-                completion_provider: Some(CompletionOptions::default()),
+                code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+                completion_provider: Some(CompletionOptions {
+                    trigger_characters: Some(
+                        TRIGGER_CHARACTERS.iter().map(|x| x.to_string()).collect(),
+                    ),
+                    resolve_provider: Some(true),
+                    ..Default::default()
+                }),
+                definition_provider: Some(OneOf::Left(true)),
+                document_symbol_provider: Some(OneOf::Left(true)),
+                document_link_provider: Some(DocumentLinkOptions {
+                    resolve_provider: Some(false),
+                    work_done_progress_options: Default::default(),
+                }),
+                folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
+                inlay_hint_provider: Some(OneOf::Left(true)),
+                selection_range_provider: Some(SelectionRangeProviderCapability::Simple(true)),
+                workspace_symbol_provider: Some(OneOf::Left(true)),
+                workspace: Some(WorkspaceServerCapabilities {
+                    workspace_folders: Some(WorkspaceFoldersServerCapabilities {
+                        supported: Some(true),
+                        change_notifications: Some(OneOf::Left(true)),
+                    }),
+                    file_operations: None,
+                }),
+                diagnostic_provider: Some(DiagnosticServerCapabilities::Options(
+                    DiagnosticOptions {
+                        identifier: Some("btls".to_string()),
+                        inter_file_dependencies: false,
+                        workspace_diagnostics: true,
+                        ..Default::default()
+                    },
+                )),
                 text_document_sync: Some(tower_lsp::lsp_types::TextDocumentSyncCapability::Kind(
                     tower_lsp::lsp_types::TextDocumentSyncKind::FULL,
                 )),
@@ -44,50 +212,174 @@ impl LanguageServer for Backend {
                 "btls (bpftrace language server) initialized",
             )
             .await;
+        let context = self.context.clone();
+        tokio::spawn(async move { context.workspace.scan(&context.storage).await });
     }
 
     async fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
-        let Ok(path) = params
-            .text_document_position
-            .text_document
-            .uri
-            .to_file_path()
-        else {
-            return Ok(None);
-        };
+        let uri = &params.text_document_position.text_document.uri;
         let pos = params.text_document_position.position;
-        super::completion_provider::completion(&self.context, &path, pos).await
+        super::completion_provider::completion(&self.context, uri, pos).await
     }
 
-    async fn did_open(&self, params: DidOpenTextDocumentParams) {
-        let Ok(path) = params.text_document.uri.to_file_path() else {
-            return;
-        };
-        self.context
-            .storage
-            .lock()
-            .await
-            .load(&path, &params.text_document.text, params.text_document.version);
+    async fn completion_resolve(&self, item: CompletionItem) -> Result<CompletionItem> {
+        Ok(super::completion_provider::resolve(item))
+    }
+
+    async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
+        super::code_action_provider::code_actions(&self.context, &params).await
+    }
+
+    async fn goto_definition(
+        &self,
+        params: GotoDefinitionParams,
+    ) -> Result<Option<GotoDefinitionResponse>> {
+        let uri = &params.text_document_position_params.text_document.uri;
+        let pos = params.text_document_position_params.position;
+        super::definition_provider::definition(&self.context, uri, pos).await
+    }
+
+    async fn document_link(&self, params: DocumentLinkParams) -> Result<Option<Vec<DocumentLink>>> {
+        super::definition_provider::document_links(&self.context, &params.text_document.uri).await
+    }
 
-        super::diagnostic_provider::publish_diagnostics(&self.context, params.text_document.uri)
+    async fn document_symbol(
+        &self,
+        params: DocumentSymbolParams,
+    ) -> Result<Option<DocumentSymbolResponse>> {
+        let symbols = self
+            .context
+            .workspace
+            .file_symbols(&self.context.storage, &params.text_document.uri)
             .await;
+        Ok(Some(DocumentSymbolResponse::Flat(symbols)))
+    }
+
+    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let uri = &params.text_document_position_params.text_document.uri;
+        let pos = params.text_document_position_params.position;
+        super::hover_provider::hover(&self.context, uri, pos).await
+    }
+
+    async fn inlay_hint(&self, params: InlayHintParams) -> Result<Option<Vec<InlayHint>>> {
+        super::inlay_hint_provider::inlay_hints(
+            &self.context,
+            &params.text_document.uri,
+            params.range,
+        )
+        .await
+    }
+
+    async fn folding_range(&self, params: FoldingRangeParams) -> Result<Option<Vec<FoldingRange>>> {
+        super::folding_range_provider::folding_ranges(&self.context, &params.text_document.uri)
+            .await
+    }
+
+    async fn selection_range(
+        &self,
+        params: SelectionRangeParams,
+    ) -> Result<Option<Vec<SelectionRange>>> {
+        super::selection_range_provider::selection_ranges(
+            &self.context,
+            &params.text_document.uri,
+            params.positions,
+        )
+        .await
+    }
+
+    async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        self.context.storage.lock().await.load(
+            &params.text_document.uri,
+            &params.text_document.text,
+            params.text_document.version,
+        );
+
+        self.context
+            .diagnostics
+            .schedule(&self.context, params.text_document.uri, Duration::ZERO);
     }
 
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
-        let Ok(path) = params.text_document.uri.to_file_path() else {
-            return;
-        };
         let Some(changes) = params.content_changes.first() else {
             return;
         };
         self.context.storage.lock().await.load(
-            &path,
+            &params.text_document.uri,
             &changes.text,
             params.text_document.version,
         );
 
-        super::diagnostic_provider::publish_diagnostics(&self.context, params.text_document.uri)
+        self.context
+            .diagnostics
+            .schedule(&self.context, params.text_document.uri, DEBOUNCE);
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        let uri = &params.text_document.uri;
+        self.context.diagnostics.cancel(uri);
+        self.context.storage.lock().await.unload(uri);
+        self.context.analyzer.invalidate(uri);
+        self.context
+            .client
+            .publish_diagnostics(params.text_document.uri, vec![], None)
+            .await;
+    }
+
+    async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
+        // clients pushing their settings send them in full, others just
+        // notify us that it's time to ask again
+        if let Some(settings) = params.settings.get(BTLS_SECTION) {
+            *self.context.settings.write().await = settings.clone();
+        }
+        self.settings_changed().await;
+    }
+
+    async fn did_change_workspace_folders(&self, params: DidChangeWorkspaceFoldersParams) {
+        self.context.workspace.change_folders(params.event);
+        // documents may have moved to a folder with different settings
+        self.settings_changed().await;
+        let context = self.context.clone();
+        tokio::spawn(async move { context.workspace.scan(&context.storage).await });
+    }
+
+    async fn diagnostic(
+        &self,
+        params: DocumentDiagnosticParams,
+    ) -> Result<DocumentDiagnosticReportResult> {
+        let report = super::diagnostic_provider::document_diagnostic(
+            &self.context,
+            &params.text_document.uri,
+            params.previous_result_id,
+        )
+        .await?;
+        Ok(DocumentDiagnosticReportResult::Report(report))
+    }
+
+    async fn workspace_diagnostic(
+        &self,
+        params: WorkspaceDiagnosticParams,
+    ) -> Result<WorkspaceDiagnosticReportResult> {
+        let previous_result_ids = params
+            .previous_result_ids
+            .into_iter()
+            .map(|x| (x.uri, x.value))
+            .collect();
+        let report =
+            super::diagnostic_provider::workspace_diagnostic(&self.context, previous_result_ids)
+                .await?;
+        Ok(WorkspaceDiagnosticReportResult::Report(report))
+    }
+
+    async fn symbol(
+        &self,
+        params: WorkspaceSymbolParams,
+    ) -> Result<Option<Vec<SymbolInformation>>> {
+        let symbols = self
+            .context
+            .workspace
+            .symbols(&self.context.storage, &params.query)
             .await;
+        Ok(Some(symbols))
     }
 
     async fn shutdown(&self) -> Result<()> {
@@ -96,16 +388,23 @@ impl LanguageServer for Backend {
 }
 
 pub async fn run() {
-    let analyzer = SemanticAnalyzer::new();
     let (service, socket) = LspService::new(move |client| {
         let client = Client::new(client);
+        let analyzer = SemanticAnalyzer::new();
         let storage = Arc::new(Mutex::new(Storage::new()));
         let context = Context {
             client,
             storage,
-            analyzer: tokio::sync::Mutex::new(analyzer),
+            analyzer,
+            btf: BtfCache::new(),
+            diagnostics: DiagnosticScheduler::new(),
+            configs: RwLock::new(HashMap::new()),
+            settings: RwLock::new(serde_json::Value::Null),
+            workspace: WorkspaceIndex::new(),
         };
-        Backend { context }
+        Backend {
+            context: Arc::new(context),
+        }
     });
 
     Server::new(tokio::io::stdin(), tokio::io::stdout(), socket)
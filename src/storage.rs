@@ -1,9 +1,6 @@
-use std::{
-    collections::BTreeMap,
-    path::{Path, PathBuf},
-    sync::Arc,
-    time::SystemTime,
-};
+use std::{collections::BTreeMap, sync::Arc, time::SystemTime};
+
+use tower_lsp::lsp_types::Url;
 
 use crate::common::utils::OwnedLineIndex;
 
@@ -22,18 +19,18 @@ impl DocumentVersion {
 
 #[derive(Debug)]
 pub struct Document {
-    pub path: PathBuf,
+    pub uri: Url,
     pub data: Arc<String>,
     pub version: DocumentVersion,
     pub line_index: OwnedLineIndex,
 }
 
 impl Document {
-    pub fn new(path: &Path, data: String, version: DocumentVersion) -> Self {
+    pub fn new(uri: &Url, data: String, version: DocumentVersion) -> Self {
         let data = Arc::new(data);
         let line_index = OwnedLineIndex::new(data.clone());
         Self {
-            path: path.to_path_buf(),
+            uri: uri.clone(),
             data,
             version,
             line_index,
@@ -43,7 +40,7 @@ impl Document {
 
 impl std::hash::Hash for Document {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.path.hash(state);
+        self.uri.hash(state);
         self.data.hash(state);
         self.version.hash(state);
     }
@@ -51,15 +48,17 @@ impl std::hash::Hash for Document {
 
 impl PartialEq for Document {
     fn eq(&self, other: &Self) -> bool {
-        self.path == other.path && self.data == other.data && self.version == other.version
+        self.uri == other.uri && self.data == other.data && self.version == other.version
     }
 }
 
 impl Eq for Document {}
 
+/// Documents by URI. Open documents are kept in memory, whatever their
+/// scheme, while others are read from disk if they are `file:` URIs.
 #[derive(Default)]
 pub struct Storage {
-    memory_docs: BTreeMap<PathBuf, Arc<Document>>,
+    memory_docs: BTreeMap<Url, Arc<Document>>,
 }
 
 impl Storage {
@@ -67,10 +66,14 @@ impl Storage {
         Default::default()
     }
 
-    pub fn read_version(&self, path: &Path) -> DocumentVersion {
-        if let Some(doc) = self.memory_docs.get(path) {
+    pub fn read_version(&self, uri: &Url) -> DocumentVersion {
+        if let Some(doc) = self.memory_docs.get(uri) {
             return doc.version;
         }
+        // untitled buffers and remote files only exist once they're opened
+        let Ok(path) = uri.to_file_path() else {
+            return DocumentVersion::IoError;
+        };
         let modified = match std::fs::metadata(path).and_then(|meta| meta.modified()) {
             Ok(modified) => modified,
             Err(_) => return DocumentVersion::IoError,
@@ -78,28 +81,32 @@ impl Storage {
         DocumentVersion::OnDisk { modified }
     }
 
-    pub fn read(&self, path: &Path) -> Arc<Document> {
-        if let Some(doc) = self.memory_docs.get(path) {
+    pub fn read(&self, uri: &Url) -> Arc<Document> {
+        if let Some(doc) = self.memory_docs.get(uri) {
             return doc.clone();
         }
-        let version = self.read_version(path);
-        let data = std::fs::read_to_string(path).unwrap_or_default();
-        Arc::new(Document::new(path, data, version))
+        let version = self.read_version(uri);
+        let data = uri
+            .to_file_path()
+            .ok()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .unwrap_or_default();
+        Arc::new(Document::new(uri, data, version))
     }
 
-    pub fn load(&mut self, path: &Path, data: &str, revision: i32) {
+    pub fn load(&mut self, uri: &Url, data: &str, revision: i32) {
         self.memory_docs.insert(
-            path.to_path_buf(),
+            uri.clone(),
             Arc::new(Document::new(
-                path,
+                uri,
                 data.to_string(),
                 DocumentVersion::InMemory { revision },
             )),
         );
     }
 
-    pub fn unload(&mut self, path: &Path) {
-        self.memory_docs.remove(path);
+    pub fn unload(&mut self, uri: &Url) {
+        self.memory_docs.remove(uri);
     }
 
     pub fn memory_docs(&self) -> Vec<Arc<Document>> {
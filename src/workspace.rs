@@ -0,0 +1,294 @@
+use super::analyzer::options;
+use super::parser::{Expr, IdentKind, Lvalue, Node, Preamble, Statement, Walk, ast};
+use super::storage::{DocumentVersion, Storage};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+use tower_lsp::lsp_types::{
+    Location, Range, SymbolInformation, SymbolKind, Url, WorkspaceFolder,
+    WorkspaceFoldersChangeEvent,
+};
+
+/// A probe or map of a script, as found by `workspace/symbol`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// Attach points of the probe a map is first written in.
+    pub container: Option<String>,
+    pub range: Range,
+}
+
+#[derive(Debug)]
+struct IndexedFile {
+    version: DocumentVersion,
+    symbols: Vec<Symbol>,
+}
+
+/// The workspace folders, along with the symbols of every script in them
+/// and of the open documents. Scripts are indexed once in the background and
+/// then again whenever a query finds them changed.
+#[derive(Default)]
+pub struct WorkspaceIndex {
+    folders: std::sync::Mutex<Vec<WorkspaceFolder>>,
+    files: std::sync::Mutex<HashMap<Url, IndexedFile>>,
+}
+
+impl WorkspaceIndex {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn set_folders(&self, folders: Vec<WorkspaceFolder>) {
+        *self.folders.lock().unwrap() = folders;
+    }
+
+    /// Applies a `workspace/didChangeWorkspaceFolders` notification, scripts
+    /// of removed folders are dropped from the index.
+    pub fn change_folders(&self, event: WorkspaceFoldersChangeEvent) {
+        let mut folders = self.folders.lock().unwrap();
+        folders.retain(|x| !event.removed.iter().any(|removed| removed.uri == x.uri));
+        folders.extend(event.added);
+        self.files
+            .lock()
+            .unwrap()
+            .retain(|uri, _| folders.iter().any(|x| contains(&x.uri, uri)));
+    }
+
+    /// The innermost workspace folder containing a document.
+    pub fn folder(&self, uri: &Url) -> Option<Url> {
+        self.folders
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|x| contains(&x.uri, uri))
+            .max_by_key(|x| x.uri.path().len())
+            .map(|x| x.uri.clone())
+    }
+
+    /// Indexes every `.bt` file under the workspace folders. Scripts indexed
+    /// before are only parsed again if they changed.
+    pub async fn scan(&self, storage: &Mutex<Storage>) {
+        let paths = folder_paths(&self.folders.lock().unwrap());
+        let scripts = tokio::task::spawn_blocking(move || {
+            let mut scripts = vec![];
+            for path in paths {
+                find_scripts(&path, &mut scripts);
+            }
+            scripts
+        })
+        .await
+        .unwrap_or_default();
+        for script in scripts {
+            if let Ok(uri) = Url::from_file_path(&script) {
+                self.update(storage, &uri).await;
+            }
+        }
+    }
+
+    /// Indexes a script again if it changed since it was last indexed, and
+    /// forgets it if it can't be read anymore.
+    pub async fn update(&self, storage: &Mutex<Storage>, uri: &Url) {
+        let version = storage.lock().await.read_version(uri);
+        if version.is_error() {
+            self.files.lock().unwrap().remove(uri);
+            return;
+        }
+        if self
+            .files
+            .lock()
+            .unwrap()
+            .get(uri)
+            .is_some_and(|x| x.version == version)
+        {
+            return;
+        }
+
+        let document = storage.lock().await.read(uri);
+        let symbols = document_symbols(&document.data, |span| document.line_index.range(span));
+        self.files.lock().unwrap().insert(
+            uri.clone(),
+            IndexedFile {
+                version: document.version,
+                symbols,
+            },
+        );
+    }
+
+    /// Symbols whose name contains the query, ignoring case.
+    pub async fn symbols(&self, storage: &Mutex<Storage>, query: &str) -> Vec<SymbolInformation> {
+        let mut uris = self
+            .files
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        let open = storage.lock().await.memory_docs();
+        uris.extend(open.iter().map(|x| x.uri.clone()));
+        uris.sort();
+        uris.dedup();
+        for uri in &uris {
+            self.update(storage, uri).await;
+        }
+
+        let query = query.to_lowercase();
+        let files = self.files.lock().unwrap();
+        let mut symbols = vec![];
+        for uri in uris {
+            let Some(file) = files.get(&uri) else {
+                continue;
+            };
+            for symbol in &file.symbols {
+                if symbol.name.to_lowercase().contains(&query) {
+                    symbols.push(symbol.information(&uri));
+                }
+            }
+        }
+        symbols
+    }
+
+    /// The symbols of a single document, for `textDocument/documentSymbol`.
+    pub async fn file_symbols(
+        &self,
+        storage: &Mutex<Storage>,
+        uri: &Url,
+    ) -> Vec<SymbolInformation> {
+        self.update(storage, uri).await;
+        self.files.lock().unwrap().get(uri).map_or(vec![], |file| {
+            file.symbols.iter().map(|x| x.information(uri)).collect()
+        })
+    }
+}
+
+impl Symbol {
+    fn information(&self, uri: &Url) -> SymbolInformation {
+        #[allow(deprecated)]
+        SymbolInformation {
+            name: self.name.clone(),
+            kind: self.kind,
+            tags: None,
+            deprecated: None,
+            location: Location::new(uri.clone(), self.range),
+            container_name: self.container.clone(),
+        }
+    }
+}
+
+/// Whether a document is in a folder, comparing the path segments of URIs
+/// with the same scheme and host so that remote folders work as well.
+fn contains(folder: &Url, uri: &Url) -> bool {
+    if folder.scheme() != uri.scheme()
+        || folder.host_str() != uri.host_str()
+        || folder.port() != uri.port()
+    {
+        return false;
+    }
+    let dir = folder.path().trim_end_matches('/');
+    uri.path()
+        .strip_prefix(dir)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+fn folder_paths(folders: &[WorkspaceFolder]) -> Vec<PathBuf> {
+    folders
+        .iter()
+        .filter_map(|x| x.uri.to_file_path().ok())
+        .collect()
+}
+
+/// Collects the `.bt` files under a directory, skipping hidden directories
+/// like `.git`. Symlinks to directories aren't followed, so loops end.
+fn find_scripts(dir: &Path, scripts: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut entries = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            Some((entry.path(), entry.file_type().ok()?))
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    for (entry, file_type) in entries {
+        let hidden = entry
+            .file_name()
+            .is_some_and(|x| x.to_string_lossy().starts_with('.'));
+        if file_type.is_dir() && !hidden {
+            find_scripts(&entry, scripts);
+        } else if entry.extension().is_some_and(|x| x == "bt") {
+            scripts.push(entry);
+        }
+    }
+}
+
+/// One symbol per attach point of each probe, one per user function, one per
+/// map at its first write and one per `getopt()` option where it is first
+/// read.
+pub fn document_symbols<'a>(text: &'a str, range: impl Fn(pest::Span<'a>) -> Range) -> Vec<Symbol> {
+    let Ok(program) = ast::parse(text) else {
+        return Vec::new();
+    };
+    let mut symbols = vec![];
+    let mut maps: Vec<&str> = vec![];
+    for preamble in &program.preambles {
+        let (block, container) = match preamble {
+            Preamble::Probe(probe) => {
+                for attach_point in &probe.attach_points {
+                    symbols.push(Symbol {
+                        name: attach_point.to_string(),
+                        kind: SymbolKind::EVENT,
+                        container: None,
+                        range: range(probe.span),
+                    });
+                }
+                (&probe.block, probe.attach_points.join(", "))
+            }
+            Preamble::Function(function) => {
+                symbols.push(Symbol {
+                    name: function.name.name.to_string(),
+                    kind: SymbolKind::FUNCTION,
+                    container: None,
+                    range: range(function.span),
+                });
+                (&function.block, function.name.name.to_string())
+            }
+            Preamble::Include(_) | Preamble::Definition(_) | Preamble::Error(_) => continue,
+        };
+        let writes = Walk::new(block.as_node()).filter_map(|x| match x.as_statement()? {
+            Statement::Assignment(assign) => {
+                let Lvalue::Identifier(ident) = &assign.lvalue;
+                Some(ident.as_ref())
+            }
+            Statement::Expr(expr) => match expr.as_ref() {
+                Expr::UnaryExpr(unary) => match unary.expr.as_ref() {
+                    Expr::Identifier(ident) => Some(ident.as_ref()),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        });
+        for ident in writes {
+            if ident.kind != IdentKind::Map || maps.contains(&ident.name) {
+                continue;
+            }
+            maps.push(ident.name);
+            symbols.push(Symbol {
+                name: format!("@{}", ident.name),
+                kind: SymbolKind::VARIABLE,
+                container: Some(container.clone()),
+                range: range(ident.sigil_span()),
+            });
+        }
+    }
+    for option in options::table(&program) {
+        symbols.push(Symbol {
+            name: format!("--{}", option.name),
+            kind: SymbolKind::PROPERTY,
+            container: None,
+            range: range(option.span),
+        });
+    }
+    symbols
+}
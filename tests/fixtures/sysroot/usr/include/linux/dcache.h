@@ -0,0 +1,24 @@
+#ifndef __LINUX_DCACHE_H
+#define __LINUX_DCACHE_H
+
+#define DNAME_INLINE_LEN 40
+
+struct qstr {
+	union {
+		struct {
+			u32 hash;
+			u32 len;
+		};
+		u64 hash_len;
+	};
+	const unsigned char *name;
+};
+
+struct dentry {
+	unsigned int d_flags;
+	struct dentry *d_parent;
+	struct qstr d_name;
+	unsigned char d_iname[DNAME_INLINE_LEN];
+};
+
+#endif /* __LINUX_DCACHE_H */